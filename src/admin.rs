@@ -0,0 +1,135 @@
+use super::auth;
+use super::config;
+use super::handlers;
+use super::models::api_key::NewApiKey;
+use super::models::fixture::Fixture;
+use super::models::outbox::OutboxQuery;
+use super::models::Id;
+use super::store::Db;
+use std::convert::Infallible;
+use warp::Filter;
+
+pub fn admin(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    export(db.clone())
+        .or(import(db.clone()))
+        .or(revoke_sessions(db.clone()))
+        .or(unlock(db.clone()))
+        .or(impersonate(db.clone()))
+        .or(outbox(db.clone()))
+        .or(create_api_key(db.clone()))
+        .or(list_api_keys(db.clone()))
+        .or(revoke_api_key(db))
+}
+
+pub fn export(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "export")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::admin::export)
+}
+
+pub fn import(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "import")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::admin::import)
+}
+
+/// Logs a profile out everywhere, e.g. when the account is deactivated.
+pub fn revoke_sessions(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "profiles" / Id / "sessions" / "revoke")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::admin::revoke_sessions)
+}
+
+/// Lets a profile locked out by failed logins try again.
+pub fn unlock(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "profiles" / Id / "unlock")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::admin::unlock)
+}
+
+/// POST /admin/profiles/{id}/impersonate, which takes a session: API keys
+/// cannot impersonate.
+pub fn impersonate(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "profiles" / Id / "impersonate")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(auth::with_session(db))
+        .and_then(handlers::admin::impersonate)
+}
+
+/// GET /admin/outbox, optionally with `?to=ADDRESS`
+pub fn outbox(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "outbox")
+        .and(warp::get())
+        .and(warp::query::<OutboxQuery>())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::admin::outbox)
+}
+
+/// POST /admin/api-keys with JSON body
+pub fn create_api_key(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "api-keys")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(config::body_limit()))
+        .and(warp::body::json::<NewApiKey>())
+        .and(with_db(db.clone()))
+        .and(auth::with_session(db))
+        .and_then(handlers::admin::create_api_key)
+}
+
+/// GET /admin/api-keys
+pub fn list_api_keys(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "api-keys")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(auth::with_session(db))
+        .and_then(handlers::admin::list_api_keys)
+}
+
+/// POST /admin/api-keys/{id}/revoke
+pub fn revoke_api_key(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "api-keys" / Id / "revoke")
+        .and(warp::post())
+        .and(with_db(db.clone()))
+        .and(auth::with_session(db))
+        .and_then(handlers::admin::revoke_api_key)
+}
+
+fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+fn json_body() -> impl Filter<Extract = (Fixture,), Error = warp::Rejection> + Clone {
+    // A whole dataset is much larger than a single document.
+    warp::body::content_length_limit(1024 * 1024 * 16).and(warp::body::json())
+}
@@ -1,16 +1,37 @@
-use super::config::CONFIG;
+use super::config::{self, CONFIG};
 use super::handlers;
-use super::models::profile::{get_kind, Credentials, Kind, Profile};
-use super::store::Db;
+use super::models::profile::{Kind, Profile};
+use super::models::{api_key, refresh_token, revocation, Id};
+use super::store::{self, Db};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use warp::http::header::HeaderValue;
+use warp::http::Response;
 use warp::{Filter, Rejection};
 
 pub fn auth(db: Db) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     login(db.clone())
+        .or(answer_challenge(db.clone()))
+        .or(enroll_two_factor(db.clone()))
+        .or(confirm_two_factor(db.clone()))
+        .or(disable_two_factor(db.clone()))
+        .or(regenerate_recovery_codes(db.clone()))
+        .or(forgot_password(db.clone()))
+        .or(reset_password(db.clone()))
+        .or(refresh(db.clone()))
+        .or(end_impersonation(db.clone()))
+        .or(logout(db))
+        .or(jwks())
 }
 
 pub fn login(
@@ -19,33 +40,215 @@ pub fn login(
     warp::path!("auth")
         .and(warp::post())
         .and(json_body())
+        .and(remote())
         .and(with_db(db))
         .and_then(handlers::auth::login)
 }
 
+pub fn refresh(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "refresh")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::auth::refresh)
+}
+
+pub fn logout(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "logout")
+        .and(warp::post())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::logout)
+}
+
+/// POST /auth/impersonation/end, with an impersonation token.
+pub fn end_impersonation(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "impersonation" / "end")
+        .and(warp::post())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::end_impersonation)
+}
+
+/// POST /auth/password/forgot, which emails a reset token.
+pub fn forgot_password(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "password" / "forgot")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::auth::forgot_password)
+}
+
+/// POST /auth/password/reset, which sets a new password given the token.
+pub fn reset_password(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "password" / "reset")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::auth::reset_password)
+}
+
+/// POST /auth/2fa, the second step of a login with two-factor
+/// authentication.
+pub fn answer_challenge(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "2fa")
+        .and(warp::post())
+        .and(json_body())
+        .and(remote())
+        .and(with_db(db))
+        .and_then(handlers::auth::answer_challenge)
+}
+
+pub fn enroll_two_factor(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "2fa" / "enroll")
+        .and(warp::post())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::enroll_two_factor)
+}
+
+pub fn confirm_two_factor(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "2fa" / "confirm")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::confirm_two_factor)
+}
+
+pub fn disable_two_factor(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "2fa" / "disable")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::disable_two_factor)
+}
+
+pub fn regenerate_recovery_codes(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("auth" / "2fa" / "recovery-codes")
+        .and(warp::post())
+        .and(json_body())
+        .and(with_session(db.clone()))
+        .and(with_db(db))
+        .and_then(handlers::auth::regenerate_recovery_codes)
+}
+
+/// GET /.well-known/jwks.json
+pub fn jwks() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!(".well-known" / "jwks.json")
+        .and(warp::get())
+        .and_then(handlers::auth::jwks)
+}
+
 fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
 
-fn json_body() -> impl Filter<Extract = (Credentials,), Error = warp::Rejection> + Clone {
+fn json_body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    warp::body::content_length_limit(config::body_limit()).and(warp::body::json())
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
-    user_id: u8,
-    exp: i64,
+    pub user_id: Id,
+    pub exp: i64,
+
+    /// When the token was issued, as a Unix timestamp.
+    pub iat: i64,
+
+    /// The token is not accepted before then, as a Unix timestamp.
+    pub nbf: i64,
+
+    /// The deployment that issued the token, see `Config::jwt_issuer`.
+    pub iss: String,
+
+    /// The deployment the token is for, see `Config::jwt_audience`.
+    pub aud: String,
+
+    /// The role of the user when the token was issued, so that requests do
+    /// not have to look it up.
+    pub role: Kind,
+
+    /// What the token allows, see `scopes`.
+    pub scopes: Vec<String>,
+
+    /// Identifies the token, so that it can be revoked.
+    pub jti: String,
+
+    /// The login the token belongs to, which is the family of its refresh
+    /// token.
+    pub sid: Id,
+
+    /// Who is acting as the user, for tokens issued to impersonate them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+}
+
+/// The user behind an impersonation token, as in the `act` claim of RFC
+/// 8693. The token belongs to their login.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Actor {
+    pub user_id: Id,
+    pub role: Kind,
+}
+
+impl Actor {
+    /// Whether the actor may act as `profile`: root users as anyone, admins
+    /// as anyone but root users, and nobody as themselves.
+    pub fn can_impersonate(&self, profile: &Profile) -> bool {
+        if self.user_id == profile.id {
+            return false;
+        }
+
+        match self.role {
+            Kind::Root => true,
+            Kind::Admin => profile.kind != Kind::Root,
+            Kind::Mentor | Kind::Trainee => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct User {
-    pub id: u8,
+    pub id: Id,
     pub role: Kind,
+
+    /// What the user may do: the scopes of their role, or those of the API
+    /// key they called with.
+    #[serde(default)]
+    pub scopes: Vec<String>,
 }
 
 impl User {
+    /// Whether the user was granted `scope`, see `scopes`.
+    pub fn can(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
     pub fn can_view(&self, profile: &Profile) -> bool {
         if self.id == profile.id {
             return true;
@@ -63,23 +266,168 @@ impl User {
     }
 }
 
-pub fn generate_token(user_id: u8) -> Result<String, Box<dyn std::error::Error>> {
-    let expiration = Utc::now() + Duration::hours(1);
+/// The scopes granted to `role`, each of the form `resource:action`.
+pub fn scopes(role: &Kind) -> Vec<String> {
+    let mut scopes = vec!["profiles:read", "courses:read", "topics:read"];
+
+    match role {
+        Kind::Root => scopes.extend(["courses:write", "topics:write", "admin"]),
+        Kind::Admin => scopes.extend(["courses:write", "topics:write"]),
+        Kind::Mentor => scopes.push("topics:write"),
+        Kind::Trainee => {}
+    }
+
+    scopes.into_iter().map(String::from).collect()
+}
+
+/// Issues an access token for `user_id` with the given `role`, as part of
+/// the login `session`.
+pub fn generate_token(
+    user_id: Id,
+    role: Kind,
+    session: Id,
+) -> Result<String, Box<dyn std::error::Error>> {
+    issue_token(user_id, role, session, None)
+}
+
+/// Issues an access token letting `actor` act as `user_id`, with its
+/// `role`, as part of the login `session` of the actor. Such tokens cannot
+/// be refreshed.
+pub fn generate_impersonation_token(
+    user_id: Id,
+    role: Kind,
+    session: Id,
+    actor: Actor,
+) -> Result<String, Box<dyn std::error::Error>> {
+    issue_token(user_id, role, session, Some(actor))
+}
+
+fn issue_token(
+    user_id: Id,
+    role: Kind,
+    session: Id,
+    act: Option<Actor>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let config = CONFIG
+        .get()
+        .expect("Application is not properly configured.");
+
+    let now = Utc::now();
+    let expiration = now + Duration::seconds(config.jwt_expiry as i64);
     let claims = Claims {
         user_id,
         exp: expiration.timestamp(),
+        iat: now.timestamp(),
+        nbf: now.timestamp(),
+        iss: config.jwt_issuer.clone(),
+        aud: config.jwt_audience.clone(),
+        scopes: scopes(&role),
+        role,
+        jti: hex::encode(generate_secret_key(16)),
+        sid: session,
+        act,
     };
 
+    Ok(sign(config, &claims)?)
+}
+
+/// The OpenID Connect claims describing a profile, as returned by the
+/// userinfo endpoint.
+#[derive(Serialize, Deserialize)]
+pub struct UserInfo {
+    /// The profile ID.
+    pub sub: String,
+
+    pub preferred_username: String,
+    pub given_name: String,
+    pub family_name: String,
+    pub name: String,
+    pub role: Kind,
+}
+
+impl From<&Profile> for UserInfo {
+    fn from(profile: &Profile) -> UserInfo {
+        UserInfo {
+            sub: profile.id.to_string(),
+            preferred_username: profile.username.clone(),
+            given_name: profile.first_name.clone(),
+            family_name: profile.last_name.clone(),
+            name: format!("{} {}", profile.first_name, profile.last_name)
+                .trim()
+                .to_string(),
+            role: profile.kind.clone(),
+        }
+    }
+}
+
+/// The claims of an OpenID Connect ID token, telling a client who signed in.
+#[derive(Serialize, Deserialize)]
+pub struct IdClaims {
+    pub iss: String,
+
+    /// The client the token is for.
+    pub aud: String,
+
+    pub exp: i64,
+    pub iat: i64,
+
+    /// When the user signed in, as a Unix timestamp.
+    pub auth_time: i64,
+
+    /// The nonce of the authorization request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+
+    #[serde(flatten)]
+    pub user: UserInfo,
+}
+
+impl IdClaims {
+    pub fn new(profile: &Profile, client_id: &str, auth_time: i64) -> IdClaims {
+        let config = CONFIG
+            .get()
+            .expect("Application is not properly configured.");
+
+        let now = Utc::now();
+        IdClaims {
+            iss: config.jwt_issuer.clone(),
+            aud: client_id.to_string(),
+            exp: (now + Duration::seconds(config.jwt_expiry as i64)).timestamp(),
+            iat: now.timestamp(),
+            auth_time,
+            nonce: None,
+            user: UserInfo::from(profile),
+        }
+    }
+}
+
+/// Signs an ID token, with the same key as access tokens.
+pub fn generate_id_token(claims: &IdClaims) -> Result<String, Box<dyn std::error::Error>> {
     let config = CONFIG
         .get()
         .expect("Application is not properly configured.");
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(config.jwt_secret),
-    )?;
-    Ok(token)
+    Ok(sign(config, claims)?)
+}
+
+// Signs with the key pair when there is one, naming it in the header, or with
+// the secret.
+fn sign<T: serde::Serialize>(
+    config: &config::Config,
+    claims: &T,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    match &config.jwt_key_pair {
+        Some(pair) => {
+            let mut header = Header::new(pair.algorithm);
+            header.kid = Some(pair.kid.clone());
+            encode(&header, claims, pair.encoding_key())
+        }
+        None => encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(config.jwt_secret),
+        ),
+    }
 }
 
 pub fn generate_secret_key(length: usize) -> Vec<u8> {
@@ -88,56 +436,370 @@ pub fn generate_secret_key(length: usize) -> Vec<u8> {
     key
 }
 
-fn decode_token(token: &str) -> Result<u8, Rejection> {
+/// Why a request could not be authenticated.
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// There is no `Authorization` header.
+    MissingHeader,
+    /// The header is not of the form `Bearer <token>`.
+    MalformedHeader,
+    ExpiredToken,
+    /// The `nbf` claim of the token is still ahead.
+    ImmatureToken,
+    /// The token was issued by or for another deployment.
+    ForeignToken,
+    /// The token was not signed with any of our secrets or keys.
+    InvalidSignature,
+    /// The token cannot be decoded, lacks a claim or has scopes its role
+    /// does not grant.
+    InvalidToken,
+    RevokedToken,
+    /// The token is valid but its profile no longer exists.
+    UnknownUser,
+    /// The API key is unknown or was revoked.
+    InvalidApiKey,
+    /// The revocation list, the profiles or the API keys could not be read.
+    Storage,
+}
+
+impl warp::reject::Reject for AuthError {}
+
+impl AuthError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            AuthError::MissingHeader => "Not authorized!",
+            AuthError::MalformedHeader => "Malformed authorization header!",
+            AuthError::ExpiredToken => "Token has expired!",
+            AuthError::ImmatureToken => "Token is not valid yet!",
+            AuthError::ForeignToken => "Token was issued for another server!",
+            AuthError::InvalidSignature | AuthError::InvalidToken => "Invalid token!",
+            AuthError::RevokedToken => "Token has been revoked!",
+            AuthError::UnknownUser => "Unknown user!",
+            AuthError::InvalidApiKey => "Invalid API key!",
+            AuthError::Storage => "Unable to access the database.",
+        }
+    }
+}
+
+fn decode_token(token: &str) -> Result<Claims, AuthError> {
     let config = CONFIG
         .get()
         .expect("Application is not properly configured.");
 
+    verify_token(config, token)
+}
+
+// Checks the token against the key its `kid` names, or against the current
+// secret and the recently retired ones, then checks its claims.
+fn verify_token(config: &config::Config, token: &str) -> Result<Claims, AuthError> {
     let token = token.replace('\"', "");
 
-    let validation = Validation::new(Algorithm::HS256);
-    let token_message = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(config.jwt_secret),
-        &validation,
+    let header = decode_header(&token).map_err(|_| AuthError::InvalidToken)?;
+
+    let claims = match header.kid {
+        Some(kid) => match &config.jwt_key_pair {
+            Some(pair) if pair.kid == kid => {
+                decode_claims(config, &token, pair.algorithm, pair.decoding_key())?
+            }
+            _ => return Err(AuthError::InvalidSignature),
+        },
+        None => decode_with_secrets(config, &token)?,
+    };
+
+    // A token cannot grant more than the role it was issued for.
+    let granted = scopes(&claims.role);
+    if !claims.scopes.iter().all(|scope| granted.contains(scope)) {
+        return Err(AuthError::InvalidToken);
+    }
+
+    Ok(claims)
+}
+
+fn decode_with_secrets(config: &config::Config, token: &str) -> Result<Claims, AuthError> {
+    let now = Utc::now().timestamp();
+
+    // Tokens signed before the secret was rotated are accepted for a while.
+    let secrets = std::iter::once(config.jwt_secret).chain(
+        config
+            .jwt_previous_secrets
+            .iter()
+            .filter(|previous| previous.expires > now)
+            .map(|previous| previous.secret.as_slice()),
     );
 
-    match token_message {
-        Ok(data) => Ok(data.claims.user_id),
-        Err(_) => Err(warp::reject()),
+    for secret in secrets {
+        let key = DecodingKey::from_secret(secret);
+
+        match decode_claims(config, token, Algorithm::HS256, &key) {
+            Err(AuthError::InvalidSignature) => continue,
+            result => return result,
+        }
     }
+
+    Err(AuthError::InvalidSignature)
+}
+
+fn decode_claims(
+    config: &config::Config,
+    token: &str,
+    algorithm: Algorithm,
+    key: &DecodingKey,
+) -> Result<Claims, AuthError> {
+    let mut validation = Validation::new(algorithm);
+    validation.validate_nbf = true;
+    validation.set_issuer(&[&config.jwt_issuer]);
+    validation.set_audience(&[&config.jwt_audience]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud"]);
+
+    match decode::<Claims>(token, key, &validation) {
+        Ok(data) => Ok(data.claims),
+        Err(err) => match err.kind() {
+            ErrorKind::InvalidSignature => Err(AuthError::InvalidSignature),
+            ErrorKind::ExpiredSignature => Err(AuthError::ExpiredToken),
+            ErrorKind::ImmatureSignature => Err(AuthError::ImmatureToken),
+            ErrorKind::InvalidIssuer | ErrorKind::InvalidAudience => Err(AuthError::ForeignToken),
+            _ => Err(AuthError::InvalidToken),
+        },
+    }
+}
+
+/// The user of a request along with the claims of their access token.
+pub struct Session {
+    pub user: User,
+    pub claims: Claims,
 }
 
+/// Requires a valid access token that was not revoked, and rejects the
+/// request with an `AuthError` otherwise.
+pub fn with_session(db: Db) -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("Authorization").and_then(move |header: Option<String>| {
+        let db = db.clone();
+        async move {
+            match header {
+                Some(header) => authenticate(&db, &header)
+                    .await
+                    .map_err(warp::reject::custom),
+                None => Err(warp::reject::custom(AuthError::MissingHeader)),
+            }
+        }
+    })
+}
+
+/// Requires a signed-in user or an API key, given in the `X-API-Key` header
+/// or as a bearer token. Rejects the request with an `AuthError` otherwise.
 pub fn with_auth(db: Db) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
-    warp::header::<String>("Authorization")
-        .and_then(move |auth_header: String| {
+    optional_auth(db).and_then(|user: Option<User>| async move {
+        user.ok_or_else(|| warp::reject::custom(AuthError::MissingHeader))
+    })
+}
+
+/// For endpoints that also serve anonymous requests: gives the user when
+/// there are credentials, which must then be valid, and `None` otherwise.
+pub fn optional_auth(db: Db) -> impl Filter<Extract = (Option<User>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("X-API-Key")
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(move |key: Option<String>, header: Option<String>| {
             let db = db.clone();
             async move {
-                let token = auth_header.replace("Bearer ", "");
-
-                match decode_token(&token) {
-                    Ok(user_id) => {
-                        if let Ok(kind) = get_kind(&db, user_id).await {
-                            return Ok(User {
-                                id: user_id,
-                                role: kind,
-                            });
-                        }
-                        Ok(User {
-                            id: user_id,
-                            role: Kind::Trainee,
-                        })
+                let bearer = header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "));
+
+                let user = match (key.as_deref(), bearer) {
+                    (Some(key), _) => authenticate_key(&db, key).await.map(Some),
+                    (None, Some(key)) if key.starts_with(api_key::PREFIX) => {
+                        authenticate_key(&db, key).await.map(Some)
                     }
-                    Err(_) => Err(warp::reject()),
-                }
+                    (None, _) => match header {
+                        Some(header) => authenticate(&db, &header).await.map(|s| Some(s.user)),
+                        None => Ok(None),
+                    },
+                };
+
+                user.map_err(warp::reject::custom)
             }
         })
-        .or_else(|_| async {
-            Ok::<_, warp::Rejection>((User {
-                id: 0,
-                role: Kind::Trainee,
-            },))
+}
+
+async fn authenticate_key(db: &Db, key: &str) -> Result<User, AuthError> {
+    let key = match api_key::verify(db, key).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(AuthError::InvalidApiKey),
+        Err(err) => {
+            log::error!("storage: {}", err);
+            return Err(AuthError::Storage);
+        }
+    };
+
+    let profile = db
+        .read::<Profile>()
+        .await
+        .and_then(|profiles| profiles.get(key.profile_id));
+    let profile = match profile {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return Err(AuthError::UnknownUser),
+        Err(err) => {
+            log::error!("storage: {}", err);
+            return Err(AuthError::Storage);
+        }
+    };
+
+    // Keys never outrank their profile, which may have been demoted since.
+    let role = lesser_role(key.role, profile.kind);
+    let granted = scopes(&role);
+    Ok(User {
+        id: key.profile_id,
+        role,
+        scopes: key
+            .scopes
+            .into_iter()
+            .filter(|scope| granted.contains(scope))
+            .collect(),
+    })
+}
+
+// The role of the two with the fewest rights.
+fn lesser_role(a: Kind, b: Kind) -> Kind {
+    let rank = |kind: &Kind| match kind {
+        Kind::Root => 3,
+        Kind::Admin => 2,
+        Kind::Mentor => 1,
+        Kind::Trainee => 0,
+    };
+
+    match rank(&a) <= rank(&b) {
+        true => a,
+        false => b,
+    }
+}
+
+async fn authenticate(db: &Db, header: &str) -> Result<Session, AuthError> {
+    let token = header
+        .strip_prefix("Bearer ")
+        .ok_or(AuthError::MalformedHeader)?;
+    let claims = decode_token(token)?;
+
+    let target = claims.act.as_ref().map(|_| claims.user_id);
+    match revocation::is_revoked(db, &claims.jti, claims.sid, target, claims.iat).await {
+        Ok(false) => {}
+        Ok(true) => return Err(AuthError::RevokedToken),
+        Err(err) => {
+            log::error!("storage: {}", err);
+            return Err(AuthError::Storage);
+        }
+    }
+
+    let profile = db
+        .read::<Profile>()
+        .await
+        .and_then(|profiles| profiles.get(claims.user_id));
+    match profile {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(AuthError::UnknownUser),
+        Err(err) => {
+            log::error!("storage: {}", err);
+            return Err(AuthError::Storage);
+        }
+    }
+
+    if let Some(actor) = &claims.act {
+        log::info!(
+            target: "auth",
+            "profile {} is acting as profile {}",
+            actor.user_id,
+            claims.user_id
+        );
+        let _ = ACTOR.try_with(|cell| cell.set(Some(actor.user_id)));
+    }
+
+    // The role comes from the token, role changes apply from the next
+    // refresh on.
+    Ok(Session {
+        user: User {
+            id: claims.user_id,
+            role: claims.role.clone(),
+            scopes: claims.scopes.clone(),
+        },
+        claims,
+    })
+}
+
+/// Revokes the access token of `session` along with every other token of
+/// its login, refresh tokens included. Impersonation tokens are revoked
+/// alone, which ends the impersonation but keeps the actor signed in.
+pub async fn revoke_session(db: &Db, session: &Session) -> Result<(), store::Error> {
+    let claims = &session.claims;
+
+    if claims.act.is_some() {
+        return revocation::revoke(db, &claims.jti, 0, revocation_expiry(claims.exp)).await;
+    }
+
+    if claims.sid != 0 {
+        refresh_token::revoke_family(db, claims.sid).await?;
+    }
+
+    revocation::revoke(db, &claims.jti, claims.sid, revocation_expiry(claims.exp)).await
+}
+
+/// Revokes every access token of the login `session`, once one of its
+/// refresh tokens was reused and the refresh tokens are revoked already.
+pub async fn revoke_login(db: &Db, session: Id) -> Result<(), store::Error> {
+    revocation::revoke_sessions(db, &[session], revocation_expiry(0)).await
+}
+
+/// Revokes every token and API key of `profile_id`, logging it out
+/// everywhere and ending its impersonations. Returns the number of sessions
+/// revoked.
+pub async fn revoke_profile(db: &Db, profile_id: Id) -> Result<usize, store::Error> {
+    api_key::revoke_profile(db, profile_id).await?;
+    let sessions = refresh_token::revoke_profile(db, profile_id).await?;
+    revocation::revoke_sessions(db, &sessions, revocation_expiry(0)).await?;
+    revocation::revoke_impersonations(db, profile_id, revocation_expiry(0)).await?;
+    Ok(sessions.len())
+}
+
+tokio::task_local! {
+    // The actor behind the impersonation token of the request being served.
+    static ACTOR: Cell<Option<Id>>;
+}
+
+/// Names the actor in the `X-Impersonated-By` header of the replies to
+/// requests made with an impersonation token, so that they stand out. The
+/// actor is the one `authenticate` accepted while `reply` ran, so tokens
+/// that were rejected never mark a reply.
+pub async fn mark_impersonation<B>(reply: impl Future<Output = Response<B>>) -> Response<B> {
+    let (mut response, actor) = ACTOR
+        .scope(Cell::new(None), async {
+            let response = reply.await;
+            (response, ACTOR.with(Cell::get))
         })
+        .await;
+
+    if let Some(actor) = actor {
+        response
+            .headers_mut()
+            .insert("x-impersonated-by", HeaderValue::from(actor));
+    }
+
+    response
+}
+
+/// The address of the client. Servers wrapping the routes in a service give
+/// it as a request extension, as warp only knows it when serving them itself.
+pub fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<SocketAddr>())
+        .map(|addr: Option<SocketAddr>, extension: Option<SocketAddr>| addr.or(extension))
+}
+
+// Every access token issued until now has expired by then.
+fn revocation_expiry(exp: i64) -> i64 {
+    let config = CONFIG
+        .get()
+        .expect("Application is not properly configured.");
+
+    let latest = Utc::now().timestamp() + config.jwt_expiry as i64;
+    latest.max(exp)
 }
 
 #[tokio::test]
@@ -147,14 +809,165 @@ async fn test_jwt_encode_decode() {
     CONFIG
         .set(Config {
             jwt_secret: "secret_key".as_bytes(),
+            ..Default::default()
         })
         .expect("Error setting application configuration.");
 
-    let token = generate_token(123).unwrap();
+    let token = generate_token(123, Kind::Mentor, 7).unwrap();
     assert_ne!(token, "");
 
-    let user_id = decode_token(&token).unwrap();
-    assert_eq!(user_id, 123);
+    let claims = decode_token(&token).unwrap();
+    assert_eq!((claims.user_id, claims.sid), (123, 7));
+    assert_eq!(claims.role, Kind::Mentor);
+    assert!(claims.scopes.contains(&String::from("topics:write")));
+    assert!(!claims.scopes.contains(&String::from("courses:write")));
+    assert_ne!(claims.jti, "");
+    assert_eq!(claims.act, None);
+
+    let actor = Actor {
+        user_id: 1,
+        role: Kind::Root,
+    };
+    let token = generate_impersonation_token(123, Kind::Trainee, 7, actor.clone()).unwrap();
+
+    let claims = decode_token(&token).unwrap();
+    assert_eq!((claims.user_id, claims.sid), (123, 7));
+    assert_eq!(claims.scopes, scopes(&Kind::Trainee));
+    assert_eq!(claims.act, Some(actor));
+}
+
+#[test]
+fn test_actor_can_impersonate() {
+    let profile = |id, kind| Profile {
+        id,
+        kind,
+        ..Default::default()
+    };
+
+    let root = Actor {
+        user_id: 1,
+        role: Kind::Root,
+    };
+    let admin = Actor {
+        user_id: 2,
+        role: Kind::Admin,
+    };
+    let mentor = Actor {
+        user_id: 3,
+        role: Kind::Mentor,
+    };
+
+    assert!(root.can_impersonate(&profile(4, Kind::Root)));
+    assert!(root.can_impersonate(&profile(2, Kind::Admin)));
+    assert!(!root.can_impersonate(&profile(1, Kind::Root)));
+
+    assert!(admin.can_impersonate(&profile(5, Kind::Admin)));
+    assert!(admin.can_impersonate(&profile(6, Kind::Trainee)));
+    assert!(!admin.can_impersonate(&profile(1, Kind::Root)));
+
+    assert!(!mentor.can_impersonate(&profile(6, Kind::Trainee)));
+}
+
+#[test]
+fn test_jwt_previous_secret() {
+    use super::config::{Config, PreviousSecret};
+
+    let now = Utc::now().timestamp();
+    let claims = test_claims(now);
+    let sign = |secret: &[u8]| {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    };
+
+    let mut config = Config {
+        jwt_secret: "current".as_bytes(),
+        jwt_previous_secrets: vec![PreviousSecret {
+            secret: b"previous".to_vec(),
+            expires: now + 10,
+        }],
+        ..Default::default()
+    };
+
+    assert_eq!(
+        verify_token(&config, &sign(b"current")).unwrap().user_id,
+        123
+    );
+    assert_eq!(
+        verify_token(&config, &sign(b"previous")).unwrap().user_id,
+        123
+    );
+    assert!(verify_token(&config, &sign(b"unknown")).is_err());
+
+    // once the grace period is over
+    config.jwt_previous_secrets[0].expires = now - 1;
+    assert!(verify_token(&config, &sign(b"previous")).is_err());
+}
+
+#[cfg(test)]
+fn test_claims(now: i64) -> Claims {
+    Claims {
+        user_id: 123,
+        exp: now + 60,
+        iat: now,
+        nbf: now,
+        iss: String::from("dummy-api"),
+        aud: String::from("dummy-api"),
+        role: Kind::Trainee,
+        scopes: scopes(&Kind::Trainee),
+        jti: String::from("jti"),
+        sid: 1,
+        act: None,
+    }
+}
+
+#[test]
+fn test_jwt_claims() {
+    use super::config::Config;
+
+    let now = Utc::now().timestamp();
+    let config = Config {
+        jwt_secret: "current".as_bytes(),
+        ..Default::default()
+    };
+    let verify = |claims: &Claims| {
+        let token = encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(b"current"),
+        );
+        verify_token(&config, &token.unwrap())
+    };
+
+    assert!(verify(&test_claims(now)).is_ok());
+
+    let staging = Claims {
+        iss: String::from("staging"),
+        ..test_claims(now)
+    };
+    assert_eq!(verify(&staging).err(), Some(AuthError::ForeignToken));
+
+    let elsewhere = Claims {
+        aud: String::from("elsewhere"),
+        ..test_claims(now)
+    };
+    assert_eq!(verify(&elsewhere).err(), Some(AuthError::ForeignToken));
+
+    let early = Claims {
+        nbf: now + 3600,
+        ..test_claims(now)
+    };
+    assert_eq!(verify(&early).err(), Some(AuthError::ImmatureToken));
+
+    // a trainee token claiming to be allowed more
+    let escalated = Claims {
+        scopes: scopes(&Kind::Root),
+        ..test_claims(now)
+    };
+    assert_eq!(verify(&escalated).err(), Some(AuthError::InvalidToken));
 }
 
 #[test]
@@ -162,21 +975,35 @@ fn test_user_can_view() {
     let trainee = User {
         id: 1,
         role: Kind::Trainee,
+        scopes: scopes(&Kind::Trainee),
     };
 
     let admin = User {
         id: 2,
         role: Kind::Admin,
+        scopes: scopes(&Kind::Admin),
     };
 
     let mentor = User {
         id: 3,
         role: Kind::Mentor,
+        scopes: scopes(&Kind::Mentor),
     };
 
-    let trainee_profile = Profile { id: trainee.id, ..Default::default() };
-    let mentor_profile = Profile { id: mentor.id, kind: Kind::Mentor, ..Default::default() };
-    let admin_profile = Profile { id: admin.id, kind: Kind::Admin, ..Default::default() };
+    let trainee_profile = Profile {
+        id: trainee.id,
+        ..Default::default()
+    };
+    let mentor_profile = Profile {
+        id: mentor.id,
+        kind: Kind::Mentor,
+        ..Default::default()
+    };
+    let admin_profile = Profile {
+        id: admin.id,
+        kind: Kind::Admin,
+        ..Default::default()
+    };
 
     assert!(trainee.can_view(&trainee_profile));
     assert!(admin.can_view(&trainee_profile));
@@ -1,9 +1,708 @@
+use super::models::lockout::Policy;
+use super::models::profile::valid_email;
+use super::signing::KeyPair;
+use serde_derive::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::sync::OnceCell;
+use warp::http::Method;
 
+/// The settings the library reads while serving requests.
 #[derive(Debug)]
 pub struct Config {
-    pub jwt_secret: &'static [u8]
+    pub jwt_secret: &'static [u8],
+
+    /// Secrets that signed tokens before the current one, still accepted
+    /// for a while after a rotation.
+    pub jwt_previous_secrets: Vec<PreviousSecret>,
+
+    /// Signs tokens instead of `jwt_secret` when set. Tokens it signs name
+    /// it in their `kid` header.
+    pub jwt_key_pair: Option<KeyPair>,
+
+    /// How long issued tokens stay valid, in seconds.
+    pub jwt_expiry: u64,
+
+    /// Who issues tokens, required in the `iss` claim of accepted tokens.
+    pub jwt_issuer: String,
+
+    /// Who tokens are for, required in the `aud` claim of accepted tokens.
+    pub jwt_audience: String,
+
+    /// How long refresh tokens stay valid, in seconds.
+    pub refresh_expiry: u64,
+
+    /// The largest request body that is accepted, in bytes.
+    pub body_limit: u64,
+
+    /// Where the OpenID Connect provider may send users back to after they
+    /// signed in. Without any, no client can sign in.
+    pub oidc_redirect_uris: Vec<String>,
+
+    /// How failed logins lock out usernames and addresses.
+    pub lockout: Policy,
+
+    /// The sender of the emails the server sends.
+    pub mail_from: String,
+
+    /// Where sent emails are written as `.eml` files, besides the outbox.
+    pub outbox_dir: Option<PathBuf>,
+
+    /// The page of the frontend resetting passwords, which reset emails
+    /// link to with the token in its `token` query parameter.
+    pub password_reset_url: Option<String>,
+
+    /// How long password reset tokens stay valid, in seconds.
+    pub password_reset_expiry: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            jwt_secret: &[],
+            jwt_previous_secrets: Vec::new(),
+            jwt_key_pair: None,
+            jwt_expiry: DEFAULT_JWT_EXPIRY,
+            jwt_issuer: String::from(DEFAULT_JWT_ISSUER),
+            jwt_audience: String::from(DEFAULT_JWT_AUDIENCE),
+            refresh_expiry: DEFAULT_REFRESH_EXPIRY,
+            body_limit: DEFAULT_BODY_LIMIT,
+            oidc_redirect_uris: Vec::new(),
+            lockout: Policy::default(),
+            mail_from: String::from(DEFAULT_MAIL_FROM),
+            outbox_dir: None,
+            password_reset_url: None,
+            password_reset_expiry: DEFAULT_PASSWORD_RESET_EXPIRY,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PreviousSecret {
+    pub secret: Vec<u8>,
+    /// When tokens signed with it stop being accepted, as a Unix timestamp.
+    pub expires: i64,
 }
 
 // Initialize and access the configuration
 pub static CONFIG: OnceCell<Config> = OnceCell::const_new();
+
+const DEFAULT_JWT_EXPIRY: u64 = 60 * 60;
+const DEFAULT_JWT_ISSUER: &str = "dummy-api";
+const DEFAULT_JWT_AUDIENCE: &str = "dummy-api";
+const DEFAULT_REFRESH_EXPIRY: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_BODY_LIMIT: u64 = 16 * 1024;
+const DEFAULT_JWT_ROTATION_GRACE: u64 = 24 * 60 * 60;
+const DEFAULT_MAIL_FROM: &str = "dummy-api@localhost";
+const DEFAULT_PASSWORD_RESET_EXPIRY: u64 = 60 * 60;
+
+// Read when neither `--config` nor `CONFIG_FILE` is given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "dummy-api.toml";
+
+/// The largest request body that is accepted, in bytes.
+pub fn body_limit() -> u64 {
+    CONFIG
+        .get()
+        .map(|config| config.body_limit)
+        .unwrap_or(DEFAULT_BODY_LIMIT)
+}
+
+/// How long issued tokens stay valid, in seconds.
+pub fn jwt_expiry() -> u64 {
+    CONFIG
+        .get()
+        .map(|config| config.jwt_expiry)
+        .unwrap_or(DEFAULT_JWT_EXPIRY)
+}
+
+/// How long refresh tokens stay valid, in seconds.
+pub fn refresh_expiry() -> u64 {
+    CONFIG
+        .get()
+        .map(|config| config.refresh_expiry)
+        .unwrap_or(DEFAULT_REFRESH_EXPIRY)
+}
+
+/// How failed logins lock out usernames and addresses.
+pub fn lockout() -> Policy {
+    CONFIG
+        .get()
+        .map(|config| config.lockout)
+        .unwrap_or_default()
+}
+
+/// Every setting of the server. Each is read from the config file, then from
+/// its environment variable and then from its command-line flag, the last one
+/// found winning.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub host: IpAddr,
+    pub port: u16,
+
+    /// Without a secret or a secret file, tokens are signed with a secret
+    /// generated once and kept in `data_dir`, or a random one.
+    pub jwt_secret: Option<String>,
+
+    /// A file holding the secret, as an alternative to `jwt_secret`.
+    pub jwt_secret_file: Option<PathBuf>,
+
+    /// How long a replaced secret is still accepted, in seconds.
+    pub jwt_rotation_grace: u64,
+
+    /// Either `HS256`, signing with the secret, or `RS256` or `EdDSA`,
+    /// signing with a private key whose public key is published.
+    pub jwt_algorithm: String,
+
+    /// The private key in PEM format, required for `RS256`. Without it,
+    /// an `EdDSA` key is generated and kept in `data_dir`.
+    pub jwt_private_key_file: Option<PathBuf>,
+
+    /// How long issued tokens stay valid, in seconds.
+    pub jwt_expiry: u64,
+
+    /// Written to the `iss` claim of tokens, which is checked along with
+    /// `jwt_audience` so that tokens of another deployment sharing the
+    /// secret are not accepted.
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+
+    /// How long refresh tokens stay valid, in seconds. Each refresh issues
+    /// a new one.
+    pub refresh_token_expiry: u64,
+
+    /// The origins allowed to call the API, `*` allowing any.
+    pub cors_origins: Vec<String>,
+    pub cors_methods: Vec<String>,
+
+    /// The largest request body that is accepted, in bytes.
+    pub body_limit: u64,
+
+    /// The redirect URIs OpenID Connect clients may use, which enables the
+    /// provider.
+    pub oidc_redirect_uris: Vec<String>,
+
+    /// Failed logins for a username, and from an address, before further
+    /// attempts are refused, 0 never refusing them.
+    pub lockout_threshold: u32,
+    pub lockout_ip_threshold: u32,
+
+    /// How long the first lockout lasts, in seconds, each following one
+    /// lasting twice as long up to `lockout_max_duration`.
+    pub lockout_duration: u64,
+    pub lockout_max_duration: u64,
+
+    /// The sender of the emails the server sends. Emails are never
+    /// delivered, they are kept in an outbox admins can read.
+    pub mail_from: String,
+
+    /// Where emails are written as `.eml` files as well, if anywhere.
+    pub outbox_dir: Option<PathBuf>,
+
+    /// The frontend page password reset emails link to, with the token in
+    /// its `token` query parameter. Without it, emails only show the token.
+    pub password_reset_url: Option<String>,
+
+    /// How long password reset tokens stay valid, in seconds.
+    pub password_reset_expiry: u64,
+
+    /// Keeps the data across restarts when set.
+    pub data_dir: Option<PathBuf>,
+
+    /// Either `memory` or `sqlite`.
+    pub storage: String,
+
+    /// Compact the write-ahead log once it grows past this many bytes.
+    pub wal_limit: u64,
+
+    /// How often the data is saved to `data_dir`, in seconds.
+    pub snapshot_interval: u64,
+
+    /// A filter in the format of `RUST_LOG`.
+    pub log_level: String,
+
+    /// The seed to start from, instead of a root profile with a random
+    /// password.
+    pub seed_file: Option<PathBuf>,
+
+    /// A fixture replacing the data on startup.
+    pub import_file: Option<PathBuf>,
+
+    /// Where to write a fixture of the data on shutdown.
+    pub export_file: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3030,
+            jwt_secret: None,
+            jwt_secret_file: None,
+            jwt_rotation_grace: DEFAULT_JWT_ROTATION_GRACE,
+            jwt_algorithm: String::from("HS256"),
+            jwt_private_key_file: None,
+            jwt_expiry: DEFAULT_JWT_EXPIRY,
+            jwt_issuer: String::from(DEFAULT_JWT_ISSUER),
+            jwt_audience: String::from(DEFAULT_JWT_AUDIENCE),
+            refresh_token_expiry: DEFAULT_REFRESH_EXPIRY,
+            cors_origins: vec![String::from("*")],
+            cors_methods: ["OPTIONS", "GET", "POST", "PUT"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            body_limit: DEFAULT_BODY_LIMIT,
+            oidc_redirect_uris: Vec::new(),
+            lockout_threshold: Policy::default().username_threshold,
+            lockout_ip_threshold: Policy::default().ip_threshold,
+            lockout_duration: Policy::default().duration,
+            lockout_max_duration: Policy::default().max_duration,
+            mail_from: String::from(DEFAULT_MAIL_FROM),
+            outbox_dir: None,
+            password_reset_url: None,
+            password_reset_expiry: DEFAULT_PASSWORD_RESET_EXPIRY,
+            data_dir: None,
+            storage: String::from("memory"),
+            wal_limit: crate::store::DEFAULT_WAL_LIMIT,
+            snapshot_interval: 60,
+            // Only show access logs, use `auth=debug` to see debug logs.
+            log_level: String::from("auth=info"),
+            seed_file: None,
+            import_file: None,
+            export_file: None,
+        }
+    }
+}
+
+// Every setting with its environment variable. Flags are named after the
+// setting, as in `--jwt-secret`.
+const VARIABLES: &[(&str, &str)] = &[
+    ("host", "HOST"),
+    ("port", "PORT"),
+    ("jwt_secret", "JWT_SECRET"),
+    ("jwt_secret_file", "JWT_SECRET_FILE"),
+    ("jwt_rotation_grace", "JWT_ROTATION_GRACE"),
+    ("jwt_algorithm", "JWT_ALGORITHM"),
+    ("jwt_private_key_file", "JWT_PRIVATE_KEY_FILE"),
+    ("jwt_expiry", "JWT_EXPIRY"),
+    ("jwt_issuer", "JWT_ISSUER"),
+    ("jwt_audience", "JWT_AUDIENCE"),
+    ("refresh_token_expiry", "REFRESH_TOKEN_EXPIRY"),
+    ("cors_origins", "CORS_ORIGINS"),
+    ("cors_methods", "CORS_METHODS"),
+    ("body_limit", "BODY_LIMIT"),
+    ("oidc_redirect_uris", "OIDC_REDIRECT_URIS"),
+    ("lockout_threshold", "LOCKOUT_THRESHOLD"),
+    ("lockout_ip_threshold", "LOCKOUT_IP_THRESHOLD"),
+    ("lockout_duration", "LOCKOUT_DURATION"),
+    ("lockout_max_duration", "LOCKOUT_MAX_DURATION"),
+    ("mail_from", "MAIL_FROM"),
+    ("outbox_dir", "OUTBOX_DIR"),
+    ("password_reset_url", "PASSWORD_RESET_URL"),
+    ("password_reset_expiry", "PASSWORD_RESET_EXPIRY"),
+    ("data_dir", "DATA_DIR"),
+    ("storage", "STORAGE"),
+    ("wal_limit", "WAL_LIMIT"),
+    ("snapshot_interval", "SNAPSHOT_INTERVAL"),
+    ("log_level", "RUST_LOG"),
+    ("seed_file", "SEED_FILE"),
+    ("import_file", "IMPORT_FILE"),
+    ("export_file", "EXPORT_FILE"),
+];
+
+pub const USAGE: &str = "Usage: dummy-api [--config FILE] [--SETTING VALUE]...
+
+Settings are read from FILE (or CONFIG_FILE, or dummy-api.toml), then from
+the environment and then from the command line:
+
+    --host ADDRESS             HOST               (0.0.0.0)
+    --port PORT                PORT               (3030)
+    --jwt-secret SECRET        JWT_SECRET         (generated)
+    --jwt-secret-file FILE     JWT_SECRET_FILE
+    --jwt-rotation-grace SECS  JWT_ROTATION_GRACE (86400)
+    --jwt-algorithm HS256|RS256|EdDSA
+                               JWT_ALGORITHM      (HS256)
+    --jwt-private-key-file FILE
+                               JWT_PRIVATE_KEY_FILE
+    --jwt-expiry SECONDS       JWT_EXPIRY         (3600)
+    --jwt-issuer ISSUER        JWT_ISSUER         (dummy-api)
+    --jwt-audience AUDIENCE    JWT_AUDIENCE       (dummy-api)
+    --refresh-token-expiry SECONDS
+                               REFRESH_TOKEN_EXPIRY (2592000)
+    --cors-origins ORIGINS     CORS_ORIGINS       (*)
+    --cors-methods METHODS     CORS_METHODS       (OPTIONS,GET,POST,PUT)
+    --body-limit BYTES         BODY_LIMIT         (16384)
+    --oidc-redirect-uris URIS  OIDC_REDIRECT_URIS
+    --lockout-threshold COUNT  LOCKOUT_THRESHOLD  (5)
+    --lockout-ip-threshold COUNT
+                               LOCKOUT_IP_THRESHOLD (20)
+    --lockout-duration SECS    LOCKOUT_DURATION   (60)
+    --lockout-max-duration SECS
+                               LOCKOUT_MAX_DURATION (3600)
+    --mail-from ADDRESS        MAIL_FROM          (dummy-api@localhost)
+    --outbox-dir DIR           OUTBOX_DIR
+    --password-reset-url URL   PASSWORD_RESET_URL
+    --password-reset-expiry SECS
+                               PASSWORD_RESET_EXPIRY (3600)
+    --data-dir DIR             DATA_DIR
+    --storage memory|sqlite    STORAGE            (memory)
+    --wal-limit BYTES          WAL_LIMIT          (1048576)
+    --snapshot-interval SECS   SNAPSHOT_INTERVAL  (60)
+    --log-level FILTER         RUST_LOG           (auth=info)
+    --seed-file FILE           SEED_FILE
+    --import-file FILE         IMPORT_FILE
+    --export-file FILE         EXPORT_FILE
+
+Lists are separated by commas.";
+
+impl Settings {
+    /// Reads the settings from every source, `var` looking up environment
+    /// variables. Every problem found is reported, not just the first one.
+    pub fn load<F>(args: &[String], var: F) -> Result<Settings, Vec<String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut errors = Vec::new();
+
+        let flags = match parse_args(args) {
+            Ok(flags) => flags,
+            Err(err) => return Err(vec![err]),
+        };
+
+        let file = flags
+            .iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, path)| PathBuf::from(path))
+            .or_else(|| var("CONFIG_FILE").map(PathBuf::from));
+
+        let mut settings = match file {
+            Some(path) => Settings::read(&path).map_err(|err| vec![err])?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Settings::read(Path::new(DEFAULT_CONFIG_FILE)).map_err(|err| vec![err])?
+            }
+            None => Settings::default(),
+        };
+
+        for (key, name) in VARIABLES {
+            if let Some(value) = var(name) {
+                if let Err(err) = settings.set(key, &value) {
+                    errors.push(format!("{}: {}", name, err));
+                }
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            if let Err(err) = settings.set(key, value) {
+                errors.push(format!("--{}: {}", key.replace('_', "-"), err));
+            }
+        }
+
+        errors.extend(settings.validate());
+
+        match errors.is_empty() {
+            true => Ok(settings),
+            false => Err(errors),
+        }
+    }
+
+    /// How failed logins lock out usernames and addresses.
+    pub fn lockout(&self) -> Policy {
+        Policy {
+            username_threshold: self.lockout_threshold,
+            ip_threshold: self.lockout_ip_threshold,
+            duration: self.lockout_duration,
+            max_duration: self.lockout_max_duration,
+        }
+    }
+
+    fn read(path: &Path) -> Result<Settings, String> {
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let path = || Some(PathBuf::from(value)).filter(|_| !value.is_empty());
+
+        match key {
+            "host" => self.host = parse(value)?,
+            "port" => self.port = parse(value)?,
+            "jwt_secret" => self.jwt_secret = Some(value.to_string()),
+            "jwt_secret_file" => self.jwt_secret_file = path(),
+            "jwt_rotation_grace" => self.jwt_rotation_grace = parse(value)?,
+            "jwt_algorithm" => self.jwt_algorithm = value.to_string(),
+            "jwt_private_key_file" => self.jwt_private_key_file = path(),
+            "jwt_expiry" => self.jwt_expiry = parse(value)?,
+            "jwt_issuer" => self.jwt_issuer = value.to_string(),
+            "jwt_audience" => self.jwt_audience = value.to_string(),
+            "refresh_token_expiry" => self.refresh_token_expiry = parse(value)?,
+            "cors_origins" => self.cors_origins = list(value),
+            "cors_methods" => self.cors_methods = list(value),
+            "body_limit" => self.body_limit = parse(value)?,
+            "oidc_redirect_uris" => self.oidc_redirect_uris = list(value),
+            "lockout_threshold" => self.lockout_threshold = parse(value)?,
+            "lockout_ip_threshold" => self.lockout_ip_threshold = parse(value)?,
+            "lockout_duration" => self.lockout_duration = parse(value)?,
+            "lockout_max_duration" => self.lockout_max_duration = parse(value)?,
+            "mail_from" => self.mail_from = value.to_string(),
+            "outbox_dir" => self.outbox_dir = path(),
+            "password_reset_url" => {
+                self.password_reset_url = Some(value.to_string()).filter(|_| !value.is_empty())
+            }
+            "password_reset_expiry" => self.password_reset_expiry = parse(value)?,
+            "data_dir" => self.data_dir = path(),
+            "storage" => self.storage = value.to_string(),
+            "wal_limit" => self.wal_limit = parse(value)?,
+            "snapshot_interval" => self.snapshot_interval = parse(value)?,
+            "log_level" => self.log_level = value.to_string(),
+            "seed_file" => self.seed_file = path(),
+            "import_file" => self.import_file = path(),
+            "export_file" => self.export_file = path(),
+            _ => return Err(String::from("unknown setting")),
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.port == 0 {
+            errors.push(String::from("port: must not be 0"));
+        }
+
+        if self.jwt_secret.as_deref() == Some("") {
+            errors.push(String::from("jwt_secret: must not be empty"));
+        }
+
+        if self.jwt_secret.is_some() && self.jwt_secret_file.is_some() {
+            errors.push(String::from(
+                "jwt_secret_file: must not be set together with jwt_secret",
+            ));
+        }
+
+        match self.jwt_algorithm.as_str() {
+            "HS256" if self.jwt_private_key_file.is_some() => errors.push(String::from(
+                "jwt_private_key_file: only used with RS256 or EdDSA",
+            )),
+            "RS256" if self.jwt_private_key_file.is_none() => errors.push(String::from(
+                "jwt_private_key_file: required with RS256, only EdDSA keys are generated",
+            )),
+            "HS256" | "RS256" | "EdDSA" => {}
+            other => errors.push(format!(
+                "jwt_algorithm: unknown algorithm `{}`, use `HS256`, `RS256` or `EdDSA`",
+                other
+            )),
+        }
+
+        if self.jwt_expiry == 0 {
+            errors.push(String::from("jwt_expiry: must not be 0"));
+        }
+
+        if self.jwt_issuer.is_empty() {
+            errors.push(String::from("jwt_issuer: must not be empty"));
+        }
+
+        if self.jwt_audience.is_empty() {
+            errors.push(String::from("jwt_audience: must not be empty"));
+        }
+
+        if self.refresh_token_expiry == 0 {
+            errors.push(String::from("refresh_token_expiry: must not be 0"));
+        }
+
+        if self.body_limit == 0 {
+            errors.push(String::from("body_limit: must not be 0"));
+        }
+
+        if self.lockout_duration == 0 {
+            errors.push(String::from("lockout_duration: must not be 0"));
+        }
+
+        if self.lockout_max_duration < self.lockout_duration {
+            errors.push(String::from(
+                "lockout_max_duration: must not be less than lockout_duration",
+            ));
+        }
+
+        if self.mail_from.is_empty() || !valid_email(&self.mail_from) {
+            errors.push(format!("mail_from: invalid address `{}`", self.mail_from));
+        }
+
+        if let Some(url) = &self.password_reset_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") || url.contains('#') {
+                errors.push(format!("password_reset_url: invalid URL `{}`", url));
+            }
+        }
+
+        if self.password_reset_expiry == 0 {
+            errors.push(String::from("password_reset_expiry: must not be 0"));
+        }
+
+        if self.snapshot_interval == 0 {
+            errors.push(String::from("snapshot_interval: must not be 0"));
+        }
+
+        for origin in &self.cors_origins {
+            let valid =
+                origin == "*" || origin.starts_with("http://") || origin.starts_with("https://");
+
+            if !valid {
+                errors.push(format!("cors_origins: invalid origin `{}`", origin));
+            }
+        }
+
+        for method in &self.cors_methods {
+            if method.parse::<Method>().is_err() {
+                errors.push(format!("cors_methods: invalid method `{}`", method));
+            }
+        }
+
+        // Absolute URIs without a fragment, custom schemes of native apps
+        // included.
+        for uri in &self.oidc_redirect_uris {
+            let valid = uri.split_once(':').is_some_and(|(scheme, rest)| {
+                !scheme.is_empty() && !rest.is_empty() && !uri.contains('#')
+            });
+
+            if !valid {
+                errors.push(format!("oidc_redirect_uris: invalid URI `{}`", uri));
+            }
+        }
+
+        if self.storage != "memory" && self.storage != "sqlite" {
+            errors.push(format!(
+                "storage: unknown storage `{}`, use `memory` or `sqlite`",
+                self.storage
+            ));
+        }
+
+        errors
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{}`", value))
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Splits `--some-flag value` and `--some-flag=value` into setting names and
+// values.
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(format!("unexpected argument `{}`", arg)),
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => return Err(format!("--{}: missing value", flag)),
+            },
+        };
+
+        flags.push((name.replace('-', "_"), value));
+    }
+
+    Ok(flags)
+}
+
+#[test]
+fn test_settings_layers() {
+    let dir = std::env::temp_dir().join(format!("dummy-api-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file = dir.join("config.toml");
+    fs::write(
+        &file,
+        "port = 8000\nbody_limit = 1024\nstorage = \"sqlite\"\n",
+    )
+    .unwrap();
+
+    let args: Vec<String> = ["--config", file.to_str().unwrap(), "--port=9000"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+    let var = |name: &str| match name {
+        "PORT" => Some(String::from("8500")),
+        "BODY_LIMIT" => Some(String::from("2048")),
+        "CORS_ORIGINS" => Some(String::from("http://a.test, https://b.test")),
+        "JWT_ISSUER" => Some(String::from("staging")),
+        "LOCKOUT_THRESHOLD" => Some(String::from("3")),
+        "PASSWORD_RESET_URL" => Some(String::from("https://app.test/reset")),
+        _ => None,
+    };
+
+    let settings = Settings::load(&args, var).unwrap();
+    assert_eq!(settings.port, 9000);
+    assert_eq!(settings.body_limit, 2048);
+    assert_eq!(settings.storage, "sqlite");
+    assert_eq!(
+        settings.cors_origins,
+        vec!["http://a.test", "https://b.test"]
+    );
+    assert_eq!(settings.jwt_expiry, DEFAULT_JWT_EXPIRY);
+    assert_eq!(settings.jwt_issuer, "staging");
+    assert_eq!(settings.jwt_audience, DEFAULT_JWT_AUDIENCE);
+    assert_eq!(settings.lockout().username_threshold, 3);
+    assert_eq!(settings.lockout().ip_threshold, 20);
+    assert_eq!(
+        settings.password_reset_url.as_deref(),
+        Some("https://app.test/reset")
+    );
+    assert_eq!(settings.mail_from, DEFAULT_MAIL_FROM);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_settings_errors() {
+    let args: Vec<String> = ["--port", "http", "--cors-methods", "GET,FETCH PLEASE"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+    let var = |name: &str| match name {
+        "STORAGE" => Some(String::from("postgres")),
+        "JWT_ALGORITHM" => Some(String::from("RS256")),
+        "LOCKOUT_MAX_DURATION" => Some(String::from("30")),
+        "MAIL_FROM" => Some(String::from("Dummy API <noreply@app.test>")),
+        _ => None,
+    };
+
+    let errors = Settings::load(&args, var).unwrap_err();
+    assert_eq!(
+        errors,
+        vec![
+            "--port: invalid value `http`",
+            "jwt_private_key_file: required with RS256, only EdDSA keys are generated",
+            "lockout_max_duration: must not be less than lockout_duration",
+            "mail_from: invalid address `Dummy API <noreply@app.test>`",
+            "cors_methods: invalid method `FETCH PLEASE`",
+            "storage: unknown storage `postgres`, use `memory` or `sqlite`",
+        ]
+    );
+
+    let args = vec![String::from("--colour"), String::from("blue")];
+    assert_eq!(
+        Settings::load(&args, |_| None).unwrap_err(),
+        vec!["--colour: unknown setting"]
+    );
+}
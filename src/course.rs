@@ -1,7 +1,8 @@
 use super::auth;
+use super::config;
 use super::handlers;
-use super::models::course::{self, Course};
-use super::models::ListOptions;
+use super::models::course::Course;
+use super::models::{Id, ListOptions};
 use std::convert::Infallible;
 use warp::Filter;
 use super::store::Db;
@@ -29,7 +30,7 @@ pub fn create(
 pub fn get(
     db: Db,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("courses" / u8)
+    warp::path!("courses" / Id)
         .and(warp::get())
         .and(with_db(db.clone()))
         .and(auth::with_auth(db.clone()))
@@ -39,7 +40,7 @@ pub fn get(
 pub fn update(
     db: Db,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("courses" / u8)
+    warp::path!("courses" / Id)
         .and(warp::put())
         .and(json_body())
         .and(with_db(db.clone()))
@@ -65,23 +66,16 @@ fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
 fn json_body() -> impl Filter<Extract = (Course,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    warp::body::content_length_limit(config::body_limit()).and(warp::body::json())
 }
 
-pub async fn find(id: u8, db: &Db) -> Result<Course, Box<dyn std::error::Error>> {
+pub async fn find(id: Id, db: &Db) -> Result<Course, Box<dyn std::error::Error>> {
     if id == 0 {
         return Err("Course ID is required!".into());
     }
 
-    let db = db.lock().await;
-
-    let docs: &Vec<Vec<u8>> = db.get(course::COURSES).unwrap();
-    for data in docs.iter() {
-        let course: Course = bincode::deserialize(data).unwrap();
-        if course.id == id {
-            return Ok(course);
-        }
+    match db.read::<Course>().await?.get(id)? {
+        Some(course) => Ok(course),
+        None => Err("invalid course".into()),
     }
-
-    Err("invalid course".into())
 }
@@ -1,3 +1,300 @@
+pub mod admin {
+    use crate::auth::{self, Actor};
+    use crate::config;
+    use crate::handlers::apiresponse;
+    use crate::models::api_key::{self, ApiKey, NewApiKey};
+    use crate::models::fixture::{self, Fixture};
+    use crate::models::lockout;
+    use crate::models::outbox::{self, OutboxQuery};
+    use crate::models::profile::{Kind, Profile};
+    use crate::models::Id;
+    use crate::store::Db;
+    use serde_json::json;
+    use std::convert::Infallible;
+
+    pub async fn export(db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_export");
+
+        if !user.can("admin") {
+            return apiresponse::forbidden();
+        }
+
+        match fixture::export(&db).await {
+            Ok(fixture) => apiresponse::ok(json!(fixture)),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    pub async fn import(
+        fixture: Fixture,
+        db: Db,
+        user: auth::User,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_import");
+
+        if !user.can("admin") {
+            return apiresponse::forbidden();
+        }
+
+        match fixture::import(&db, &fixture, config::jwt_expiry()).await {
+            Ok(()) => apiresponse::ok(json!({
+                "profiles": fixture.profiles.len(),
+                "courses": fixture.courses.len(),
+                "topics": fixture.topics.len(),
+            })),
+            Err(fixture::Error::Invalid(reason)) => apiresponse::bad_request(&reason),
+            Err(fixture::Error::Storage(err)) => apiresponse::storage_error(err),
+        }
+    }
+
+    pub async fn revoke_sessions(
+        id: Id,
+        db: Db,
+        user: auth::User,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_revoke_sessions: {}", id);
+
+        if !user.can("admin") {
+            return apiresponse::forbidden();
+        }
+
+        match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(id))
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => return apiresponse::not_found("Profile not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        }
+
+        match auth::revoke_profile(&db, id).await {
+            Ok(sessions) => apiresponse::ok(json!({ "id": id, "sessions": sessions })),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Lets a profile locked out by failed logins try again.
+    pub async fn unlock(id: Id, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_unlock: {}", id);
+
+        if !user.can("admin") {
+            return apiresponse::forbidden();
+        }
+
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::not_found("Profile not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        match lockout::unlock(&db, &account.username).await {
+            Ok(unlocked) => apiresponse::ok(json!({ "id": id, "unlocked": unlocked })),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// The emails the server sent, the most recent first.
+    pub async fn outbox(
+        query: OutboxQuery,
+        db: Db,
+        user: auth::User,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_outbox: {:?}", query.to);
+
+        if !user.can("admin") {
+            return apiresponse::forbidden();
+        }
+
+        match outbox::list(&db, query.to.as_deref()).await {
+            Ok(emails) => apiresponse::ok(json!(emails)),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Issues a token acting as the profile, for reproducing what its user
+    /// sees. The token names the actor and belongs to their login.
+    pub async fn impersonate(
+        id: Id,
+        db: Db,
+        session: auth::Session,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_impersonate: {}", id);
+
+        let user = session.user;
+        if user.role != Kind::Root && user.role != Kind::Admin {
+            return apiresponse::forbidden();
+        }
+
+        // Impersonations do not nest, the actor would be lost.
+        if session.claims.act.is_some() {
+            return apiresponse::bad_request("Already impersonating a profile!");
+        }
+
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::not_found("Profile not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        let actor = Actor {
+            user_id: user.id,
+            role: user.role,
+        };
+
+        if actor.user_id == account.id {
+            return apiresponse::bad_request("Cannot impersonate yourself!");
+        }
+
+        if !actor.can_impersonate(&account) {
+            return apiresponse::forbidden();
+        }
+
+        log::info!(
+            target: "auth",
+            "profile {} started acting as profile {}",
+            actor.user_id,
+            account.id
+        );
+
+        let impersonator = actor.user_id;
+        let token = auth::generate_impersonation_token(
+            account.id,
+            account.kind.clone(),
+            session.claims.sid,
+            actor,
+        );
+
+        match token {
+            Ok(token) => apiresponse::ok(json!({
+                "id": account.id,
+                "role": account.kind,
+                "token": token,
+                "impersonator": impersonator,
+                "expires_in": config::jwt_expiry(),
+            })),
+            Err(err) => apiresponse::signing_error(err),
+        }
+    }
+
+    /// Creates a key for scripts acting for the user. Keys cannot create
+    /// keys, so this takes a session.
+    pub async fn create_api_key(
+        new: NewApiKey,
+        db: Db,
+        session: auth::Session,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_create_api_key: {}", new.name);
+
+        let user = session.user;
+        if !can_manage_api_keys(&user) {
+            return apiresponse::forbidden();
+        }
+
+        // A key would outlive the impersonation.
+        if session.claims.act.is_some() {
+            return apiresponse::forbidden();
+        }
+
+        if new.name.trim().is_empty() {
+            return apiresponse::bad_request("Name is required!");
+        }
+
+        if new.scopes.is_empty() {
+            return apiresponse::bad_request("At least one scope is required!");
+        }
+
+        // A key cannot do more than its creator.
+        if let Some(scope) = new.scopes.iter().find(|scope| !user.can(scope)) {
+            return apiresponse::bad_request(&format!("Scope `{}` is not available!", scope));
+        }
+
+        match api_key::create(&db, new, user.id, user.role).await {
+            Ok((api_key, key)) => {
+                let mut data = describe(&api_key);
+                data["key"] = json!(key);
+                apiresponse::created(data)
+            }
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Lists every key to root users, and their own keys to admins.
+    pub async fn list_api_keys(
+        db: Db,
+        session: auth::Session,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_list_api_keys");
+
+        let user = session.user;
+        if !can_manage_api_keys(&user) {
+            return apiresponse::forbidden();
+        }
+
+        let owner = match user.role {
+            Kind::Root => None,
+            _ => Some(user.id),
+        };
+
+        match api_key::list(&db, owner).await {
+            Ok(keys) => apiresponse::ok(json!(keys.iter().map(describe).collect::<Vec<_>>())),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    pub async fn revoke_api_key(
+        id: Id,
+        db: Db,
+        session: auth::Session,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("admin_revoke_api_key: {}", id);
+
+        let user = session.user;
+        if !can_manage_api_keys(&user) {
+            return apiresponse::forbidden();
+        }
+
+        // Admins only see their own keys.
+        match api_key::list(&db, Some(user.id)).await {
+            Ok(keys) if user.role == Kind::Root || keys.iter().any(|key| key.id == id) => {}
+            Ok(_) => return apiresponse::not_found("API key not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        }
+
+        match api_key::revoke(&db, id).await {
+            Ok(Some(api_key)) => apiresponse::ok(describe(&api_key)),
+            Ok(None) => apiresponse::not_found("API key not found!"),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    fn can_manage_api_keys(user: &auth::User) -> bool {
+        user.role == Kind::Root || user.role == Kind::Admin
+    }
+
+    // Everything but the hash of the key.
+    fn describe(api_key: &ApiKey) -> serde_json::Value {
+        json!({
+            "id": api_key.id,
+            "name": api_key.name,
+            "prefix": api_key.prefix,
+            "profile_id": api_key.profile_id,
+            "scopes": api_key.scopes,
+            "created_at": api_key.created_at,
+            "last_used_at": api_key.last_used_at,
+            "revoked": api_key.revoked,
+        })
+    }
+}
+
 pub mod apiresponse {
     use crate::models::Response;
     use serde_json::json;
@@ -41,6 +338,24 @@ pub mod apiresponse {
         ))
     }
 
+    /// Reports a failure of the database, including documents that cannot
+    /// be decoded, without leaking its details to the client.
+    pub fn storage_error(
+        err: crate::store::Error,
+    ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+        log::error!("storage: {}", err);
+        internal_server_error("Unable to access the database.")
+    }
+
+    /// Reports a token that could not be signed, e.g. with a broken key,
+    /// without leaking its details to the client.
+    pub fn signing_error(
+        err: Box<dyn std::error::Error>,
+    ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+        log::error!("signing: {}", err);
+        internal_server_error("Unable to issue a token.")
+    }
+
     pub fn bad_request(
         message: &str,
     ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
@@ -71,6 +386,36 @@ pub mod apiresponse {
         Ok(warp::reply::with_status(json, StatusCode::OK))
     }
 
+    pub fn error(
+        status: StatusCode,
+        message: &str,
+    ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+        let json = warp::reply::json(&Response {
+            data: json!(null),
+            error: json!(message),
+        });
+        Ok(warp::reply::with_status(json, status))
+    }
+
+    /// Refuses a request for `retry_after` seconds, as told by the
+    /// `Retry-After` header.
+    pub fn too_many_requests(
+        message: &str,
+        retry_after: u64,
+    ) -> Result<warp::reply::WithHeader<warp::reply::WithStatus<warp::reply::Json>>, Infallible>
+    {
+        let json = warp::reply::json(&Response {
+            data: json!(null),
+            error: json!(message),
+        });
+
+        Ok(warp::reply::with_header(
+            warp::reply::with_status(json, StatusCode::TOO_MANY_REQUESTS),
+            "retry-after",
+            retry_after,
+        ))
+    }
+
     pub fn created(
         data: serde_json::Value,
     ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
@@ -83,111 +428,1101 @@ pub mod apiresponse {
     }
 }
 
+pub mod rejection {
+    use crate::auth::AuthError;
+    use crate::handlers::apiresponse;
+    use std::convert::Infallible;
+    use warp::http::StatusCode;
+    use warp::reject;
+    use warp::Rejection;
+
+    /// Turns every rejection into a JSON error response, so that clients get
+    /// the same shape of response whatever went wrong.
+    pub async fn recover(rejection: Rejection) -> Result<impl warp::Reply, Infallible> {
+        if let Some(err) = rejection.find::<AuthError>() {
+            return match err {
+                AuthError::Storage => apiresponse::internal_server_error(err.message()),
+                _ => apiresponse::unauthorized(err.message()),
+            };
+        }
+
+        if rejection.is_not_found() {
+            return apiresponse::not_found("Not found!");
+        }
+
+        if let Some(err) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+            return apiresponse::bad_request(&err.to_string());
+        }
+
+        if rejection.find::<reject::PayloadTooLarge>().is_some() {
+            return apiresponse::error(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large!");
+        }
+
+        if rejection.find::<reject::UnsupportedMediaType>().is_some() {
+            return apiresponse::error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported media type!",
+            );
+        }
+
+        if rejection.find::<reject::LengthRequired>().is_some() {
+            return apiresponse::error(StatusCode::LENGTH_REQUIRED, "Length required!");
+        }
+
+        if rejection.find::<reject::MethodNotAllowed>().is_some() {
+            return apiresponse::error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed!");
+        }
+
+        if let Some(err) = rejection.find::<reject::InvalidQuery>() {
+            return apiresponse::bad_request(&err.to_string());
+        }
+
+        log::error!("unhandled rejection: {:?}", rejection);
+        apiresponse::internal_server_error("Internal server error!")
+    }
+}
+
 pub mod auth {
-    use crate::auth::generate_token;
+    use crate::auth::{self, generate_token};
+    use crate::config::{self, CONFIG};
     use crate::handlers::apiresponse;
-    use crate::models::profile::{Credentials, Profile, PROFILES};
+    use crate::models::lockout::{self, Attempt};
+    use crate::models::outbox::{self, Email};
+    use crate::models::password_reset::{self, ForgotPassword, ResetPassword};
+    use crate::models::profile::{self, Credentials, Profile, BY_EMAIL};
+    use crate::models::refresh_token::{self, RefreshRequest, Rotation};
+    use crate::models::two_factor::{self, Answer, ChallengeResponse, CodeRequest};
+    use crate::models::Id;
     use crate::store::Db;
+    use crate::totp;
     use serde_json::json;
     use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use warp::reply::Response;
+    use warp::Reply;
 
-    pub async fn login(credentials: Credentials, db: Db) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("auth_login: {:?}", credentials);
+    pub async fn login(
+        credentials: Credentials,
+        remote: Option<SocketAddr>,
+        db: Db,
+    ) -> Result<Response, Infallible> {
+        log::debug!("auth_login: {}", credentials.username);
+
+        let attempt = lockout::check_credentials(
+            &db,
+            &config::lockout(),
+            &credentials.username,
+            &credentials.password,
+            remote.map(|remote| remote.ip()),
+        )
+        .await;
+
+        let account = match attempt {
+            Ok(Attempt::Accepted(account)) => account,
+            Ok(Attempt::Rejected) => {
+                return Ok(
+                    apiresponse::unauthorized("Invalid username or password!").into_response()
+                )
+            }
+            Ok(Attempt::Locked { retry_after }) => {
+                log::warn!("auth_login: {} is locked out", credentials.username);
+                return Ok(
+                    apiresponse::too_many_requests(lockout::LOCKED_MESSAGE, retry_after)
+                        .into_response(),
+                );
+            }
+            Err(err) => return Ok(apiresponse::storage_error(err).into_response()),
+        };
+
+        // The login takes a second step, and the username stays counted
+        // against until it is done.
+        let reply = match two_factor::is_enabled(&db, account.id).await {
+            Ok(true) => match two_factor::challenge(&db, account.id).await {
+                Ok(challenge_token) => apiresponse::ok(json!({
+                    "id": account.id,
+                    "two_factor": true,
+                    "challenge_token": challenge_token,
+                    "expires_in": two_factor::CHALLENGE_LIFETIME,
+                })),
+                Err(err) => apiresponse::storage_error(err),
+            },
+            Ok(false) => sign_in(&db, &account).await,
+            Err(err) => apiresponse::storage_error(err),
+        };
+
+        Ok(reply.into_response())
+    }
 
-        let db = db.lock().await;
-        let docs = db.get(PROFILES).unwrap();
+    /// Completes a login with a code of the authenticator app, or a
+    /// recovery code.
+    pub async fn answer_challenge(
+        response: ChallengeResponse,
+        remote: Option<SocketAddr>,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_answer_challenge");
+
+        let answer = two_factor::answer(&db, &response.challenge_token, &response.code).await;
+
+        let (profile_id, accepted) = match answer {
+            Ok(Answer::Accepted(profile_id)) => (profile_id, true),
+            Ok(Answer::Rejected(profile_id)) => (profile_id, false),
+            Ok(Answer::Invalid) => return apiresponse::unauthorized("Invalid challenge token!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(profile_id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::unauthorized("Invalid challenge token!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        if !accepted {
+            let ip = remote.map(|remote| remote.ip());
+            let failure =
+                lockout::record_failure(&db, &config::lockout(), &account.username, ip).await;
+
+            return match failure {
+                Ok(()) => apiresponse::unauthorized("Invalid two-factor code!"),
+                Err(err) => apiresponse::storage_error(err),
+            };
+        }
 
-        for doc in docs.iter() {
-            let account: Profile = bincode::deserialize(doc).unwrap();
-            if account.username == credentials.username && account.password == credentials.password
-            {
-                return apiresponse::ok(json!({
-                    "id": account.id,
-                    "token": generate_token(account.id).unwrap(),
-                    "role": account.kind,
-                }));
+        sign_in(&db, &account).await
+    }
+
+    /// Starts enrolling the user in two-factor authentication, with a
+    /// secret to add to their authenticator app.
+    pub async fn enroll_two_factor(
+        session: auth::Session,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_enroll_two_factor: {}", session.user.id);
+
+        // Impersonators could lock the real owner out of their profile.
+        if session.claims.act.is_some() {
+            return apiresponse::forbidden();
+        }
+
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(session.user.id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::not_found("Profile not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        match two_factor::enroll(&db, account.id).await {
+            Ok(Some(enrolled)) => {
+                let config = CONFIG
+                    .get()
+                    .expect("Application is not properly configured.");
+                let issuer = &config.jwt_issuer;
+                apiresponse::ok(json!({
+                    "secret": enrolled.secret,
+                    "otpauth_uri": totp::uri(issuer, &account.username, &enrolled.secret),
+                }))
+            }
+            Ok(None) => apiresponse::bad_request("Two-factor authentication is already enabled!"),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Enables two-factor authentication once the app shows the right
+    /// codes, and returns the recovery codes.
+    pub async fn confirm_two_factor(
+        request: CodeRequest,
+        session: auth::Session,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_confirm_two_factor: {}", session.user.id);
+
+        if session.claims.act.is_some() {
+            return apiresponse::forbidden();
+        }
+
+        match two_factor::find(&db, session.user.id).await {
+            Ok(Some(found)) if !found.enabled => {}
+            Ok(Some(_)) => {
+                return apiresponse::bad_request("Two-factor authentication is already enabled!")
+            }
+            Ok(None) => {
+                return apiresponse::bad_request("Two-factor authentication is not enrolled!")
+            }
+            Err(err) => return apiresponse::storage_error(err),
+        }
+
+        match two_factor::confirm(&db, session.user.id, &request.code).await {
+            Ok(Some(codes)) => apiresponse::ok(json!({ "recovery_codes": codes })),
+            Ok(None) => apiresponse::bad_request("Invalid two-factor code!"),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Turns two-factor authentication off, given a code or a recovery code.
+    pub async fn disable_two_factor(
+        request: CodeRequest,
+        session: auth::Session,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_disable_two_factor: {}", session.user.id);
+
+        if session.claims.act.is_some() {
+            return apiresponse::forbidden();
+        }
+
+        let id = session.user.id;
+        if let Some(reply) = reject_code(&db, id, &request.code).await {
+            return reply;
+        }
+
+        match two_factor::disable(&db, id).await {
+            Ok(_) => apiresponse::ok(json!({ "id": id, "two_factor": false })),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Replaces the recovery codes, given a code or a recovery code.
+    pub async fn regenerate_recovery_codes(
+        request: CodeRequest,
+        session: auth::Session,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_regenerate_recovery_codes: {}", session.user.id);
+
+        if session.claims.act.is_some() {
+            return apiresponse::forbidden();
+        }
+
+        let id = session.user.id;
+        if let Some(reply) = reject_code(&db, id, &request.code).await {
+            return reply;
+        }
+
+        match two_factor::regenerate_recovery_codes(&db, id).await {
+            Ok(Some(codes)) => apiresponse::ok(json!({ "recovery_codes": codes })),
+            Ok(None) => apiresponse::bad_request("Two-factor authentication is not enabled!"),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    // An error unless two-factor authentication is enabled for `profile_id`
+    // and `code` is one of its codes.
+    async fn reject_code(
+        db: &Db,
+        profile_id: Id,
+        code: &str,
+    ) -> Option<Result<warp::reply::WithStatus<warp::reply::Json>, Infallible>> {
+        match two_factor::is_enabled(db, profile_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Some(apiresponse::bad_request(
+                    "Two-factor authentication is not enabled!",
+                ))
             }
+            Err(err) => return Some(apiresponse::storage_error(err)),
+        }
+
+        match two_factor::verify(db, profile_id, code).await {
+            Ok(true) => None,
+            Ok(false) => Some(apiresponse::bad_request("Invalid two-factor code!")),
+            Err(err) => Some(apiresponse::storage_error(err)),
+        }
+    }
+
+    // Issues the tokens of a new login, which clears its failed attempts.
+    async fn sign_in(
+        db: &Db,
+        account: &Profile,
+    ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+        if let Err(err) = lockout::unlock(db, &account.username).await {
+            return apiresponse::storage_error(err);
+        }
+
+        let (session, refresh_token) =
+            match refresh_token::create(db, account.id, config::refresh_expiry()).await {
+                Ok(created) => created,
+                Err(err) => return apiresponse::storage_error(err),
+            };
+
+        match generate_token(account.id, account.kind.clone(), session) {
+            Ok(token) => apiresponse::ok(json!({
+                "id": account.id,
+                "token": token,
+                "refresh_token": refresh_token,
+                "role": account.kind,
+            })),
+            Err(err) => apiresponse::signing_error(err),
+        }
+    }
+
+    pub async fn refresh(request: RefreshRequest, db: Db) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_refresh");
+
+        let rotation =
+            refresh_token::rotate(&db, &request.refresh_token, config::refresh_expiry()).await;
+
+        let (profile_id, session, refresh_token) = match rotation {
+            Ok(Rotation::Renewed {
+                profile_id,
+                family,
+                token,
+            }) => (profile_id, family, token),
+            Ok(Rotation::Reused { family }) => {
+                log::warn!("auth_refresh: refresh token reused, revoking its family");
+                if let Err(err) = auth::revoke_login(&db, family).await {
+                    return apiresponse::storage_error(err);
+                }
+                return apiresponse::unauthorized("Invalid refresh token!");
+            }
+            Ok(Rotation::Invalid) => return apiresponse::unauthorized("Invalid refresh token!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        // The profile may have been removed since the login.
+        let account = db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(profile_id));
+
+        let account = match account {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::unauthorized("Invalid refresh token!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        match generate_token(account.id, account.kind.clone(), session) {
+            Ok(token) => apiresponse::ok(json!({
+                "id": account.id,
+                "token": token,
+                "refresh_token": refresh_token,
+                "role": account.kind,
+            })),
+            Err(err) => apiresponse::signing_error(err),
+        }
+    }
+
+    pub async fn logout(session: auth::Session, db: Db) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_logout: {}", session.user.id);
+
+        match auth::revoke_session(&db, &session).await {
+            Ok(()) => apiresponse::ok(json!({ "id": session.user.id })),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Revokes an impersonation token, the actor staying signed in with
+    /// their own tokens.
+    pub async fn end_impersonation(
+        session: auth::Session,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_end_impersonation: {}", session.user.id);
+
+        let actor = match &session.claims.act {
+            Some(actor) => actor.user_id,
+            None => return apiresponse::bad_request("Not impersonating any profile!"),
+        };
+
+        log::info!(
+            target: "auth",
+            "profile {} stopped acting as profile {}",
+            actor,
+            session.user.id
+        );
+
+        match auth::revoke_session(&db, &session).await {
+            Ok(()) => apiresponse::ok(json!({ "id": actor, "impersonated": session.user.id })),
+            Err(err) => apiresponse::storage_error(err),
+        }
+    }
+
+    /// Emails a password reset token to every profile with the address. The
+    /// reply is the same whether there is any, so that it does not tell
+    /// which addresses have a profile.
+    pub async fn forgot_password(
+        request: ForgotPassword,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_forgot_password: {}", request.email);
+
+        let address = request.email.trim();
+        if address.is_empty() || !profile::valid_email(address) {
+            return apiresponse::bad_request("Invalid email address!");
+        }
+
+        let accounts = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.list_by(BY_EMAIL, address, 0, usize::MAX))
+        {
+            Ok(accounts) => accounts,
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        let config = CONFIG
+            .get()
+            .expect("Application is not properly configured.");
+        for account in accounts {
+            let token =
+                match password_reset::create(&db, account.id, config.password_reset_expiry).await {
+                    Ok(token) => token,
+                    Err(err) => return apiresponse::storage_error(err),
+                };
+
+            let instructions = match &config.password_reset_url {
+                Some(url) => {
+                    let separator = if url.contains('?') { '&' } else { '?' };
+                    format!(
+                        "Follow this link to choose a new password:\n\n{}{}token={}",
+                        url, separator, token
+                    )
+                }
+                None => format!("Use this token to choose a new password:\n\n{}", token),
+            };
+
+            let email = Email {
+                from: config.mail_from.clone(),
+                to: account.email.clone(),
+                subject: String::from("Reset your password"),
+                body: format!(
+                    concat!(
+                        "Hello {},\n\n",
+                        "Someone asked to reset the password of your profile. {}\n\n",
+                        "It can be used once, within {} minutes. If you did not ask ",
+                        "for it, you can ignore this email.\n",
+                    ),
+                    account.username,
+                    instructions,
+                    config.password_reset_expiry.div_ceil(60)
+                ),
+                ..Default::default()
+            };
+
+            if let Err(err) = outbox::send(&db, email, config.outbox_dir.as_deref()).await {
+                return apiresponse::storage_error(err);
+            }
+        }
+
+        apiresponse::ok(json!({
+            "message": "If a profile has this address, a reset token was sent to it."
+        }))
+    }
+
+    /// Sets the password of the profile a reset token was sent to, and logs
+    /// it out everywhere.
+    pub async fn reset_password(
+        request: ResetPassword,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_reset_password");
+
+        // Checked first, so that the token is not used up for nothing.
+        if request.password.is_empty() {
+            return apiresponse::bad_request("Password is required!");
+        }
+
+        let id = match password_reset::redeem(&db, &request.token).await {
+            Ok(Some(id)) => id,
+            Ok(None) => return apiresponse::bad_request("Invalid or expired reset token!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        let password = request.password;
+        let hash =
+            match tokio::task::spawn_blocking(move || profile::hash_password(&password)).await {
+                Ok(Ok(hash)) => hash,
+                _ => return apiresponse::internal_server_error("Unable to store the password."),
+            };
+
+        let account = {
+            let mut profiles = match db.write::<Profile>().await {
+                Ok(profiles) => profiles,
+                Err(err) => return apiresponse::storage_error(err),
+            };
+
+            let mut account = match profiles.get(id) {
+                Ok(Some(account)) => account,
+                Ok(None) => return apiresponse::bad_request("Invalid or expired reset token!"),
+                Err(err) => return apiresponse::storage_error(err),
+            };
+
+            account.password = hash;
+            if let Err(err) = profiles.replace(&account) {
+                return apiresponse::storage_error(err);
+            }
+
+            account
+        };
+
+        if let Err(err) = auth::revoke_profile(&db, id).await {
+            return apiresponse::storage_error(err);
+        }
+
+        if let Err(err) = lockout::unlock(&db, &account.username).await {
+            return apiresponse::storage_error(err);
+        }
+
+        if account.email.is_empty() {
+            return apiresponse::ok(json!({ "id": id }));
+        }
+
+        let config = CONFIG
+            .get()
+            .expect("Application is not properly configured.");
+        let email = Email {
+            from: config.mail_from.clone(),
+            to: account.email.clone(),
+            subject: String::from("Your password was changed"),
+            body: format!(
+                concat!(
+                    "Hello {},\n\n",
+                    "The password of your profile was just reset, and every ",
+                    "session of it was logged out.\n",
+                ),
+                account.username
+            ),
+            ..Default::default()
+        };
+
+        if let Err(err) = outbox::send(&db, email, config.outbox_dir.as_deref()).await {
+            return apiresponse::storage_error(err);
         }
 
-        apiresponse::unauthorized("Invalid username or password!")
+        apiresponse::ok(json!({ "id": id }))
+    }
+
+    /// The public keys tokens are signed with, as a JSON Web Key Set. It is
+    /// empty when tokens are signed with a secret.
+    pub async fn jwks() -> Result<impl warp::Reply, Infallible> {
+        log::debug!("auth_jwks");
+
+        let keys: Vec<_> = config::CONFIG
+            .get()
+            .and_then(|config| config.jwt_key_pair.as_ref())
+            .map(|pair| pair.jwk())
+            .into_iter()
+            .collect();
+
+        Ok(warp::reply::json(&json!({ "keys": keys })))
     }
 }
 
-pub mod profile {
-    use crate::auth;
+pub mod oidc {
+    use crate::auth::{self, generate_token, IdClaims, UserInfo};
+    use crate::config::{self, CONFIG};
     use crate::handlers::apiresponse;
-    use crate::models::profile::{Profile, PROFILES};
+    use crate::models::authorization_code::{
+        self, AuthorizationCode, AuthorizationRequest, LoginForm, TokenRequest,
+    };
+    use crate::models::lockout::{self, Attempt};
+    use crate::models::profile::Profile;
+    use crate::models::refresh_token::{self, Rotation};
+    use crate::models::two_factor;
+    use crate::models::Id;
     use crate::store::Db;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::Algorithm;
     use serde_json::json;
+    use sha2::{Digest, Sha256};
     use std::convert::Infallible;
-    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use warp::http::StatusCode;
+    use warp::reply::Response;
+    use warp::Reply;
+
+    pub async fn discovery(base_url: String) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("oidc_discovery");
+
+        let config = CONFIG
+            .get()
+            .expect("Application is not properly configured.");
+
+        let algorithm = config
+            .jwt_key_pair
+            .as_ref()
+            .map(|pair| pair.algorithm)
+            .unwrap_or(Algorithm::HS256);
+
+        Ok(warp::reply::json(&json!({
+            "issuer": config.jwt_issuer,
+            "authorization_endpoint": format!("{}/oauth/authorize", base_url),
+            "token_endpoint": format!("{}/oauth/token", base_url),
+            "userinfo_endpoint": format!("{}/oauth/userinfo", base_url),
+            "jwks_uri": format!("{}/.well-known/jwks.json", base_url),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "refresh_token"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": [algorithm],
+            "scopes_supported": ["openid", "profile"],
+            "token_endpoint_auth_methods_supported": ["none"],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": [
+                "sub", "preferred_username", "given_name", "family_name", "name", "role",
+            ],
+        })))
+    }
 
-    pub async fn create(mut profile: Profile, db: Db) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("profile_create: {:?}", profile);
+    /// Shows the login form to the user the client sent.
+    pub async fn authorize(request: AuthorizationRequest) -> Result<Response, Infallible> {
+        log::debug!("oidc_authorize: {}", request.client_id);
 
-        let mut db = db.lock().await;
+        if let Some(response) = reject_request(&request) {
+            return Ok(response);
+        }
 
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(PROFILES).unwrap();
+        Ok(login_page(&request, None, StatusCode::OK))
+    }
+
+    /// Signs the user in and sends them back to the client with a code.
+    pub async fn login(
+        form: LoginForm,
+        remote: Option<SocketAddr>,
+        db: Db,
+    ) -> Result<Response, Infallible> {
+        log::debug!("oidc_login: {}", form.username);
 
-        match u8::try_from(docs.len()) {
-            Ok(v) => profile.id = v + 1,
-            Err(_) => {
-                return apiresponse::internal_server_error("Unable to provide profile ID.");
+        let request = form.request;
+        if let Some(response) = reject_request(&request) {
+            return Ok(response);
+        }
+
+        let attempt = lockout::check_credentials(
+            &db,
+            &config::lockout(),
+            &form.username,
+            &form.password,
+            remote.map(|remote| remote.ip()),
+        )
+        .await;
+
+        let account = match attempt {
+            Ok(Attempt::Accepted(account)) => account,
+            Ok(Attempt::Rejected) => {
+                return Ok(login_page(
+                    &request,
+                    Some("Invalid username or password!"),
+                    StatusCode::UNAUTHORIZED,
+                ))
+            }
+            Ok(Attempt::Locked { retry_after }) => {
+                log::warn!("oidc_login: {} is locked out", form.username);
+                let page = login_page(
+                    &request,
+                    Some(lockout::LOCKED_MESSAGE),
+                    StatusCode::TOO_MANY_REQUESTS,
+                );
+                return Ok(
+                    warp::reply::with_header(page, "retry-after", retry_after).into_response()
+                );
+            }
+            Err(err) => return Ok(apiresponse::storage_error(err).into_response()),
+        };
+
+        // Without a separate step, the code comes along with the password.
+        let code = match two_factor::is_enabled(&db, account.id).await {
+            Ok(true) => two_factor::verify(&db, account.id, &form.code).await,
+            Ok(false) => Ok(true),
+            Err(err) => Err(err),
+        };
+
+        let failure = match code {
+            Ok(true) => lockout::unlock(&db, &account.username).await.map(|_| None),
+            Ok(false) => {
+                let ip = remote.map(|remote| remote.ip());
+                lockout::record_failure(&db, &config::lockout(), &account.username, ip)
+                    .await
+                    .map(|()| Some("Invalid two-factor code!"))
             }
+            Err(err) => Err(err),
+        };
+
+        match failure {
+            Ok(None) => {}
+            Ok(Some(error)) => {
+                return Ok(login_page(&request, Some(error), StatusCode::UNAUTHORIZED))
+            }
+            Err(err) => return Ok(apiresponse::storage_error(err).into_response()),
         }
 
-        for doc in docs.iter() {
-            let account: Profile = bincode::deserialize(doc).unwrap();
-            if account.username == profile.username {
-                return apiresponse::bad_request("Username is no longer available!");
+        match authorization_code::create(&db, &request, account.id).await {
+            Ok(code) => Ok(redirect(&request, &[("code", &code)])),
+            Err(err) => Ok(apiresponse::storage_error(err).into_response()),
+        }
+    }
+
+    /// Exchanges an authorization code or a refresh token for tokens.
+    pub async fn token(request: TokenRequest, db: Db) -> Result<Response, Infallible> {
+        log::debug!("oidc_token: {}", request.grant_type);
+
+        match request.grant_type.as_str() {
+            "authorization_code" => exchange_code(request, db).await,
+            "refresh_token" => refresh(request, db).await,
+            _ => Ok(oauth_error(
+                "unsupported_grant_type",
+                "Unsupported grant_type!",
+            )),
+        }
+    }
+
+    pub async fn userinfo(db: Db, user: auth::User) -> Result<Response, Infallible> {
+        log::debug!("oidc_userinfo: {}", user.id);
+
+        match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(user.id))
+        {
+            Ok(Some(profile)) => Ok(warp::reply::json(&UserInfo::from(&profile)).into_response()),
+            Ok(None) => Ok(apiresponse::not_found("Profile not found!").into_response()),
+            Err(err) => Ok(apiresponse::storage_error(err).into_response()),
+        }
+    }
+
+    async fn exchange_code(request: TokenRequest, db: Db) -> Result<Response, Infallible> {
+        let (code, redirect_uri, client_id, verifier) = match (
+            request.code,
+            request.redirect_uri,
+            request.client_id,
+            request.code_verifier,
+        ) {
+            (Some(code), Some(redirect_uri), Some(client_id), Some(verifier)) => {
+                (code, redirect_uri, client_id, verifier)
+            }
+            _ => {
+                return Ok(oauth_error(
+                    "invalid_request",
+                    "code, redirect_uri, client_id and code_verifier are required!",
+                ))
             }
+        };
+
+        let grant = match authorization_code::redeem(&db, &code).await {
+            Ok(Some(grant)) => grant,
+            Ok(None) => return Ok(oauth_error("invalid_grant", "Invalid authorization code!")),
+            Err(err) => return Ok(apiresponse::storage_error(err).into_response()),
+        };
+
+        if grant.client_id != client_id || grant.redirect_uri != redirect_uri {
+            return Ok(oauth_error("invalid_grant", "Invalid authorization code!"));
         }
 
-        let id = profile.id;
-        let kind = profile.kind.clone();
+        // PKCE: only the client that started the flow knows the verifier.
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != grant.code_challenge {
+            return Ok(oauth_error("invalid_grant", "Invalid code_verifier!"));
+        }
 
-        let data: Vec<u8> = bincode::serialize(&profile).unwrap();
-        docs.push(data);
+        issue_tokens(&db, &grant).await
+    }
 
-        apiresponse::created(json!({ "id": id, "type": kind }))
+    async fn refresh(request: TokenRequest, db: Db) -> Result<Response, Infallible> {
+        let token = match request.refresh_token {
+            Some(token) => token,
+            None => return Ok(oauth_error("invalid_request", "refresh_token is required!")),
+        };
+
+        let rotation = refresh_token::rotate(&db, &token, config::refresh_expiry()).await;
+
+        match rotation {
+            Ok(Rotation::Renewed {
+                profile_id,
+                family,
+                token,
+            }) => issue_session_tokens(&db, profile_id, family, token, None).await,
+            Ok(Rotation::Reused { family }) => {
+                log::warn!("oidc_token: refresh token reused, revoking its family");
+                match auth::revoke_login(&db, family).await {
+                    Ok(()) => Ok(oauth_error("invalid_grant", "Invalid refresh token!")),
+                    Err(err) => Ok(apiresponse::storage_error(err).into_response()),
+                }
+            }
+            Ok(Rotation::Invalid) => Ok(oauth_error("invalid_grant", "Invalid refresh token!")),
+            Err(err) => Ok(apiresponse::storage_error(err).into_response()),
+        }
     }
 
-    pub async fn get(id: u8, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("profile_get: {:?}", id);
+    // Starts a session for the profile that signed in, as a login to `/auth`
+    // does.
+    async fn issue_tokens(db: &Db, grant: &AuthorizationCode) -> Result<Response, Infallible> {
+        let profile_id = grant.profile_id;
+
+        match refresh_token::create(db, profile_id, config::refresh_expiry()).await {
+            Ok((session, token)) => {
+                issue_session_tokens(db, profile_id, session, token, Some(grant)).await
+            }
+            Err(err) => Ok(apiresponse::storage_error(err).into_response()),
+        }
+    }
 
-        let db = db.lock().await;
-        let docs = db.get(PROFILES).unwrap();
+    // Only the authorization code grant issues an ID token, when the client
+    // asked for the `openid` scope.
+    async fn issue_session_tokens(
+        db: &Db,
+        profile_id: Id,
+        session: Id,
+        refresh_token: String,
+        grant: Option<&AuthorizationCode>,
+    ) -> Result<Response, Infallible> {
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(profile_id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return Ok(oauth_error("invalid_grant", "Profile not found!")),
+            Err(err) => return Ok(apiresponse::storage_error(err).into_response()),
+        };
+
+        let access_token = match generate_token(account.id, account.kind.clone(), session) {
+            Ok(token) => token,
+            Err(err) => return Ok(apiresponse::signing_error(err).into_response()),
+        };
+
+        let mut body = json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": CONFIG.get().map(|config| config.jwt_expiry),
+            "refresh_token": refresh_token,
+        });
+
+        if let Some(grant) = grant {
+            body["scope"] = json!(grant.scope);
+
+            if grant
+                .scope
+                .split_whitespace()
+                .any(|scope| scope == "openid")
+            {
+                let mut claims = IdClaims::new(&account, &grant.client_id, grant.auth_time);
+                claims.nonce = grant.nonce.clone();
 
-        for doc in docs.iter() {
-            let account: Profile = bincode::deserialize(doc).unwrap();
-            if account.id == id {
-                if !user.can_view(&account) {
-                    return apiresponse::forbidden();
+                match auth::generate_id_token(&claims) {
+                    Ok(id_token) => body["id_token"] = json!(id_token),
+                    Err(err) => return Ok(apiresponse::signing_error(err).into_response()),
                 }
-                return apiresponse::ok(json!({
-                    "id": account.id,
-                    "username": account.username,
-                    "firstname": account.first_name,
-                    "lastname": account.last_name,
-                    "type": account.kind,
-                }));
             }
         }
 
-        apiresponse::not_found("Profile not found!")
+        let reply = warp::reply::json(&body);
+        Ok(warp::reply::with_header(reply, "cache-control", "no-store").into_response())
+    }
+
+    // The response to an invalid request. Requests that cannot be sent back
+    // to the client are shown to the user, the others are redirected with an
+    // error.
+    fn reject_request(request: &AuthorizationRequest) -> Option<Response> {
+        let config = CONFIG
+            .get()
+            .expect("Application is not properly configured.");
+
+        if request.client_id.is_empty() {
+            return Some(error_page("The client_id is missing!"));
+        }
+
+        if !config.oidc_redirect_uris.contains(&request.redirect_uri) {
+            return Some(error_page("The redirect_uri is not allowed!"));
+        }
+
+        if request.response_type != "code" {
+            return Some(redirect(request, &[("error", "unsupported_response_type")]));
+        }
+
+        if request.code_challenge.is_empty() || request.code_challenge_method != "S256" {
+            return Some(redirect(
+                request,
+                &[
+                    ("error", "invalid_request"),
+                    ("error_description", "PKCE with S256 is required"),
+                ],
+            ));
+        }
+
+        None
+    }
+
+    fn redirect(request: &AuthorizationRequest, params: &[(&str, &str)]) -> Response {
+        let mut params = params.to_vec();
+        if let Some(state) = &request.state {
+            params.push(("state", state));
+        }
+
+        let separator = match request.redirect_uri.contains('?') {
+            true => '&',
+            false => '?',
+        };
+
+        let location = format!(
+            "{}{}{}",
+            request.redirect_uri,
+            separator,
+            serde_urlencoded::to_string(&params).unwrap_or_default()
+        );
+
+        warp::reply::with_header(StatusCode::FOUND, "location", location).into_response()
+    }
+
+    fn oauth_error(error: &str, description: &str) -> Response {
+        let json = warp::reply::json(&json!({
+            "error": error,
+            "error_description": description,
+        }));
+        warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response()
+    }
+
+    fn login_page(
+        request: &AuthorizationRequest,
+        error: Option<&str>,
+        status: StatusCode,
+    ) -> Response {
+        // The authorization request goes along with the credentials.
+        let mut hidden = String::new();
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(request) {
+            for (name, value) in fields {
+                if let Some(value) = value.as_str() {
+                    hidden.push_str(&format!(
+                        "<input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+                        name,
+                        escape(value)
+                    ));
+                }
+            }
+        }
+
+        let error = error
+            .map(|error| format!("<p class=\"error\">{}</p>", escape(error)))
+            .unwrap_or_default();
+
+        let body = format!(
+            "<h1>Sign in to {}</h1>\n{}<form method=\"post\" action=\"/oauth/authorize\">\n{}\
+             <label>Username <input name=\"username\" autofocus required></label>\n\
+             <label>Password <input name=\"password\" type=\"password\" required></label>\n\
+             <label>Two-factor code, if enabled \
+             <input name=\"code\" autocomplete=\"one-time-code\"></label>\n\
+             <button type=\"submit\">Sign in</button>\n</form>",
+            escape(&request.client_id),
+            error,
+            hidden
+        );
+
+        page(&body, status)
+    }
+
+    fn error_page(message: &str) -> Response {
+        page(
+            &format!(
+                "<h1>Unable to sign in</h1>\n<p class=\"error\">{}</p>",
+                escape(message)
+            ),
+            StatusCode::BAD_REQUEST,
+        )
+    }
+
+    fn page(body: &str, status: StatusCode) -> Response {
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Sign in</title>\
+             <style>label, button {{ display: block; margin: 0.5em 0; }} \
+             .error {{ color: #b00; }}</style></head>\n<body>\n{}\n</body>\n</html>\n",
+            body
+        );
+
+        warp::reply::with_status(warp::reply::html(html), status).into_response()
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#39;")
+    }
+}
+
+pub mod profile {
+    use crate::auth;
+    use crate::handlers::apiresponse;
+    use crate::models::profile::{self, Profile};
+    use crate::models::Id;
+    use crate::store::{Db, Error};
+    use serde_json::json;
+    use std::convert::Infallible;
+
+    pub async fn create(mut profile: Profile, db: Db) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("profile_create: {:?}", profile);
+
+        if !profile::valid_email(&profile.email) {
+            return apiresponse::bad_request("Invalid email address!");
+        }
+
+        let password = profile.password.clone();
+        match tokio::task::spawn_blocking(move || profile::hash_password(&password)).await {
+            Ok(Ok(hash)) => profile.password = hash,
+            _ => return apiresponse::internal_server_error("Unable to store the password."),
+        }
+
+        let mut profiles = match db.write::<Profile>().await {
+            Ok(profiles) => profiles,
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        match profiles.next_id() {
+            Ok(id) => profile.id = id,
+            Err(err) => return apiresponse::storage_error(err),
+        }
+
+        match profiles.insert(&profile) {
+            Ok(()) => {}
+            Err(Error::Conflict(..)) => {
+                return apiresponse::bad_request("Username is no longer available!");
+            }
+            Err(err) => return apiresponse::storage_error(err),
+        }
+
+        apiresponse::created(json!({ "id": profile.id, "type": profile.kind }))
+    }
+
+    pub async fn get(id: Id, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("profile_get: {:?}", id);
+
+        let account = match db
+            .read::<Profile>()
+            .await
+            .and_then(|profiles| profiles.get(id))
+        {
+            Ok(Some(account)) => account,
+            Ok(None) => return apiresponse::not_found("Profile not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        if !user.can("profiles:read") || !user.can_view(&account) {
+            return apiresponse::forbidden();
+        }
+
+        apiresponse::ok(json!({
+            "id": account.id,
+            "username": account.username,
+            "firstname": account.first_name,
+            "lastname": account.last_name,
+            "email": account.email,
+            "type": account.kind,
+        }))
     }
 }
 
 pub mod course {
     use crate::auth;
     use crate::handlers::apiresponse;
-    use crate::models::course::{Course, COURSES};
-    use crate::models::{profile, ListOptions};
-    use crate::store::Db;
+    use crate::models::course::Course;
+    use crate::models::{Id, ListOptions};
+    use crate::store::{Db, Error};
     use serde_json::json;
-    use std;
     use std::convert::Infallible;
-    use std::convert::TryFrom;
 
     pub async fn create(
         course: Course,
@@ -196,103 +1531,90 @@ pub mod course {
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("course_create: {:?}", course);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
-        }
-
-        match user.role {
-            profile::Kind::Trainee | profile::Kind::Mentor => {
-                return apiresponse::forbidden();
-            }
-            _ => {}
+        if !user.can("courses:write") {
+            return apiresponse::forbidden();
         }
 
         let mut course = course.with_creator_id(user.id);
 
-        let mut db = db.lock().await;
+        let mut courses = match db.write::<Course>().await {
+            Ok(courses) => courses,
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(COURSES).unwrap();
-
-        match u8::try_from(docs.len()) {
-            Ok(v) => course.id = v + 1,
-            Err(_) => {
-                return apiresponse::internal_server_error("Unable to provide course ID.");
-            }
+        match courses.next_id() {
+            Ok(id) => course.id = id,
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        for doc in docs.iter() {
-            let existing: Course = bincode::deserialize(doc).unwrap();
-            if existing.title == course.title {
+        match courses.insert(&course) {
+            Ok(()) => {}
+            Err(Error::Conflict(..)) => {
                 return apiresponse::bad_request("Title is no longer available!");
             }
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        docs.push(bincode::serialize(&course).unwrap());
-
         apiresponse::created(json!(course))
     }
 
     pub async fn update(
-        id: u8,
+        id: Id,
         course: Course,
         db: Db,
         user: auth::User,
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("course_update: {:?}", course);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
-        }
-
-        match user.role {
-            profile::Kind::Trainee | profile::Kind::Mentor => {
-                return apiresponse::forbidden();
-            }
-            _ => {}
+        if !user.can("courses:write") {
+            return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
+        let mut courses = match db.write::<Course>().await {
+            Ok(courses) => courses,
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(COURSES).unwrap();
+        let existing = match courses.get(id) {
+            Ok(Some(existing)) => existing,
+            Ok(None) => return apiresponse::not_found("Course not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-        for doc in docs.iter_mut() {
-            let existing: Course = bincode::deserialize(doc).unwrap();
-            if existing.id == id {
-                let creator_id = existing.creator_id;
+        let creator_id = existing.creator_id;
 
-                let mut existing = course.clone();
+        let mut existing = course.clone();
 
-                existing.id = id;
-                existing.creator_id = creator_id;
+        existing.id = id;
+        existing.creator_id = creator_id;
 
-                *doc = bincode::serialize(&existing).unwrap();
-
-                return apiresponse::ok(json!(existing));
+        match courses.replace(&existing) {
+            Ok(_) => {}
+            Err(Error::Conflict(..)) => {
+                return apiresponse::bad_request("Title is no longer available!");
             }
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        apiresponse::not_found("Course not found!")
+        apiresponse::ok(json!(existing))
     }
 
-    pub async fn get(id: u8, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
+    pub async fn get(id: Id, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
         log::debug!("course_get: {}", id);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
+        if !user.can("courses:read") {
+            return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
-
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(COURSES).unwrap();
-
-        for doc in docs.iter() {
-            let course: Course = bincode::deserialize(doc).unwrap();
-            if course.id == id {
-                return apiresponse::ok(json!(course));
-            }
+        match db
+            .read::<Course>()
+            .await
+            .and_then(|courses| courses.get(id))
+        {
+            Ok(Some(course)) => apiresponse::ok(json!(course)),
+            Ok(None) => apiresponse::not_found("Course not found!"),
+            Err(err) => apiresponse::storage_error(err),
         }
-
-        apiresponse::not_found("Course not found!")
     }
 
     pub async fn list(
@@ -302,40 +1624,34 @@ pub mod course {
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("course_list");
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
+        if !user.can("courses:read") {
+            return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
-
-        let docs: Vec<&mut Vec<u8>> = db
-            .get_mut(COURSES)
-            .unwrap()
-            .iter_mut()
-            .skip(opts.offset.unwrap_or(0) as usize)
-            .take(opts.limit.unwrap_or(std::u8::MAX) as usize)
-            .collect::<Vec<&mut Vec<u8>>>();
-
-        let mut courses: Vec<Course> = Vec::new();
+        let courses = db.read::<Course>().await.and_then(|courses| {
+            courses.list(
+                |_| true,
+                opts.offset.unwrap_or(0) as usize,
+                opts.limit.unwrap_or(u64::MAX) as usize,
+            )
+        });
 
-        for doc in docs.iter() {
-            let course: Course = bincode::deserialize(doc).unwrap();
-            courses.push(course);
+        match courses {
+            Ok(courses) => apiresponse::ok(json!(courses)),
+            Err(err) => apiresponse::storage_error(err),
         }
-
-        apiresponse::ok(json!(courses))
     }
 }
 
 pub mod topic {
+    use crate::auth;
     use crate::handlers::apiresponse;
-    use crate::models::topic::{Topic, TOPICS};
-    use crate::models::{profile, ListOptions};
-    use crate::store::Db;
-    use crate::{auth, course};
+    use crate::models::course::{Course, COURSES};
+    use crate::models::topic::{Topic, BY_COURSE, TOPICS};
+    use crate::models::{Id, ListOptions};
+    use crate::store::{Db, Error};
     use serde_json::json;
     use std::convert::Infallible;
-    use std::convert::TryFrom;
 
     pub async fn create(
         topic: Topic,
@@ -344,107 +1660,105 @@ pub mod topic {
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("topic_create: {:?}", topic);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
-        }
-
-        if user.role == profile::Kind::Trainee {
+        if !user.can("topics:write") {
             return apiresponse::forbidden();
         }
 
-        if course::find(topic.course_id, &db).await.is_err() {
-            return apiresponse::bad_request("Course not found!");
+        // The course must not go away before the topic is in.
+        let mut tx = match db.transaction(&[COURSES, TOPICS]).await {
+            Ok(tx) => tx,
+            Err(err) => return apiresponse::storage_error(err),
+        };
+
+        match tx
+            .collection::<Course>()
+            .and_then(|courses| courses.get(topic.course_id))
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => return apiresponse::bad_request("Course not found!"),
+            Err(err) => return apiresponse::storage_error(err),
         }
 
         let mut topic = topic.with_creator_id(user.id);
 
-        let mut db = db.lock().await;
-
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(TOPICS).unwrap();
+        let mut topics = match tx.collection::<Topic>() {
+            Ok(topics) => topics,
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-        match u8::try_from(docs.len()) {
-            Ok(v) => topic.id = v + 1,
-            Err(_) => {
-                return apiresponse::internal_server_error("Unable to provide topics ID.");
-            }
+        match topics.next_id() {
+            Ok(id) => topic.id = id,
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        for doc in docs.iter() {
-            let existing: Topic = bincode::deserialize(doc).unwrap();
-
-            let same_course = existing.course_id == topic.course_id;
-            let same_title = existing.title == topic.title;
-
-            if same_course && same_title {
+        match topics.insert(&topic) {
+            Ok(()) => {}
+            Err(Error::Conflict(..)) => {
                 return apiresponse::bad_request("Title is no longer available!");
             }
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        docs.push(bincode::serialize(&topic).unwrap());
+        tx.commit();
 
         apiresponse::created(json!(topic))
     }
 
-    pub async fn get(id: u8, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
+    pub async fn get(id: Id, db: Db, user: auth::User) -> Result<impl warp::Reply, Infallible> {
         log::debug!("topic_get: {}", id);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
+        if !user.can("topics:read") {
+            return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
-
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(TOPICS).unwrap();
-
-        for doc in docs.iter() {
-            let topic: Topic = bincode::deserialize(doc).unwrap();
-            if topic.id == id {
-                return apiresponse::ok(json!(topic));
-            }
+        match db.read::<Topic>().await.and_then(|topics| topics.get(id)) {
+            Ok(Some(topic)) => apiresponse::ok(json!(topic)),
+            Ok(None) => apiresponse::not_found("Topic not found!"),
+            Err(err) => apiresponse::storage_error(err),
         }
-
-        apiresponse::not_found("Topic not found!")
     }
 
     pub async fn update(
-        id: u8,
+        id: Id,
         topic: Topic,
         db: Db,
         user: auth::User,
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("topic_update: {:?}", topic);
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
-        }
-
-        if user.role == profile::Kind::Trainee {
+        if !user.can("topics:write") {
             return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
-
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(TOPICS).unwrap();
+        let mut topics = match db.write::<Topic>().await {
+            Ok(topics) => topics,
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-        for doc in docs.iter_mut() {
-            let existing: Topic = bincode::deserialize(doc).unwrap();
-            if existing.id == id {
-                let creator_id = existing.creator_id;
-                let course_id = existing.course_id;
+        let existing = match topics.get(id) {
+            Ok(Some(existing)) => existing,
+            Ok(None) => return apiresponse::not_found("Topic not found!"),
+            Err(err) => return apiresponse::storage_error(err),
+        };
 
-                let mut existing = topic.clone();
+        let creator_id = existing.creator_id;
+        let course_id = existing.course_id;
 
-                existing.id = id;
-                existing.creator_id = creator_id;
-                existing.course_id = course_id;
+        let mut existing = topic.clone();
 
-                *doc = bincode::serialize(&existing).unwrap();
+        existing.id = id;
+        existing.creator_id = creator_id;
+        existing.course_id = course_id;
 
-                return apiresponse::ok(json!(existing));
+        match topics.replace(&existing) {
+            Ok(_) => {}
+            Err(Error::Conflict(..)) => {
+                return apiresponse::bad_request("Title is no longer available!");
             }
+            Err(err) => return apiresponse::storage_error(err),
         }
 
-        apiresponse::not_found("Topic not found!")
+        apiresponse::ok(json!(existing))
     }
 
     pub async fn list(
@@ -454,37 +1768,24 @@ pub mod topic {
     ) -> Result<impl warp::Reply, Infallible> {
         log::debug!("topic_list");
 
-        if user.id == 0 {
-            return apiresponse::unauthorized("");
+        if !user.can("topics:read") {
+            return apiresponse::forbidden();
         }
 
-        let mut db = db.lock().await;
-
-        let docs: Vec<&mut Vec<u8>> = db
-            .get_mut(TOPICS)
-            .unwrap()
-            .iter_mut()
-            .filter(|doc| {
-                // NOTE: This is not effecient.
-                let course_id = opts.course_id.unwrap_or(0);
-                if course_id == 0 {
-                    return true;
-                }
-
-                let topic: Topic = bincode::deserialize(doc).unwrap();
-                topic.course_id == course_id
-            })
-            .skip(opts.offset.unwrap_or(0) as usize)
-            .take(opts.limit.unwrap_or(std::u8::MAX) as usize)
-            .collect::<Vec<&mut Vec<u8>>>();
+        let offset = opts.offset.unwrap_or(0) as usize;
+        let limit = opts.limit.unwrap_or(u64::MAX) as usize;
 
-        let mut topics: Vec<Topic> = Vec::new();
+        let topics =
+            db.read::<Topic>()
+                .await
+                .and_then(|topics| match opts.course_id.unwrap_or(0) {
+                    0 => topics.list(|_| true, offset, limit),
+                    course_id => topics.list_by(BY_COURSE, &course_id, offset, limit),
+                });
 
-        for doc in docs.iter() {
-            let topic: Topic = bincode::deserialize(doc).unwrap();
-            topics.push(topic);
+        match topics {
+            Ok(topics) => apiresponse::ok(json!(topics)),
+            Err(err) => apiresponse::storage_error(err),
         }
-
-        apiresponse::ok(json!(topics))
     }
 }
@@ -0,0 +1,168 @@
+use super::auth;
+use super::config::PreviousSecret;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// The secrets tokens are signed with, kept in the data directory so that
+/// tokens survive restarts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyRing {
+    /// Signs new tokens.
+    pub current: String,
+
+    /// Secrets that were replaced, accepted for a grace period after their
+    /// retirement so that tokens they signed keep working.
+    #[serde(default)]
+    pub previous: Vec<Retired>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Retired {
+    pub secret: String,
+    /// When the secret stopped signing tokens, as a Unix timestamp.
+    pub retired_at: i64,
+}
+
+impl KeyRing {
+    pub fn new(secret: String) -> KeyRing {
+        KeyRing {
+            current: secret,
+            previous: Vec::new(),
+        }
+    }
+
+    /// A key ring with a random secret.
+    pub fn generate() -> KeyRing {
+        KeyRing::new(hex::encode(auth::generate_secret_key(32)))
+    }
+
+    /// Signs tokens with `secret` from now on, retiring the current secret.
+    pub fn rotate(&mut self, secret: String, now: i64) {
+        let retired = std::mem::replace(&mut self.current, secret);
+        self.previous
+            .retain(|previous| previous.secret != self.current);
+        self.previous.insert(
+            0,
+            Retired {
+                secret: retired,
+                retired_at: now,
+            },
+        );
+    }
+
+    /// Forgets the secrets retired more than `grace` seconds ago.
+    pub fn prune(&mut self, grace: u64, now: i64) {
+        self.previous
+            .retain(|previous| previous.retired_at + grace as i64 > now);
+    }
+
+    /// The retired secrets with the time they stop being accepted.
+    pub fn previous_secrets(&self, grace: u64) -> Vec<PreviousSecret> {
+        self.previous
+            .iter()
+            .map(|previous| PreviousSecret {
+                secret: previous.secret.as_bytes().to_vec(),
+                expires: previous.retired_at + grace as i64,
+            })
+            .collect()
+    }
+
+    fn read(path: &Path) -> Result<Option<KeyRing>, Box<dyn Error>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        write_private(path, &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Replaces the file at `path` at once, readable by its owner only.
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads the key ring at `path`, generating it the first time. A `configured`
+/// secret that differs from the current one replaces it, which is how secrets
+/// are rotated.
+pub fn load(
+    path: &Path,
+    configured: Option<&str>,
+    grace: u64,
+    now: i64,
+) -> Result<KeyRing, Box<dyn Error>> {
+    let stored = KeyRing::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    let mut ring = match (stored, configured) {
+        (Some(mut ring), Some(secret)) => {
+            if ring.current != secret {
+                ring.rotate(secret.to_string(), now);
+            }
+            ring
+        }
+        (Some(ring), None) => ring,
+        (None, Some(secret)) => KeyRing::new(secret.to_string()),
+        (None, None) => KeyRing::generate(),
+    };
+
+    ring.prune(grace, now);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    ring.write(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    Ok(ring)
+}
+
+#[test]
+fn test_load_rotate() {
+    let dir = std::env::temp_dir().join(format!("dummy-api-keyring-{}", std::process::id()));
+    let path = dir.join("jwt_secrets.json");
+
+    // generated once, then kept
+    let first = load(&path, None, 60, 1000).unwrap();
+    let again = load(&path, None, 60, 1010).unwrap();
+    assert_eq!(first.current, again.current);
+    assert!(again.previous.is_empty());
+
+    // a new secret retires the generated one
+    let rotated = load(&path, Some("new secret"), 60, 1020).unwrap();
+    assert_eq!(rotated.current, "new secret");
+    assert_eq!(rotated.previous.len(), 1);
+    assert_eq!(rotated.previous[0].secret, first.current);
+    assert_eq!(rotated.previous_secrets(60)[0].expires, 1080);
+
+    // until the grace period is over
+    let same = load(&path, Some("new secret"), 60, 1079).unwrap();
+    assert_eq!(same.previous.len(), 1);
+
+    let pruned = load(&path, Some("new secret"), 60, 1080).unwrap();
+    assert!(pruned.previous.is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
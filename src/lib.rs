@@ -1,8 +1,13 @@
+pub mod admin;
 pub mod auth;
 pub mod handlers;
+pub mod keyring;
 pub mod models;
+pub mod oidc;
 pub mod profile;
+pub mod signing;
 pub mod config;
 pub mod course;
 pub mod store;
 pub mod topic;
+pub mod totp;
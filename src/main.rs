@@ -1,27 +1,75 @@
-use dummy_api::{auth, config, course, models, profile, store, topic};
-use lazy_static::lazy_static;
+use chrono::Utc;
+use dummy_api::keyring::{self, KeyRing};
+use dummy_api::signing::{self, KeyPair};
+use dummy_api::{admin, auth, config, course, handlers, models, oidc, profile, store, topic};
+use jsonwebtoken::Algorithm;
+use std::convert::Infallible;
 use std::env;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+use warp::http::header::{HeaderValue, REFERER, USER_AGENT};
+use warp::hyper::server::conn::AddrStream;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request, Response, Server};
 use warp::Filter;
 
+// Where the JWT secrets are kept inside the data directory.
+const JWT_SECRETS_FILE: &str = "jwt_secrets.json";
+
+// Where a generated signing key is kept inside the data directory.
+const JWT_SIGNING_KEY_FILE: &str = "jwt_signing_key.pem";
+
 #[tokio::main]
 async fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        // Set `RUST_LOG=auth=debug` to see debug logs,
-        // this only shows access logs.
-        env::set_var("RUST_LOG", "auth=info");
-    }
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    // Generate a random secret key to sign JWT tokens with.
-    lazy_static! {
-        static ref SECRET_KEY: String = {
-            let key = auth::generate_secret_key(32);
-            hex::encode(key)
-        };
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::USAGE);
+        return;
     }
 
+    // Every invalid setting is reported before anything is started.
+    let settings =
+        config::Settings::load(&args, |name| env::var(name).ok()).unwrap_or_else(|errors| {
+            eprintln!("Invalid configuration:");
+            for err in errors {
+                eprintln!("\t{}", err);
+            }
+            process::exit(2);
+        });
+
+    env::set_var("RUST_LOG", &settings.log_level);
+
+    let key_ring = load_key_ring(&settings).unwrap_or_else(|err| {
+        eprintln!("Unable to load the JWT secret: {}", err);
+        process::exit(1);
+    });
+
+    let key_pair = load_key_pair(&settings).unwrap_or_else(|err| {
+        eprintln!("Unable to load the JWT signing key: {}", err);
+        process::exit(1);
+    });
+
     config::CONFIG
         .set(config::Config {
-            jwt_secret: SECRET_KEY.as_bytes(),
+            jwt_secret: Box::leak(key_ring.current.clone().into_boxed_str()).as_bytes(),
+            jwt_previous_secrets: key_ring.previous_secrets(settings.jwt_rotation_grace),
+            jwt_key_pair: key_pair,
+            jwt_expiry: settings.jwt_expiry,
+            jwt_issuer: settings.jwt_issuer.clone(),
+            jwt_audience: settings.jwt_audience.clone(),
+            refresh_expiry: settings.refresh_token_expiry,
+            body_limit: settings.body_limit,
+            oidc_redirect_uris: settings.oidc_redirect_uris.clone(),
+            lockout: settings.lockout(),
+            mail_from: settings.mail_from.clone(),
+            outbox_dir: settings.outbox_dir.clone(),
+            password_reset_url: settings.password_reset_url.clone(),
+            password_reset_expiry: settings.password_reset_expiry,
         })
         .expect("Error setting application configuration.");
 
@@ -31,51 +79,329 @@ async fn main() {
         models::profile::PROFILES,
         models::course::COURSES,
         models::topic::TOPICS,
+        models::refresh_token::REFRESH_TOKENS,
+        models::revocation::REVOCATIONS,
+        models::authorization_code::AUTHORIZATION_CODES,
+        models::api_key::API_KEYS,
+        models::lockout::LOCKOUTS,
+        models::two_factor::TWO_FACTORS,
+        models::two_factor::CHALLENGES,
+        models::outbox::OUTBOX,
+        models::password_reset::PASSWORD_RESETS,
     ];
 
-    let db = store::new_db(collections).await;
+    // Changes are logged to disk as they happen, and the log is folded into
+    // a fresh snapshot once it grows past `wal_limit` bytes.
+    let data_dir = settings.data_dir.as_deref();
 
-    let roots = [models::profile::Profile::new()
-        .with_id(1)
-        .with_username(String::from("root"))
-        .with_generated_password()
-        .with_kind(models::profile::Kind::Root)];
+    let db = match open_storage(&settings.storage, data_dir, settings.wal_limit) {
+        Ok(storage) => store::open(collections, storage.as_ref()).await,
+        Err(err) => Err(err),
+    };
 
-    models::profile::initialize(&db, &roots).await;
+    let db = db.unwrap_or_else(|err| {
+        eprintln!("Unable to load data: {}", err);
+        process::exit(1);
+    });
+
+    // Replace the data with a fixture, as exported by `export_file` or
+    // `GET /admin/export`.
+    if let Some(path) = &settings.import_file {
+        if let Err(err) = import_fixture(&db, path).await {
+            eprintln!("Unable to import {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+
+    // Only a seed file asked for is used, as its passwords are well known.
+    let seed = match &settings.seed_file {
+        Some(path) => read_seed(path),
+        None => Ok(generated_seed()),
+    };
+
+    let seed = seed.unwrap_or_else(|err| {
+        eprintln!("Unable to read seed: {}", err);
+        process::exit(1);
+    });
+
+    let added = models::seed::apply(&db, &seed).await.unwrap_or_else(|err| {
+        eprintln!("Unable to seed data: {}", err);
+        process::exit(1);
+    });
+
+    if data_dir.is_some() {
+        tokio::spawn(snapshot_periodically(
+            db.clone(),
+            Duration::from_secs(settings.snapshot_interval),
+        ));
+    }
 
     let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .or(oidc::oidc(db.clone()))
         .or(profile::profiles(db.clone()))
         .or(course::courses(db.clone()))
         .or(topic::topics(db.clone()));
 
     let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec!["Content-Type", "Authorization"])
-        .allow_methods(vec!["OPTIONS", "GET", "POST", "PUT"]);
+        .allow_headers(vec!["Content-Type", "Authorization", "X-API-Key"])
+        .allow_methods(settings.cors_methods.iter().map(String::as_str));
+
+    let cors = match settings.cors_origins.iter().any(|origin| origin == "*") {
+        true => cors.allow_any_origin(),
+        false => cors.allow_origins(settings.cors_origins.iter().map(String::as_str)),
+    };
 
-    // View access logs by setting `RUST_LOG=auth`.
-    let routes = api.with(cors).with(warp::log("auth"));
+    let routes = api.recover(handlers::rejection::recover).with(cors);
 
-    let host = [0, 0, 0, 0];
-    let port = 3030;
+    // Replies to impersonation tokens name the actor, which takes serving
+    // the routes as a service: warp cannot wrap the handling of a request.
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let service = service.clone();
+        let remote = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(service.clone(), request, remote)
+            }))
+        }
+    });
 
-    let host_string = host
-        .iter()
-        .map(|item| item.to_string())
-        .collect::<Vec<String>>()
-        .join(".");
+    let address = SocketAddr::new(settings.host, settings.port);
 
-    println!("Starting dummy server at http://{}:{}", host_string, &port);
+    println!("Starting dummy server at http://{}", address);
 
-    show_root_credentials(&roots);
+    // Seed files list their passwords already, generated ones are only
+    // shown here.
+    if settings.seed_file.is_none() {
+        show_credentials(&added);
+    }
 
     // Start up the server...
-    warp::serve(routes).run((host, port)).await;
+    let server = match Server::try_bind(&address) {
+        Ok(builder) => builder
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown_signal()),
+        Err(err) => {
+            eprintln!("Unable to listen on {}: {}", address, err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = server.await {
+        eprintln!("Server error: {}", err);
+    }
+
+    // Write the data as a fixture on shutdown.
+    if let Some(path) = &settings.export_file {
+        if let Err(err) = export_fixture(&db, path).await {
+            eprintln!("Unable to export {}: {}", path.display(), err);
+        }
+    }
+
+    if let Err(err) = store::snapshot(&db).await {
+        eprintln!("Unable to save data: {}", err);
+        process::exit(1);
+    }
+}
+
+// Serves a request with the routes, naming the actor of impersonation
+// tokens. The address of the client is passed on as a request extension and
+// logged here, as warp only knows it when serving the routes itself. View
+// access logs by setting `log_level` to `auth`.
+async fn handle<S>(
+    mut service: S,
+    mut request: Request<Body>,
+    remote: SocketAddr,
+) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let start = Instant::now();
+    let line = format!(
+        "\"{} {} {:?}\"",
+        request.method(),
+        request.uri().path(),
+        request.version()
+    );
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .unwrap_or("-")
+            .to_string()
+    };
+    let (referer, agent) = (header(REFERER), header(USER_AGENT));
+
+    request.extensions_mut().insert(remote);
+    let reply = async {
+        match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    };
+    let response = auth::mark_impersonation(reply).await;
+
+    log::info!(
+        target: "auth",
+        "{} {} {} \"{}\" \"{}\" {:?}",
+        remote,
+        line,
+        response.status().as_u16(),
+        referer,
+        agent,
+        start.elapsed()
+    );
+
+    Ok(response)
+}
+
+// Tokens only survive restarts when signed with a configured secret, or one
+// kept in the data directory. Secrets replaced there are still accepted for
+// the rotation grace period.
+fn load_key_ring(settings: &config::Settings) -> Result<KeyRing, Box<dyn Error>> {
+    let configured = match &settings.jwt_secret_file {
+        Some(path) => Some(read_secret(path)?),
+        None => settings.jwt_secret.clone(),
+    };
+
+    match &settings.data_dir {
+        Some(dir) => keyring::load(
+            &dir.join(JWT_SECRETS_FILE),
+            configured.as_deref(),
+            settings.jwt_rotation_grace,
+            Utc::now().timestamp(),
+        ),
+        None => Ok(configured
+            .map(KeyRing::new)
+            .unwrap_or_else(KeyRing::generate)),
+    }
 }
 
-fn show_root_credentials(roots: &[models::profile::Profile]) {
-    println!("\nYou can login using the following root credentials.\n");
-    for p in roots {
+// Tokens are signed with a private key for RS256 and EdDSA, generated for
+// EdDSA when none is configured.
+fn load_key_pair(settings: &config::Settings) -> Result<Option<KeyPair>, Box<dyn Error>> {
+    let algorithm = match settings.jwt_algorithm.as_str() {
+        "RS256" => Algorithm::RS256,
+        "EdDSA" => Algorithm::EdDSA,
+        _ => return Ok(None),
+    };
+
+    let generated = settings
+        .data_dir
+        .as_ref()
+        .map(|dir| dir.join(JWT_SIGNING_KEY_FILE));
+
+    signing::load(
+        algorithm,
+        settings.jwt_private_key_file.as_deref(),
+        generated.as_deref(),
+    )
+    .map(Some)
+}
+
+fn read_secret(path: &Path) -> Result<String, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    match text.trim() {
+        "" => Err(format!("{}: the secret is empty", path.display()).into()),
+        secret => Ok(secret.to_string()),
+    }
+}
+
+async fn import_fixture(db: &store::Db, path: &Path) -> Result<(), Box<dyn Error>> {
+    let fixture: models::fixture::Fixture = serde_json::from_slice(&fs::read(path)?)?;
+    models::fixture::import(db, &fixture, config::jwt_expiry()).await?;
+    Ok(())
+}
+
+async fn export_fixture(db: &store::Db, path: &Path) -> Result<(), Box<dyn Error>> {
+    let fixture = models::fixture::export(db).await?;
+    fs::write(path, serde_json::to_vec_pretty(&fixture)?)?;
+    Ok(())
+}
+
+fn open_storage(
+    name: &str,
+    data_dir: Option<&Path>,
+    wal_limit: u64,
+) -> Result<Box<dyn store::Storage>, store::Error> {
+    match (name, data_dir) {
+        ("memory", Some(dir)) => Ok(Box::new(store::MemoryStorage::persistent(dir, wal_limit)?)),
+        ("memory", None) => Ok(Box::<store::MemoryStorage>::default()),
+        ("sqlite", Some(dir)) => {
+            fs::create_dir_all(dir).map_err(|err| store::Error::Io(dir.to_path_buf(), err))?;
+            Ok(Box::new(store::SqliteStorage::open(
+                &dir.join("dummy-api.sqlite3"),
+            )?))
+        }
+        ("sqlite", None) => Ok(Box::new(store::SqliteStorage::open_in_memory()?)),
+        // The settings only allow the storages above.
+        _ => unreachable!("unknown storage `{}`", name),
+    }
+}
+
+async fn snapshot_periodically(db: store::Db, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+
+    // The first tick completes immediately, there is nothing to save yet.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(err) = store::snapshot(&db).await {
+            log::error!("Unable to save data: {}", err);
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Error installing the SIGTERM handler.");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+
+    println!("Shutting down dummy server...");
+}
+
+// Without a seed file there is only a root profile with a random password.
+fn generated_seed() -> models::seed::Seed {
+    models::seed::Seed {
+        profiles: vec![models::profile::Profile::new()
+            .with_username(String::from("root"))
+            .with_generated_password()
+            .with_kind(models::profile::Kind::Root)],
+        ..Default::default()
+    }
+}
+
+fn read_seed(path: &Path) -> Result<models::seed::Seed, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let seed =
+        models::seed::Seed::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(seed)
+}
+
+fn show_credentials(profiles: &[models::profile::Profile]) {
+    if profiles.is_empty() {
+        return;
+    }
+
+    println!("\nYou can login using the following credentials.\n");
+    for p in profiles {
         println!("\tusername: {}\n\tpassword: {}\n", p.username, p.password);
     }
 }
@@ -1,7 +1,60 @@
-use super::store::Db;
+use super::store::{key, Db, Document, Index, Key, Schema};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Identifies a document within its collection.
+pub type Id = u64;
+
+/// Converts a document of `collection` saved with the layout of `version` to
+/// the current layout, so that data written by older releases still loads.
+pub fn upgrade(collection: &str, version: u32, doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+    let mut doc = doc.to_vec();
+
+    if version < 2 {
+        doc = match collection {
+            profile::PROFILES => profile::upgrade_v1(&doc)?,
+            course::COURSES => course::upgrade_v1(&doc)?,
+            topic::TOPICS => topic::upgrade_v1(&doc)?,
+            _ => doc,
+        };
+    }
+
+    if version < 3 && collection == profile::PROFILES {
+        doc = profile::upgrade_v2(&doc)?;
+    }
+
+    if version < 4 && collection == profile::PROFILES {
+        doc = profile::upgrade_v3(&doc)?;
+    }
+
+    if version < 5 && collection == revocation::REVOCATIONS {
+        doc = revocation::upgrade_v4(&doc)?;
+    }
+
+    Ok(doc)
+}
+
+/// Describes how the documents of `collection` are indexed.
+pub fn schema(collection: &str) -> Schema {
+    match collection {
+        profile::PROFILES => Schema::of::<profile::Profile>(),
+        course::COURSES => Schema::of::<course::Course>(),
+        topic::TOPICS => Schema::of::<topic::Topic>(),
+        refresh_token::REFRESH_TOKENS => Schema::of::<refresh_token::RefreshToken>(),
+        revocation::REVOCATIONS => Schema::of::<revocation::Revocation>(),
+        api_key::API_KEYS => Schema::of::<api_key::ApiKey>(),
+        lockout::LOCKOUTS => Schema::of::<lockout::Lockout>(),
+        two_factor::TWO_FACTORS => Schema::of::<two_factor::TwoFactor>(),
+        two_factor::CHALLENGES => Schema::of::<two_factor::Challenge>(),
+        outbox::OUTBOX => Schema::of::<outbox::Email>(),
+        password_reset::PASSWORD_RESETS => Schema::of::<password_reset::PasswordReset>(),
+        authorization_code::AUTHORIZATION_CODES => {
+            Schema::of::<authorization_code::AuthorizationCode>()
+        }
+        _ => Schema::plain(collection),
+    }
+}
+
 #[derive(Serialize)]
 pub struct Response {
     #[serde(skip_serializing_if = "Value::is_null")]
@@ -12,19 +65,29 @@ pub struct Response {
 }
 
 pub mod profile {
-    use bincode;
+    use super::{key, Document, Id, Index, Key};
+    use argon2::password_hash::rand_core::OsRng;
+    use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+    use argon2::Argon2;
     use rand::Rng;
     use serde_derive::{Deserialize, Serialize};
-    use std::error::Error;
+    use std::fmt;
+    use std::sync::OnceLock;
 
     pub const PROFILES: &str = "profiles";
+    pub const BY_USERNAME: &str = "username";
+    pub const BY_EMAIL: &str = "email";
 
-    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    #[derive(Default, Deserialize, Serialize, Clone)]
     pub struct Profile {
         #[serde(default)]
-        pub id: u8,
+        pub id: Id,
 
         pub username: String,
+
+        /// The password as sent by the client, and its salted hash once the
+        /// profile is stored.
+        #[serde(default)]
         pub password: String,
 
         #[serde(default)]
@@ -35,6 +98,10 @@ pub mod profile {
 
         #[serde(default)]
         pub kind: Kind,
+
+        /// Where password resets are sent, if anywhere.
+        #[serde(default)]
+        pub email: String,
     }
 
     impl Profile {
@@ -42,7 +109,7 @@ pub mod profile {
             Profile::default()
         }
 
-        pub fn with_id(mut self, value: u8) -> Profile {
+        pub fn with_id(mut self, value: Id) -> Profile {
             self.id = value;
             self
         }
@@ -76,6 +143,164 @@ pub mod profile {
             self.kind = value;
             self
         }
+
+        pub fn with_email(mut self, value: String) -> Profile {
+            self.email = value;
+            self
+        }
+
+        /// Replaces the password with its salted hash, before storing the
+        /// profile.
+        pub fn hash_password(&mut self) -> Result<(), password_hash::Error> {
+            self.password = hash_password(&self.password)?;
+            Ok(())
+        }
+    }
+
+    // Passwords never show up in logs, hashed or not.
+    impl fmt::Debug for Profile {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Profile")
+                .field("id", &self.id)
+                .field("username", &self.username)
+                .field("password", &"<redacted>")
+                .field("first_name", &self.first_name)
+                .field("last_name", &self.last_name)
+                .field("kind", &self.kind)
+                .field("email", &self.email)
+                .finish()
+        }
+    }
+
+    /// Hashes `password` with Argon2 and a random salt, in the PHC string
+    /// format that also records the salt and parameters.
+    pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    }
+
+    /// Checks `password` against a hash made by `hash_password`, in constant
+    /// time. Profiles without a valid hash never match.
+    pub fn verify_password(hash: &str, password: &str) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// A hash to check passwords against when there is no such profile, so
+    /// that unknown usernames take as long to reject as wrong passwords.
+    pub fn dummy_hash() -> &'static str {
+        static HASH: OnceLock<String> = OnceLock::new();
+        HASH.get_or_init(|| hash_password("").unwrap_or_default())
+    }
+
+    /// Whether `email` can be written to the header of an email, an empty
+    /// address meaning that the profile has none.
+    pub fn valid_email(email: &str) -> bool {
+        if email.is_empty() {
+            return true;
+        }
+
+        // Anything that would let the address break out of its header.
+        let forbidden = |c: char| c.is_whitespace() || c.is_control() || "<>,;\"".contains(c);
+
+        match email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.is_empty()
+                    && !domain.contains('@')
+                    && !email.contains(forbidden)
+            }
+            None => false,
+        }
+    }
+
+    impl Document for Profile {
+        const COLLECTION: &'static str = PROFILES;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_USERNAME,
+                unique: true,
+            },
+            Index {
+                name: BY_EMAIL,
+                unique: false,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.username), key(&self.email)]
+        }
+    }
+
+    // Profiles saved before IDs were widened to 64 bits.
+    #[derive(Deserialize)]
+    struct ProfileV1 {
+        id: u8,
+        username: String,
+        password: String,
+        first_name: String,
+        last_name: String,
+        kind: Kind,
+    }
+
+    pub(crate) fn upgrade_v1(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let legacy: ProfileV1 = bincode::deserialize(doc)?;
+
+        bincode::serialize(&ProfileV3 {
+            id: Id::from(legacy.id),
+            username: legacy.username,
+            password: legacy.password,
+            first_name: legacy.first_name,
+            last_name: legacy.last_name,
+            kind: legacy.kind,
+        })
+    }
+
+    // Profiles saved before passwords were hashed.
+    pub(crate) fn upgrade_v2(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let mut profile: ProfileV3 = bincode::deserialize(doc)?;
+
+        if !profile.password.is_empty() {
+            profile.password = hash_password(&profile.password)
+                .map_err(|err| bincode::ErrorKind::Custom(err.to_string()))?;
+        }
+
+        bincode::serialize(&profile)
+    }
+
+    // Profiles saved before they had an email address.
+    #[derive(Deserialize, Serialize)]
+    struct ProfileV3 {
+        id: Id,
+        username: String,
+        password: String,
+        first_name: String,
+        last_name: String,
+        kind: Kind,
+    }
+
+    pub(crate) fn upgrade_v3(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let legacy: ProfileV3 = bincode::deserialize(doc)?;
+
+        bincode::serialize(&Profile {
+            id: legacy.id,
+            username: legacy.username,
+            password: legacy.password,
+            first_name: legacy.first_name,
+            last_name: legacy.last_name,
+            kind: legacy.kind,
+            email: String::new(),
+        })
     }
 
     fn generate_password(length: usize) -> String {
@@ -93,7 +318,7 @@ pub mod profile {
         password
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+    #[derive(Default, Debug, Deserialize, Serialize, Clone, PartialEq)]
     pub enum Kind {
         #[serde(rename = "root")]
         Root,
@@ -104,56 +329,157 @@ pub mod profile {
         #[serde(rename = "teacher")]
         Mentor,
 
+        #[default]
         #[serde(rename = "student")]
         Trainee,
     }
 
-    impl Default for Kind {
-        fn default() -> Self {
-            Self::Trainee
-        }
-    }
-
-    #[derive(Debug, Deserialize, Serialize, Clone)]
+    #[derive(Deserialize, Serialize, Clone)]
     pub struct Credentials {
         pub username: String,
         pub password: String,
     }
 
-    pub async fn initialize<'a>(db: &'a super::Db, list: &[Profile]) {
-        let mut db = db.lock().await;
+    impl fmt::Debug for Credentials {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Credentials")
+                .field("username", &self.username)
+                .field("password", &"<redacted>")
+                .finish()
+        }
+    }
+
+    /// Adds the profiles in `list` whose username is not taken yet and
+    /// returns the ones that were actually added, so that seeding a database
+    /// restored from disk does not duplicate accounts. Passwords are only
+    /// stored hashed, the returned profiles keep them as given.
+    pub async fn initialize(db: &super::Db, list: &[Profile]) -> Vec<Profile> {
+        let profiles = match db.read::<Profile>().await {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                log::error!("Unable to add profiles: {}", err);
+                return Vec::new();
+            }
+        };
 
-        let docs: &mut Vec<Vec<u8>> = db.get_mut(PROFILES).unwrap();
+        let mut missing = Vec::new();
         for profs in list {
-            let data: Vec<u8> = bincode::serialize(&profs).unwrap();
-            docs.push(data);
+            match profiles.find_by(BY_USERNAME, &profs.username) {
+                Ok(None) => missing.push(profs.clone()),
+                Ok(Some(_)) => {}
+                Err(err) => log::error!("Unable to add profile {}: {}", profs.username, err),
+            }
         }
-    }
+        drop(profiles);
+
+        // Hashing takes a while, better not to hold the lock meanwhile, nor
+        // a worker of the runtime.
+        let hashed = tokio::task::spawn_blocking(move || {
+            let mut hashed = Vec::new();
+            for profs in missing {
+                let mut stored = profs.clone();
+                if let Err(err) = stored.hash_password() {
+                    log::error!("Unable to add profile {}: {}", profs.username, err);
+                    continue;
+                }
+                hashed.push((profs, stored));
+            }
+            hashed
+        })
+        .await;
+
+        let hashed = match hashed {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                log::error!("Unable to add profiles: {}", err);
+                return Vec::new();
+            }
+        };
 
-    pub async fn get_kind(db: &super::Db, id: u8) -> Result<Kind, Box<dyn Error>> {
-        let db = db.lock().await;
+        let mut profiles = match db.write::<Profile>().await {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                log::error!("Unable to add profiles: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut added = Vec::new();
+        for (profs, stored) in hashed {
+            // The username may have been taken while hashing.
+            match profiles.find_by(BY_USERNAME, &profs.username) {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(err) => {
+                    log::error!("Unable to add profile {}: {}", profs.username, err);
+                    continue;
+                }
+            }
+
+            // Profiles without an ID get the next one of the collection.
+            let id = match profs.id {
+                0 => profiles.next_id(),
+                id => Ok(id),
+            };
 
-        let docs: &Vec<Vec<u8>> = db.get(PROFILES).unwrap();
-        for data in docs.iter() {
-            let prof: Profile = bincode::deserialize(data).unwrap();
-            if prof.id == id {
-                return Ok(prof.kind.clone());
+            let id = match id {
+                Ok(id) => id,
+                Err(err) => {
+                    log::error!("Unable to add profile {}: {}", profs.username, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = profiles.insert(&stored.with_id(id)) {
+                log::error!("Unable to add profile {}: {}", profs.username, err);
+                continue;
             }
+            added.push(profs.with_id(id));
         }
 
-        Err("invalid role".into())
+        added
+    }
+
+    /// The profile `username` signs in to, provided `password` is its
+    /// password.
+    pub async fn check_credentials(
+        db: &super::Db,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Profile>, crate::store::Error> {
+        let account = db.read::<Profile>().await?.find_by(BY_USERNAME, username)?;
+        Ok(check_password(account, password).await)
+    }
+
+    /// `account` provided `password` is its password. Without an account the
+    /// password is checked against a dummy hash, so that unknown usernames
+    /// take as long to reject as wrong passwords.
+    pub async fn check_password(account: Option<Profile>, password: &str) -> Option<Profile> {
+        let hash = match &account {
+            Some(account) => account.password.clone(),
+            None => dummy_hash().to_string(),
+        };
+
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || verify_password(&hash, &password))
+            .await
+            .unwrap_or(false);
+
+        account.filter(|_| valid)
     }
 }
 
 pub mod course {
+    use super::{key, Document, Id, Index, Key};
     use serde_derive::{Deserialize, Serialize};
 
     pub const COURSES: &str = "courses";
+    pub const BY_TITLE: &str = "title";
 
     #[derive(Default, Debug, Deserialize, Serialize, Clone)]
     pub struct Course {
         #[serde(default)]
-        pub id: u8,
+        pub id: Id,
 
         #[serde(default)]
         pub title: String,
@@ -162,7 +488,7 @@ pub mod course {
         pub description: String,
 
         #[serde(default)]
-        pub creator_id: u8,
+        pub creator_id: Id,
     }
 
     impl Course {
@@ -170,7 +496,7 @@ pub mod course {
             Course::default()
         }
 
-        pub fn with_id(mut self, value: u8) -> Course {
+        pub fn with_id(mut self, value: Id) -> Course {
             self.id = value;
             self
         }
@@ -185,22 +511,108 @@ pub mod course {
             self
         }
 
-        pub fn with_creator_id(mut self, value: u8) -> Course {
+        pub fn with_creator_id(mut self, value: Id) -> Course {
             self.creator_id = value;
             self
         }
     }
+
+    impl Document for Course {
+        const COLLECTION: &'static str = COURSES;
+
+        const INDEXES: &'static [Index] = &[Index {
+            name: BY_TITLE,
+            unique: true,
+        }];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.title)]
+        }
+    }
+
+    /// Adds the courses in `list` whose title is not taken yet and returns
+    /// the ones that were actually added.
+    pub async fn initialize(db: &super::Db, list: &[Course]) -> Vec<Course> {
+        let mut courses = match db.write::<Course>().await {
+            Ok(courses) => courses,
+            Err(err) => {
+                log::error!("Unable to add courses: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut added = Vec::new();
+        for item in list {
+            match courses.find_by(BY_TITLE, &item.title) {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(err) => {
+                    log::error!("Unable to add course {}: {}", item.title, err);
+                    continue;
+                }
+            }
+
+            // Courses without an ID get the next one of the collection.
+            let course = match item.id {
+                0 => courses.next_id().map(|id| item.clone().with_id(id)),
+                _ => Ok(item.clone()),
+            };
+
+            let course = match course {
+                Ok(course) => course,
+                Err(err) => {
+                    log::error!("Unable to add course {}: {}", item.title, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = courses.insert(&course) {
+                log::error!("Unable to add course {}: {}", item.title, err);
+                continue;
+            }
+            added.push(course);
+        }
+
+        added
+    }
+
+    // Courses saved before IDs were widened to 64 bits.
+    #[derive(Deserialize)]
+    struct CourseV1 {
+        id: u8,
+        title: String,
+        description: String,
+        creator_id: u8,
+    }
+
+    pub(crate) fn upgrade_v1(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let legacy: CourseV1 = bincode::deserialize(doc)?;
+
+        bincode::serialize(&Course {
+            id: Id::from(legacy.id),
+            title: legacy.title,
+            description: legacy.description,
+            creator_id: Id::from(legacy.creator_id),
+        })
+    }
 }
 
 pub mod topic {
+    use super::{key, Document, Id, Index, Key};
     use serde_derive::{Deserialize, Serialize};
 
     pub const TOPICS: &str = "topics";
+    pub const BY_COURSE: &str = "course_id";
+    pub const BY_COURSE_AND_TITLE: &str = "course_id_title";
 
     #[derive(Default, Debug, Deserialize, Serialize, Clone)]
     pub struct Topic {
         #[serde(default)]
-        pub id: u8,
+        pub id: Id,
 
         #[serde(default)]
         pub title: String,
@@ -209,10 +621,10 @@ pub mod topic {
         pub description: String,
 
         #[serde(default)]
-        pub creator_id: u8,
+        pub creator_id: Id,
 
         #[serde(default)]
-        pub course_id: u8,
+        pub course_id: Id,
     }
 
     impl Topic {
@@ -220,7 +632,7 @@ pub mod topic {
             Topic::default()
         }
 
-        pub fn with_id(mut self, value: u8) -> Topic {
+        pub fn with_id(mut self, value: Id) -> Topic {
             self.id = value;
             self
         }
@@ -235,21 +647,2112 @@ pub mod topic {
             self
         }
 
-        pub fn with_creator_id(mut self, value: u8) -> Topic {
+        pub fn with_creator_id(mut self, value: Id) -> Topic {
             self.creator_id = value;
             self
         }
 
-        pub fn with_course_id(mut self, value: u8) -> Topic {
+        pub fn with_course_id(mut self, value: Id) -> Topic {
             self.course_id = value;
             self
         }
     }
+
+    impl Document for Topic {
+        const COLLECTION: &'static str = TOPICS;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_COURSE,
+                unique: false,
+            },
+            Index {
+                name: BY_COURSE_AND_TITLE,
+                unique: true,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.course_id), key(&(self.course_id, &self.title))]
+        }
+    }
+
+    /// Adds the topics in `list` whose title is not taken within their
+    /// course yet and returns the ones that were actually added.
+    pub async fn initialize(db: &super::Db, list: &[Topic]) -> Vec<Topic> {
+        let mut topics = match db.write::<Topic>().await {
+            Ok(topics) => topics,
+            Err(err) => {
+                log::error!("Unable to add topics: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut added = Vec::new();
+        for item in list {
+            match topics.find_by(BY_COURSE_AND_TITLE, &(item.course_id, &item.title)) {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(err) => {
+                    log::error!("Unable to add topic {}: {}", item.title, err);
+                    continue;
+                }
+            }
+
+            // Topics without an ID get the next one of the collection.
+            let topic = match item.id {
+                0 => topics.next_id().map(|id| item.clone().with_id(id)),
+                _ => Ok(item.clone()),
+            };
+
+            let topic = match topic {
+                Ok(topic) => topic,
+                Err(err) => {
+                    log::error!("Unable to add topic {}: {}", item.title, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = topics.insert(&topic) {
+                log::error!("Unable to add topic {}: {}", item.title, err);
+                continue;
+            }
+            added.push(topic);
+        }
+
+        added
+    }
+
+    // Topics saved before IDs were widened to 64 bits.
+    #[derive(Deserialize)]
+    struct TopicV1 {
+        id: u8,
+        title: String,
+        description: String,
+        creator_id: u8,
+        course_id: u8,
+    }
+
+    pub(crate) fn upgrade_v1(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let legacy: TopicV1 = bincode::deserialize(doc)?;
+
+        bincode::serialize(&Topic {
+            id: Id::from(legacy.id),
+            title: legacy.title,
+            description: legacy.description,
+            creator_id: Id::from(legacy.creator_id),
+            course_id: Id::from(legacy.course_id),
+        })
+    }
+}
+
+pub mod refresh_token {
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store::{self, Writer};
+    use chrono::Utc;
+    use rand::Rng;
+    use serde_derive::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+
+    pub const REFRESH_TOKENS: &str = "refresh_tokens";
+    pub const BY_HASH: &str = "hash";
+    pub const BY_FAMILY: &str = "family";
+    pub const BY_PROFILE: &str = "profile_id";
+
+    /// A token that can be exchanged once for a new access token and a new
+    /// refresh token. The token itself is only known to the client.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct RefreshToken {
+        pub id: Id,
+
+        /// Tokens rotated from the same login share the ID of the first one.
+        pub family: Id,
+
+        pub profile_id: Id,
+
+        /// The SHA-256 of the token, hex encoded.
+        pub hash: String,
+
+        /// When the token stops being accepted, as a Unix timestamp.
+        pub expires: i64,
+
+        /// Set once the token was exchanged for a new one.
+        pub used: bool,
+
+        /// Set when a used token of the family was presented again.
+        pub revoked: bool,
+    }
+
+    impl Document for RefreshToken {
+        const COLLECTION: &'static str = REFRESH_TOKENS;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_HASH,
+                unique: true,
+            },
+            Index {
+                name: BY_FAMILY,
+                unique: false,
+            },
+            Index {
+                name: BY_PROFILE,
+                unique: false,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.hash), key(&self.family), key(&self.profile_id)]
+        }
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct RefreshRequest {
+        pub refresh_token: String,
+    }
+
+    /// The outcome of exchanging a refresh token.
+    pub enum Rotation {
+        /// The token was valid, here is the one replacing it.
+        Renewed {
+            profile_id: Id,
+            family: Id,
+            token: String,
+        },
+
+        /// The token was already exchanged before, so it may have been
+        /// stolen. Every refresh token of its family is revoked, the access
+        /// tokens of the login are left to the caller.
+        Reused { family: Id },
+
+        /// The token is unknown, expired or revoked.
+        Invalid,
+    }
+
+    /// Starts a new family of tokens for `profile_id`, valid for `lifetime`
+    /// seconds, and forgets the expired tokens of the profile. Returns the
+    /// family along with the token.
+    pub async fn create(
+        db: &Db,
+        profile_id: Id,
+        lifetime: u64,
+    ) -> Result<(Id, String), store::Error> {
+        let mut tokens = db.write::<RefreshToken>().await?;
+        let now = Utc::now().timestamp();
+
+        for expired in tokens
+            .list_by(BY_PROFILE, &profile_id, 0, usize::MAX)?
+            .into_iter()
+            .filter(|token| token.expires <= now)
+        {
+            tokens.delete(expired.id)?;
+        }
+
+        let id = tokens.next_id()?;
+        let token = generate_token();
+
+        tokens.insert(&RefreshToken {
+            id,
+            family: id,
+            profile_id,
+            hash: hash(&token),
+            expires: now + lifetime as i64,
+            used: false,
+            revoked: false,
+        })?;
+
+        Ok((id, token))
+    }
+
+    /// Exchanges `token` for a new token of the same family, valid for
+    /// `lifetime` seconds.
+    pub async fn rotate(db: &Db, token: &str, lifetime: u64) -> Result<Rotation, store::Error> {
+        let mut tokens = db.write::<RefreshToken>().await?;
+        let now = Utc::now().timestamp();
+
+        let mut current = match tokens.find_by(BY_HASH, &hash(token))? {
+            Some(current) => current,
+            None => return Ok(Rotation::Invalid),
+        };
+
+        if current.revoked || current.expires <= now {
+            return Ok(Rotation::Invalid);
+        }
+
+        if current.used {
+            revoke(&mut tokens, BY_FAMILY, current.family)?;
+            return Ok(Rotation::Reused {
+                family: current.family,
+            });
+        }
+
+        current.used = true;
+        tokens.replace(&current)?;
+
+        let id = tokens.next_id()?;
+        let token = generate_token();
+
+        tokens.insert(&RefreshToken {
+            id,
+            family: current.family,
+            profile_id: current.profile_id,
+            hash: hash(&token),
+            expires: now + lifetime as i64,
+            used: false,
+            revoked: false,
+        })?;
+
+        Ok(Rotation::Renewed {
+            profile_id: current.profile_id,
+            family: current.family,
+            token,
+        })
+    }
+
+    /// Revokes every token of `family`.
+    pub async fn revoke_family(db: &Db, family: Id) -> Result<(), store::Error> {
+        let mut tokens = db.write::<RefreshToken>().await?;
+        revoke(&mut tokens, BY_FAMILY, family)?;
+        Ok(())
+    }
+
+    /// Revokes every token of `profile_id` and returns their families.
+    pub async fn revoke_profile(db: &Db, profile_id: Id) -> Result<Vec<Id>, store::Error> {
+        let mut tokens = db.write::<RefreshToken>().await?;
+        revoke(&mut tokens, BY_PROFILE, profile_id)
+    }
+
+    fn revoke(
+        tokens: &mut Writer<RefreshToken>,
+        index: &str,
+        value: Id,
+    ) -> Result<Vec<Id>, store::Error> {
+        let mut families = Vec::new();
+
+        for mut token in tokens.list_by(index, &value, 0, usize::MAX)? {
+            if !families.contains(&token.family) {
+                families.push(token.family);
+            }
+
+            if !token.revoked {
+                token.revoked = true;
+                tokens.replace(&token)?;
+            }
+        }
+
+        Ok(families)
+    }
+
+    pub(super) fn generate_token() -> String {
+        let bytes: [u8; 32] = rand::thread_rng().gen();
+        hex::encode(bytes)
+    }
+
+    pub(super) fn hash(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+}
+
+pub mod revocation {
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store::{self, Writer};
+    use chrono::Utc;
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const REVOCATIONS: &str = "revocations";
+    pub const BY_JTI: &str = "jti";
+    pub const BY_SESSION: &str = "session";
+    pub const BY_TARGET: &str = "target";
+
+    /// Access tokens that are no longer accepted although they did not
+    /// expire yet: the one with the `jti`, every token of the `session`, or
+    /// every impersonation token of the `target` issued until then.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct Revocation {
+        pub id: Id,
+
+        /// Empty when the whole session is revoked.
+        pub jti: String,
+
+        /// The login the tokens belong to, 0 when only the `jti` is revoked.
+        pub session: Id,
+
+        /// The profile impersonated with the tokens, 0 unless impersonation
+        /// tokens are revoked.
+        pub target: Id,
+
+        /// When the last of the impersonation tokens of `target` was issued.
+        pub issued_until: i64,
+
+        /// When every token it covers has expired, and it can be forgotten.
+        pub expires: i64,
+    }
+
+    impl Document for Revocation {
+        const COLLECTION: &'static str = REVOCATIONS;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_JTI,
+                unique: false,
+            },
+            Index {
+                name: BY_SESSION,
+                unique: false,
+            },
+            Index {
+                name: BY_TARGET,
+                unique: false,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.jti), key(&self.session), key(&self.target)]
+        }
+    }
+
+    /// Revokes the token `jti` and every other token of `session`, until
+    /// `expires`.
+    pub async fn revoke(db: &Db, jti: &str, session: Id, expires: i64) -> Result<(), store::Error> {
+        let mut revocations = db.write::<Revocation>().await?;
+        prune(&mut revocations)?;
+
+        let id = revocations.next_id()?;
+        revocations.insert(&Revocation {
+            id,
+            jti: jti.to_string(),
+            session,
+            expires,
+            ..Revocation::default()
+        })
+    }
+
+    /// Revokes every token of `sessions`, until `expires`.
+    pub async fn revoke_sessions(
+        db: &Db,
+        sessions: &[Id],
+        expires: i64,
+    ) -> Result<(), store::Error> {
+        let mut revocations = db.write::<Revocation>().await?;
+        prune(&mut revocations)?;
+
+        for session in sessions {
+            let id = revocations.next_id()?;
+            revocations.insert(&Revocation {
+                id,
+                session: *session,
+                expires,
+                ..Revocation::default()
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Revokes every impersonation token of `target` issued until now, which
+    /// belong to the sessions of their actors rather than to the target.
+    pub async fn revoke_impersonations(
+        db: &Db,
+        target: Id,
+        expires: i64,
+    ) -> Result<(), store::Error> {
+        let mut revocations = db.write::<Revocation>().await?;
+        prune(&mut revocations)?;
+
+        let id = revocations.next_id()?;
+        revocations.insert(&Revocation {
+            id,
+            target,
+            issued_until: Utc::now().timestamp(),
+            expires,
+            ..Revocation::default()
+        })
+    }
+
+    /// Whether the token `jti` of `session` was revoked, or, for an
+    /// impersonation token of `target` issued at `iat`, every such token.
+    /// Tokens issued before any of them was known are never revoked.
+    pub async fn is_revoked(
+        db: &Db,
+        jti: &str,
+        session: Id,
+        target: Option<Id>,
+        iat: i64,
+    ) -> Result<bool, store::Error> {
+        let revocations = db.read::<Revocation>().await?;
+
+        if !jti.is_empty() && revocations.find_by(BY_JTI, jti)?.is_some() {
+            return Ok(true);
+        }
+
+        if session != 0 && revocations.find_by(BY_SESSION, &session)?.is_some() {
+            return Ok(true);
+        }
+
+        if let Some(target) = target {
+            let revoked = revocations.list_by(BY_TARGET, &target, 0, usize::MAX)?;
+            if revoked
+                .iter()
+                .any(|revocation| iat <= revocation.issued_until)
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Forgets the revocations whose tokens have all expired.
+    fn prune(revocations: &mut Writer<Revocation>) -> Result<(), store::Error> {
+        let now = Utc::now().timestamp();
+
+        for expired in revocations.list(|revocation| revocation.expires <= now, 0, usize::MAX)? {
+            revocations.delete(expired.id)?;
+        }
+
+        Ok(())
+    }
+
+    // Revocations saved before impersonation tokens could be revoked by
+    // their target.
+    #[derive(Deserialize, Serialize)]
+    struct RevocationV4 {
+        id: Id,
+        jti: String,
+        session: Id,
+        expires: i64,
+    }
+
+    pub(crate) fn upgrade_v4(doc: &[u8]) -> Result<Vec<u8>, bincode::Error> {
+        let legacy: RevocationV4 = bincode::deserialize(doc)?;
+
+        bincode::serialize(&Revocation {
+            id: legacy.id,
+            jti: legacy.jti,
+            session: legacy.session,
+            target: 0,
+            issued_until: 0,
+            expires: legacy.expires,
+        })
+    }
+}
+
+pub mod api_key {
+    use super::profile::Kind;
+    use super::refresh_token::{generate_token, hash};
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store;
+    use chrono::Utc;
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const API_KEYS: &str = "api_keys";
+    pub const BY_HASH: &str = "hash";
+    pub const BY_PROFILE: &str = "profile_id";
+
+    /// Starts every key, which tells them apart from access tokens.
+    pub const PREFIX: &str = "dak_";
+
+    // How often the last use of a key is recorded, in seconds, so that
+    // busy keys do not cost a write per request.
+    const LAST_USED_PRECISION: i64 = 60;
+
+    /// A long-lived credential for scripts, acting for the profile that
+    /// created it with a subset of its scopes. The key itself is only shown
+    /// once, when it is created.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct ApiKey {
+        pub id: Id,
+        pub name: String,
+
+        /// The start of the key, to recognize it in listings.
+        pub prefix: String,
+
+        /// The SHA-256 of the key, hex encoded.
+        pub hash: String,
+
+        /// The profile that created the key, and its role at the time.
+        pub profile_id: Id,
+        pub role: Kind,
+
+        pub scopes: Vec<String>,
+
+        /// As Unix timestamps, 0 for a key never used.
+        pub created_at: i64,
+        pub last_used_at: i64,
+
+        pub revoked: bool,
+    }
+
+    impl Document for ApiKey {
+        const COLLECTION: &'static str = API_KEYS;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_HASH,
+                unique: true,
+            },
+            Index {
+                name: BY_PROFILE,
+                unique: false,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.hash), key(&self.profile_id)]
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NewApiKey {
+        pub name: String,
+        pub scopes: Vec<String>,
+    }
+
+    /// Stores a new key and returns it along with the key itself.
+    pub async fn create(
+        db: &Db,
+        new: NewApiKey,
+        profile_id: Id,
+        role: Kind,
+    ) -> Result<(ApiKey, String), store::Error> {
+        let mut keys = db.write::<ApiKey>().await?;
+
+        let key = format!("{}{}", PREFIX, generate_token());
+        let api_key = ApiKey {
+            id: keys.next_id()?,
+            name: new.name,
+            prefix: key[..PREFIX.len() + 8].to_string(),
+            hash: hash(&key),
+            profile_id,
+            role,
+            scopes: new.scopes,
+            created_at: Utc::now().timestamp(),
+            last_used_at: 0,
+            revoked: false,
+        };
+
+        keys.insert(&api_key)?;
+        Ok((api_key, key))
+    }
+
+    /// The key matching `key` unless it was revoked, recording its use.
+    pub async fn verify(db: &Db, key: &str) -> Result<Option<ApiKey>, store::Error> {
+        // A single write lock, so that a key revoked meanwhile is neither
+        // accepted nor restored by recording its use.
+        let mut keys = db.write::<ApiKey>().await?;
+
+        let mut api_key = match keys.find_by(BY_HASH, &hash(key))? {
+            Some(api_key) if !api_key.revoked => api_key,
+            _ => return Ok(None),
+        };
+
+        let now = Utc::now().timestamp();
+        if now - api_key.last_used_at >= LAST_USED_PRECISION {
+            api_key.last_used_at = now;
+            keys.replace(&api_key)?;
+        }
+
+        Ok(Some(api_key))
+    }
+
+    /// The keys created by `profile_id`, or every key.
+    pub async fn list(db: &Db, profile_id: Option<Id>) -> Result<Vec<ApiKey>, store::Error> {
+        let keys = db.read::<ApiKey>().await?;
+
+        match profile_id {
+            Some(profile_id) => keys.list_by(BY_PROFILE, &profile_id, 0, usize::MAX),
+            None => keys.list(|_| true, 0, usize::MAX),
+        }
+    }
+
+    /// Stops accepting the key `id`, and returns it.
+    pub async fn revoke(db: &Db, id: Id) -> Result<Option<ApiKey>, store::Error> {
+        let mut keys = db.write::<ApiKey>().await?;
+
+        let mut api_key = match keys.get(id)? {
+            Some(api_key) => api_key,
+            None => return Ok(None),
+        };
+
+        if !api_key.revoked {
+            api_key.revoked = true;
+            keys.replace(&api_key)?;
+        }
+
+        Ok(Some(api_key))
+    }
+
+    /// Stops accepting every key of `profile_id`, and returns how many were
+    /// still in use.
+    pub async fn revoke_profile(db: &Db, profile_id: Id) -> Result<usize, store::Error> {
+        let mut keys = db.write::<ApiKey>().await?;
+        let mut revoked = 0;
+
+        for mut api_key in keys.list_by(BY_PROFILE, &profile_id, 0, usize::MAX)? {
+            if !api_key.revoked {
+                api_key.revoked = true;
+                keys.replace(&api_key)?;
+                revoked += 1;
+            }
+        }
+
+        Ok(revoked)
+    }
+}
+
+pub mod authorization_code {
+    use super::refresh_token::{generate_token, hash};
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store;
+    use chrono::Utc;
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const AUTHORIZATION_CODES: &str = "authorization_codes";
+    pub const BY_HASH: &str = "hash";
+
+    /// How long a code can be exchanged for tokens, in seconds.
+    pub const LIFETIME: i64 = 60;
+
+    /// What a client asks for when sending the user to sign in, as query
+    /// parameters of the authorization endpoint.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct AuthorizationRequest {
+        pub response_type: String,
+        pub client_id: String,
+        pub redirect_uri: String,
+
+        #[serde(default)]
+        pub scope: String,
+
+        /// Passed back to the client along with the code.
+        pub state: Option<String>,
+
+        /// Passed on to the ID token.
+        pub nonce: Option<String>,
+
+        pub code_challenge: String,
+
+        #[serde(default)]
+        pub code_challenge_method: String,
+    }
+
+    /// The login form of the authorization endpoint, which carries the
+    /// authorization request along.
+    #[derive(Deserialize)]
+    pub struct LoginForm {
+        pub username: String,
+        pub password: String,
+
+        /// A code of the authenticator app, or a recovery code, for profiles
+        /// with two-factor authentication.
+        #[serde(default)]
+        pub code: String,
+
+        #[serde(flatten)]
+        pub request: AuthorizationRequest,
+    }
+
+    /// A request to the token endpoint, either for the `authorization_code`
+    /// or the `refresh_token` grant.
+    #[derive(Default, Deserialize)]
+    pub struct TokenRequest {
+        pub grant_type: String,
+        pub code: Option<String>,
+        pub redirect_uri: Option<String>,
+        pub client_id: Option<String>,
+        pub code_verifier: Option<String>,
+        pub refresh_token: Option<String>,
+    }
+
+    /// Lets a client get tokens for the profile that signed in, once. The
+    /// code itself is only known to the client.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct AuthorizationCode {
+        pub id: Id,
+
+        /// The SHA-256 of the code, hex encoded.
+        pub hash: String,
+
+        pub profile_id: Id,
+        pub client_id: String,
+        pub redirect_uri: String,
+        pub scope: String,
+        pub nonce: Option<String>,
+
+        /// The PKCE challenge the verifier of the client must match.
+        pub code_challenge: String,
+
+        /// When the profile signed in, as a Unix timestamp.
+        pub auth_time: i64,
+
+        /// When the code stops being accepted, as a Unix timestamp.
+        pub expires: i64,
+    }
+
+    impl Document for AuthorizationCode {
+        const COLLECTION: &'static str = AUTHORIZATION_CODES;
+
+        const INDEXES: &'static [Index] = &[Index {
+            name: BY_HASH,
+            unique: true,
+        }];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.hash)]
+        }
+    }
+
+    /// Issues a code granting `request` for `profile_id`, and forgets the
+    /// expired codes.
+    pub async fn create(
+        db: &Db,
+        request: &AuthorizationRequest,
+        profile_id: Id,
+    ) -> Result<String, store::Error> {
+        let mut codes = db.write::<AuthorizationCode>().await?;
+        let now = Utc::now().timestamp();
+
+        for expired in codes.list(|code| code.expires <= now, 0, usize::MAX)? {
+            codes.delete(expired.id)?;
+        }
+
+        let id = codes.next_id()?;
+        let code = generate_token();
+
+        codes.insert(&AuthorizationCode {
+            id,
+            hash: hash(&code),
+            profile_id,
+            client_id: request.client_id.clone(),
+            redirect_uri: request.redirect_uri.clone(),
+            scope: request.scope.clone(),
+            nonce: request.nonce.clone(),
+            code_challenge: request.code_challenge.clone(),
+            auth_time: now,
+            expires: now + LIFETIME,
+        })?;
+
+        Ok(code)
+    }
+
+    /// Takes the grant of `code`, which cannot be used again, or `None` when
+    /// it is unknown, used or expired.
+    pub async fn redeem(db: &Db, code: &str) -> Result<Option<AuthorizationCode>, store::Error> {
+        let mut codes = db.write::<AuthorizationCode>().await?;
+
+        let grant = match codes.find_by(BY_HASH, &hash(code))? {
+            Some(grant) => grant,
+            None => return Ok(None),
+        };
+
+        codes.delete(grant.id)?;
+
+        Ok(Some(grant).filter(|grant| grant.expires > Utc::now().timestamp()))
+    }
+}
+
+pub mod lockout {
+    use super::profile::{self, Profile};
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store::{self, Writer};
+    use chrono::Utc;
+    use serde_derive::{Deserialize, Serialize};
+    use std::net::IpAddr;
+
+    pub const LOCKOUTS: &str = "lockouts";
+    pub const BY_SUBJECT: &str = "subject";
+
+    /// Tells locked out users apart from those mistyping their password.
+    pub const LOCKED_MESSAGE: &str = "Too many failed login attempts, try again later!";
+
+    /// How failed logins are limited. A threshold of 0 never locks.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Policy {
+        /// Failed attempts for a username, and from an address, before
+        /// further attempts are refused.
+        pub username_threshold: u32,
+        pub ip_threshold: u32,
+
+        /// How long the first lockout lasts, in seconds. Each following one
+        /// doubles it, up to `max_duration`, which is also how long failures
+        /// are remembered.
+        pub duration: u64,
+        pub max_duration: u64,
+    }
+
+    impl Default for Policy {
+        fn default() -> Self {
+            Policy {
+                username_threshold: 5,
+                ip_threshold: 20,
+                duration: 60,
+                max_duration: 60 * 60,
+            }
+        }
+    }
+
+    impl Policy {
+        /// How long the lockout following `lockouts` earlier ones lasts, in
+        /// seconds.
+        pub fn lock_duration(&self, lockouts: u32) -> u64 {
+            let factor = 1u64.checked_shl(lockouts).unwrap_or(u64::MAX);
+            self.duration.saturating_mul(factor).min(self.max_duration)
+        }
+    }
+
+    /// The failed logins of a username or of an address.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct Lockout {
+        pub id: Id,
+
+        /// Either `username:{username}` or `ip:{address}`.
+        pub subject: String,
+
+        /// Failures since the last lockout.
+        pub failures: u32,
+
+        /// Lockouts so far, each lasting twice as long as the one before.
+        pub lockouts: u32,
+
+        /// As Unix timestamps.
+        pub locked_until: i64,
+        pub last_failure: i64,
+    }
+
+    impl Document for Lockout {
+        const COLLECTION: &'static str = LOCKOUTS;
+
+        const INDEXES: &'static [Index] = &[Index {
+            name: BY_SUBJECT,
+            unique: true,
+        }];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.subject)]
+        }
+    }
+
+    /// The outcome of a login.
+    #[derive(Debug)]
+    pub enum Attempt {
+        Accepted(Profile),
+        Rejected,
+
+        /// Refused without checking the password, for `retry_after`
+        /// seconds.
+        Locked {
+            retry_after: u64,
+        },
+    }
+
+    /// Checks the credentials as `profile::check_credentials` does, unless
+    /// the username or the address `ip` is locked out. Failures count
+    /// against both. Those of the username are only cleared by `unlock`,
+    /// once every step of the login succeeded.
+    pub async fn check_credentials(
+        db: &Db,
+        policy: &Policy,
+        username: &str,
+        password: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Attempt, store::Error> {
+        let now = Utc::now().timestamp();
+        let subjects = subjects(policy, username, ip);
+
+        // The attempt counts as a failure until the password proved right,
+        // so that concurrent guesses cannot all pass the check. The lock is
+        // not held while the password is checked, which takes a while.
+        let pending = {
+            let mut lockouts = db.write::<Lockout>().await?;
+
+            let retry_after = locked(&lockouts, &subjects, now)?;
+            if retry_after > 0 {
+                return Ok(Attempt::Locked { retry_after });
+            }
+
+            fail(&mut lockouts, policy, &subjects, now)?
+        };
+
+        let account = db
+            .read::<Profile>()
+            .await?
+            .find_by(profile::BY_USERNAME, username)?;
+
+        match profile::check_password(account, password).await {
+            Some(account) => {
+                let mut lockouts = db.write::<Lockout>().await?;
+                undo(&mut lockouts, &pending)?;
+                Ok(Attempt::Accepted(account))
+            }
+            None => Ok(Attempt::Rejected),
+        }
+    }
+
+    /// Counts a failed step of a login other than the password, such as a
+    /// wrong two-factor code, against the username and the address `ip`.
+    pub async fn record_failure(
+        db: &Db,
+        policy: &Policy,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), store::Error> {
+        let now = Utc::now().timestamp();
+        let mut lockouts = db.write::<Lockout>().await?;
+        fail(&mut lockouts, policy, &subjects(policy, username, ip), now).map(|_| ())
+    }
+
+    /// Forgets the failed logins of `username`, and whether there were any.
+    pub async fn unlock(db: &Db, username: &str) -> Result<bool, store::Error> {
+        let mut lockouts = db.write::<Lockout>().await?;
+
+        match lockouts.find_by(BY_SUBJECT, &username_subject(username))? {
+            Some(lockout) => lockouts.delete(lockout.id),
+            None => Ok(false),
+        }
+    }
+
+    fn username_subject(username: &str) -> String {
+        format!("username:{}", username)
+    }
+
+    // What failures count against, along with their threshold.
+    fn subjects(policy: &Policy, username: &str, ip: Option<IpAddr>) -> Vec<(String, u32)> {
+        let mut subjects = vec![(username_subject(username), policy.username_threshold)];
+        if let Some(ip) = ip {
+            subjects.push((format!("ip:{}", ip), policy.ip_threshold));
+        }
+
+        subjects.retain(|(_, threshold)| *threshold > 0);
+        subjects
+    }
+
+    // The seconds until every subject may try again.
+    fn locked(
+        lockouts: &Writer<Lockout>,
+        subjects: &[(String, u32)],
+        now: i64,
+    ) -> Result<u64, store::Error> {
+        let mut retry_after = 0;
+
+        for (subject, _) in subjects {
+            if let Some(lockout) = lockouts.find_by(BY_SUBJECT, subject)? {
+                retry_after = retry_after.max(lockout.locked_until - now);
+            }
+        }
+
+        Ok(retry_after.max(0) as u64)
+    }
+
+    // A failure counted against `subject`, along with when it was locked
+    // until before, if the failure locked it.
+    struct Failure {
+        subject: String,
+        threshold: u32,
+        locked_until: Option<i64>,
+    }
+
+    fn fail(
+        lockouts: &mut Writer<Lockout>,
+        policy: &Policy,
+        subjects: &[(String, u32)],
+        now: i64,
+    ) -> Result<Vec<Failure>, store::Error> {
+        prune(lockouts, policy, now)?;
+        let mut failures = Vec::new();
+
+        for (subject, threshold) in subjects {
+            let found = lockouts.find_by(BY_SUBJECT, subject)?;
+            let known = found.is_some();
+
+            let mut lockout = match found {
+                Some(lockout) => lockout,
+                None => Lockout {
+                    id: lockouts.next_id()?,
+                    subject: subject.clone(),
+                    ..Default::default()
+                },
+            };
+
+            let mut failure = Failure {
+                subject: subject.clone(),
+                threshold: *threshold,
+                locked_until: None,
+            };
+
+            lockout.failures += 1;
+            lockout.last_failure = now;
+
+            if lockout.failures >= *threshold {
+                failure.locked_until = Some(lockout.locked_until);
+                lockout.locked_until = now + policy.lock_duration(lockout.lockouts) as i64;
+                lockout.lockouts += 1;
+                lockout.failures = 0;
+            }
+
+            match known {
+                true => lockouts.replace(&lockout).map(|_| ())?,
+                false => lockouts.insert(&lockout)?,
+            }
+
+            failures.push(failure);
+        }
+
+        Ok(failures)
+    }
+
+    // Takes back `failures` of an attempt that turned out right, along with
+    // the lockouts they set off.
+    fn undo(lockouts: &mut Writer<Lockout>, failures: &[Failure]) -> Result<(), store::Error> {
+        for failure in failures {
+            let mut lockout = match lockouts.find_by(BY_SUBJECT, &failure.subject)? {
+                Some(lockout) => lockout,
+                None => continue,
+            };
+
+            match failure.locked_until {
+                Some(locked_until) => {
+                    lockout.locked_until = locked_until;
+                    lockout.lockouts = lockout.lockouts.saturating_sub(1);
+                    lockout.failures = failure.threshold - 1;
+                }
+                None if lockout.failures > 0 => lockout.failures -= 1,
+                None => continue,
+            }
+
+            lockouts.replace(&lockout)?;
+        }
+
+        Ok(())
+    }
+
+    // Forgets the subjects that have not failed for `max_duration`, so that
+    // the backoff starts over.
+    fn prune(
+        lockouts: &mut Writer<Lockout>,
+        policy: &Policy,
+        now: i64,
+    ) -> Result<(), store::Error> {
+        let since = now - policy.max_duration as i64;
+
+        let idle = lockouts.list(
+            |lockout| lockout.last_failure <= since && lockout.locked_until <= now,
+            0,
+            usize::MAX,
+        )?;
+
+        for lockout in idle {
+            lockouts.delete(lockout.id)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub mod two_factor {
+    use super::refresh_token::{generate_token, hash};
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store::{self, Writer};
+    use crate::totp;
+    use chrono::Utc;
+    use rand::Rng;
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const TWO_FACTORS: &str = "two_factors";
+    pub const CHALLENGES: &str = "two_factor_challenges";
+    pub const BY_PROFILE: &str = "profile_id";
+    pub const BY_HASH: &str = "hash";
+
+    /// How long the second step of a login can be completed, in seconds.
+    pub const CHALLENGE_LIFETIME: i64 = 5 * 60;
+
+    /// Wrong codes a challenge takes before it is dropped.
+    pub const MAX_ATTEMPTS: u32 = 5;
+
+    /// Recovery codes given at once, each usable once instead of a code.
+    pub const RECOVERY_CODES: usize = 10;
+
+    /// The TOTP secret of a profile, enabled once a code of it was
+    /// confirmed.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct TwoFactor {
+        pub id: Id,
+        pub profile_id: Id,
+
+        /// Base32 encoded, as shown to the user.
+        pub secret: String,
+
+        pub enabled: bool,
+
+        /// The SHA-256 of each unused recovery code, hex encoded.
+        pub recovery_codes: Vec<String>,
+
+        /// The step of the last code accepted, which cannot be used again.
+        pub last_step: u64,
+    }
+
+    impl Document for TwoFactor {
+        const COLLECTION: &'static str = TWO_FACTORS;
+
+        const INDEXES: &'static [Index] = &[Index {
+            name: BY_PROFILE,
+            unique: true,
+        }];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.profile_id)]
+        }
+    }
+
+    /// A login whose password was right, waiting for a code. The token
+    /// itself is only known to the client.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct Challenge {
+        pub id: Id,
+
+        /// The SHA-256 of the token, hex encoded.
+        pub hash: String,
+
+        pub profile_id: Id,
+
+        /// Wrong codes given so far.
+        pub attempts: u32,
+
+        /// When the token stops being accepted, as a Unix timestamp.
+        pub expires: i64,
+    }
+
+    impl Document for Challenge {
+        const COLLECTION: &'static str = CHALLENGES;
+
+        const INDEXES: &'static [Index] = &[Index {
+            name: BY_HASH,
+            unique: true,
+        }];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.hash)]
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct CodeRequest {
+        pub code: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ChallengeResponse {
+        pub challenge_token: String,
+        pub code: String,
+    }
+
+    /// The outcome of answering a challenge.
+    #[derive(Debug, PartialEq)]
+    pub enum Answer {
+        Accepted(Id),
+
+        /// The code was wrong, for the profile given.
+        Rejected(Id),
+
+        /// The token is unknown, expired or took too many wrong codes.
+        Invalid,
+    }
+
+    /// The secret of `profile_id`, enabled or not.
+    pub async fn find(db: &Db, profile_id: Id) -> Result<Option<TwoFactor>, store::Error> {
+        db.read::<TwoFactor>()
+            .await?
+            .find_by(BY_PROFILE, &profile_id)
+    }
+
+    /// Whether logins of `profile_id` take a code.
+    pub async fn is_enabled(db: &Db, profile_id: Id) -> Result<bool, store::Error> {
+        Ok(find(db, profile_id)
+            .await?
+            .is_some_and(|found| found.enabled))
+    }
+
+    /// A new secret for `profile_id`, replacing one not confirmed yet, or
+    /// `None` when two-factor authentication is already enabled.
+    pub async fn enroll(db: &Db, profile_id: Id) -> Result<Option<TwoFactor>, store::Error> {
+        let mut secrets = db.write::<TwoFactor>().await?;
+
+        let found = secrets.find_by(BY_PROFILE, &profile_id)?;
+        if found.as_ref().is_some_and(|found| found.enabled) {
+            return Ok(None);
+        }
+
+        let two_factor = TwoFactor {
+            id: match &found {
+                Some(found) => found.id,
+                None => secrets.next_id()?,
+            },
+            profile_id,
+            secret: totp::generate_secret(),
+            ..Default::default()
+        };
+
+        match found {
+            Some(_) => secrets.replace(&two_factor).map(|_| ())?,
+            None => secrets.insert(&two_factor)?,
+        }
+
+        Ok(Some(two_factor))
+    }
+
+    /// Enables the secret of `profile_id` given a code of it, and returns
+    /// the recovery codes. `None` when the code is wrong or there is no
+    /// secret waiting for confirmation.
+    pub async fn confirm(
+        db: &Db,
+        profile_id: Id,
+        code: &str,
+    ) -> Result<Option<Vec<String>>, store::Error> {
+        let mut secrets = db.write::<TwoFactor>().await?;
+
+        let mut two_factor = match secrets.find_by(BY_PROFILE, &profile_id)? {
+            Some(found) if !found.enabled => found,
+            _ => return Ok(None),
+        };
+
+        let now = Utc::now().timestamp();
+        let step = match totp::verify(&two_factor.secret, code, now, two_factor.last_step) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        let codes = generate_recovery_codes();
+        two_factor.enabled = true;
+        two_factor.last_step = step;
+        two_factor.recovery_codes = codes.iter().map(|code| hash(code)).collect();
+
+        secrets.replace(&two_factor)?;
+        Ok(Some(codes))
+    }
+
+    /// Whether `code` is a current code or an unused recovery code of
+    /// `profile_id`, either of which is then used up.
+    pub async fn verify(db: &Db, profile_id: Id, code: &str) -> Result<bool, store::Error> {
+        let mut secrets = db.write::<TwoFactor>().await?;
+        use_code(&mut secrets, profile_id, code)
+    }
+
+    /// New recovery codes for `profile_id`, replacing the unused ones.
+    pub async fn regenerate_recovery_codes(
+        db: &Db,
+        profile_id: Id,
+    ) -> Result<Option<Vec<String>>, store::Error> {
+        let mut secrets = db.write::<TwoFactor>().await?;
+
+        let mut two_factor = match secrets.find_by(BY_PROFILE, &profile_id)? {
+            Some(found) if found.enabled => found,
+            _ => return Ok(None),
+        };
+
+        let codes = generate_recovery_codes();
+        two_factor.recovery_codes = codes.iter().map(|code| hash(code)).collect();
+
+        secrets.replace(&two_factor)?;
+        Ok(Some(codes))
+    }
+
+    /// Turns two-factor authentication off for `profile_id`, and returns
+    /// whether it was on or being enrolled.
+    pub async fn disable(db: &Db, profile_id: Id) -> Result<bool, store::Error> {
+        let mut secrets = db.write::<TwoFactor>().await?;
+
+        match secrets.find_by(BY_PROFILE, &profile_id)? {
+            Some(found) => secrets.delete(found.id),
+            None => Ok(false),
+        }
+    }
+
+    /// Issues the token completing a login of `profile_id` along with a
+    /// code, and forgets the expired ones.
+    pub async fn challenge(db: &Db, profile_id: Id) -> Result<String, store::Error> {
+        let mut challenges = db.write::<Challenge>().await?;
+        let now = Utc::now().timestamp();
+
+        for expired in challenges.list(|challenge| challenge.expires <= now, 0, usize::MAX)? {
+            challenges.delete(expired.id)?;
+        }
+
+        let id = challenges.next_id()?;
+        let token = generate_token();
+
+        challenges.insert(&Challenge {
+            id,
+            hash: hash(&token),
+            profile_id,
+            attempts: 0,
+            expires: now + CHALLENGE_LIFETIME,
+        })?;
+
+        Ok(token)
+    }
+
+    /// Checks `code` for the login waiting on `token`. A token is dropped
+    /// once answered right, or after `MAX_ATTEMPTS` wrong codes.
+    pub async fn answer(db: &Db, token: &str, code: &str) -> Result<Answer, store::Error> {
+        // Both are held throughout, so that concurrent answers count every
+        // attempt and a challenge completes a single login. Secrets come
+        // first, in the order of the store.
+        let mut secrets = db.write::<TwoFactor>().await?;
+        let mut challenges = db.write::<Challenge>().await?;
+
+        let mut challenge = match challenges.find_by(BY_HASH, &hash(token))? {
+            Some(challenge) if challenge.expires > Utc::now().timestamp() => challenge,
+            _ => return Ok(Answer::Invalid),
+        };
+
+        let accepted = use_code(&mut secrets, challenge.profile_id, code)?;
+        challenge.attempts += 1;
+
+        if accepted || challenge.attempts >= MAX_ATTEMPTS {
+            challenges.delete(challenge.id)?;
+        } else {
+            challenges.replace(&challenge)?;
+        }
+
+        match accepted {
+            true => Ok(Answer::Accepted(challenge.profile_id)),
+            false => Ok(Answer::Rejected(challenge.profile_id)),
+        }
+    }
+
+    fn use_code(
+        secrets: &mut Writer<TwoFactor>,
+        profile_id: Id,
+        code: &str,
+    ) -> Result<bool, store::Error> {
+        let mut two_factor = match secrets.find_by(BY_PROFILE, &profile_id)? {
+            Some(found) if found.enabled => found,
+            _ => return Ok(false),
+        };
+
+        let now = Utc::now().timestamp();
+        if let Some(step) = totp::verify(&two_factor.secret, code, now, two_factor.last_step) {
+            two_factor.last_step = step;
+            secrets.replace(&two_factor)?;
+            return Ok(true);
+        }
+
+        let hashed = hash(&normalize_recovery_code(code));
+        match two_factor
+            .recovery_codes
+            .iter()
+            .position(|code| *code == hashed)
+        {
+            Some(index) => {
+                two_factor.recovery_codes.remove(index);
+                secrets.replace(&two_factor)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Codes like `k3f9-x2mq`, easy enough to type from paper.
+    fn generate_recovery_codes() -> Vec<String> {
+        const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+        let mut rng = rand::thread_rng();
+
+        (0..RECOVERY_CODES)
+            .map(|_| {
+                let code: String = (0..8)
+                    .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                    .collect();
+                format!("{}-{}", &code[..4], &code[4..])
+            })
+            .collect()
+    }
+
+    // Recovery codes are accepted in any case, with or without the dash.
+    fn normalize_recovery_code(code: &str) -> String {
+        let code: String = code
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+
+        match code.len() {
+            8 => format!("{}-{}", &code[..4], &code[4..]),
+            _ => code,
+        }
+    }
+}
+
+pub mod outbox {
+    use super::{Db, Document, Id};
+    use crate::keyring;
+    use crate::store;
+    use chrono::{TimeZone, Utc};
+    use serde_derive::{Deserialize, Serialize};
+    use std::fs;
+    use std::path::Path;
+
+    pub const OUTBOX: &str = "outbox";
+
+    /// Emails kept, the oldest ones being dropped as new ones are sent.
+    pub const CAPACITY: usize = 1000;
+
+    /// An email the server would have delivered, kept so that it can be
+    /// read instead.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct Email {
+        pub id: Id,
+        pub from: String,
+        pub to: String,
+        pub subject: String,
+        pub body: String,
+
+        /// When the email was sent, as a Unix timestamp.
+        pub sent_at: i64,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct OutboxQuery {
+        /// Only lists the emails sent to this address.
+        pub to: Option<String>,
+    }
+
+    impl Document for Email {
+        const COLLECTION: &'static str = OUTBOX;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl Email {
+        /// The email in the format of RFC 5322, as found in `.eml` files.
+        pub fn to_eml(&self) -> String {
+            let date = Utc
+                .timestamp_opt(self.sent_at, 0)
+                .single()
+                .unwrap_or_default();
+            let domain = self
+                .from
+                .rsplit_once('@')
+                .map_or("localhost", |(_, domain)| domain);
+
+            format!(
+                concat!(
+                    "From: {}\r\n",
+                    "To: {}\r\n",
+                    "Subject: {}\r\n",
+                    "Date: {}\r\n",
+                    "Message-ID: <{}.{}@{}>\r\n",
+                    "MIME-Version: 1.0\r\n",
+                    "Content-Type: text/plain; charset=utf-8\r\n",
+                    "Content-Transfer-Encoding: 8bit\r\n",
+                    "\r\n",
+                    "{}\r\n",
+                ),
+                header(&self.from),
+                header(&self.to),
+                header(&self.subject),
+                date.to_rfc2822(),
+                self.sent_at,
+                self.id,
+                header(domain),
+                self.body.replace("\r\n", "\n").replace('\n', "\r\n"),
+            )
+        }
+    }
+
+    /// Keeps `email`, and writes it to `dir` as a `.eml` file when given.
+    /// Returns the email as kept.
+    pub async fn send(
+        db: &Db,
+        mut email: Email,
+        dir: Option<&Path>,
+    ) -> Result<Email, store::Error> {
+        let mut outbox = db.write::<Email>().await?;
+
+        email.id = outbox.next_id()?;
+        email.sent_at = Utc::now().timestamp();
+        outbox.insert(&email)?;
+
+        let count = outbox.count()?;
+        if count > CAPACITY {
+            for sent in outbox.list(|_| true, 0, count - CAPACITY)? {
+                outbox.delete(sent.id)?;
+            }
+        }
+
+        drop(outbox);
+
+        // The email is in the outbox either way, a file that cannot be
+        // written is not worth failing the request for.
+        if let Some(dir) = dir {
+            let path = dir.join(format!("{:08}.eml", email.id));
+            let written = fs::create_dir_all(dir)
+                .map_err(|err| err.into())
+                .and_then(|()| keyring::write_private(&path, email.to_eml().as_bytes()));
+
+            if let Err(err) = written {
+                log::error!("Unable to write {}: {}", path.display(), err);
+            }
+        }
+
+        log::info!("outbox: `{}` sent to {}", email.subject, email.to);
+        Ok(email)
+    }
+
+    /// The emails kept, the most recent first, only the ones sent to `to`
+    /// when given.
+    pub async fn list(db: &Db, to: Option<&str>) -> Result<Vec<Email>, store::Error> {
+        let outbox = db.read::<Email>().await?;
+
+        let mut emails = outbox.list(|email| to.is_none_or(|to| email.to == to), 0, usize::MAX)?;
+        emails.reverse();
+
+        Ok(emails)
+    }
+
+    // Header values stay on their line, whatever they were given.
+    fn header(value: &str) -> String {
+        value.replace(['\r', '\n'], " ")
+    }
+}
+
+pub mod password_reset {
+    use super::refresh_token::{generate_token, hash};
+    use super::{key, Db, Document, Id, Index, Key};
+    use crate::store;
+    use chrono::Utc;
+    use serde_derive::{Deserialize, Serialize};
+    use std::fmt;
+
+    pub const PASSWORD_RESETS: &str = "password_resets";
+    pub const BY_HASH: &str = "hash";
+    pub const BY_PROFILE: &str = "profile_id";
+
+    /// Lets whoever reads the email sent to a profile choose its password,
+    /// once. The token itself is only known to the email.
+    #[derive(Default, Debug, Deserialize, Serialize, Clone)]
+    pub struct PasswordReset {
+        pub id: Id,
+
+        /// The SHA-256 of the token, hex encoded.
+        pub hash: String,
+
+        pub profile_id: Id,
+
+        /// When the token stops being accepted, as a Unix timestamp.
+        pub expires: i64,
+    }
+
+    impl Document for PasswordReset {
+        const COLLECTION: &'static str = PASSWORD_RESETS;
+
+        const INDEXES: &'static [Index] = &[
+            Index {
+                name: BY_HASH,
+                unique: true,
+            },
+            Index {
+                name: BY_PROFILE,
+                unique: false,
+            },
+        ];
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            vec![key(&self.hash), key(&self.profile_id)]
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ForgotPassword {
+        pub email: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ResetPassword {
+        pub token: String,
+        pub password: String,
+    }
+
+    impl fmt::Debug for ResetPassword {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("ResetPassword")
+                .field("token", &"<redacted>")
+                .field("password", &"<redacted>")
+                .finish()
+        }
+    }
+
+    /// Issues a token resetting the password of `profile_id`, valid for
+    /// `lifetime` seconds. The tokens issued to the profile before, and the
+    /// expired ones, are dropped.
+    pub async fn create(db: &Db, profile_id: Id, lifetime: u64) -> Result<String, store::Error> {
+        let mut resets = db.write::<PasswordReset>().await?;
+        let now = Utc::now().timestamp();
+
+        for stale in resets.list(
+            |reset| reset.profile_id == profile_id || reset.expires <= now,
+            0,
+            usize::MAX,
+        )? {
+            resets.delete(stale.id)?;
+        }
+
+        let id = resets.next_id()?;
+        let token = generate_token();
+
+        resets.insert(&PasswordReset {
+            id,
+            hash: hash(&token),
+            profile_id,
+            expires: now + lifetime as i64,
+        })?;
+
+        Ok(token)
+    }
+
+    /// Takes the profile whose password `token` resets, which cannot be used
+    /// again, or `None` when it is unknown, used or expired.
+    pub async fn redeem(db: &Db, token: &str) -> Result<Option<Id>, store::Error> {
+        let mut resets = db.write::<PasswordReset>().await?;
+
+        let reset = match resets.find_by(BY_HASH, &hash(token))? {
+            Some(reset) => reset,
+            None => return Ok(None),
+        };
+
+        resets.delete(reset.id)?;
+
+        Ok(Some(reset)
+            .filter(|reset| reset.expires > Utc::now().timestamp())
+            .map(|reset| reset.profile_id))
+    }
+}
+
+pub mod seed {
+    use super::course::{self, Course, BY_TITLE};
+    use super::profile::{self, Profile, BY_USERNAME};
+    use super::topic::{self, Topic};
+    use super::{Db, Id};
+    use serde_derive::Deserialize;
+    use std::error::Error;
+
+    /// Describes the data every fresh environment starts with. Courses and
+    /// topics refer to profiles by username and to courses by title, so that
+    /// a seed file can be written by hand.
+    #[derive(Debug, Default, Deserialize)]
+    pub struct Seed {
+        #[serde(default)]
+        pub profiles: Vec<Profile>,
+
+        #[serde(default)]
+        pub courses: Vec<SeedCourse>,
+
+        #[serde(default)]
+        pub topics: Vec<SeedTopic>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct SeedCourse {
+        pub title: String,
+
+        #[serde(default)]
+        pub description: String,
+
+        /// The username of the profile that created the course.
+        pub creator: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct SeedTopic {
+        /// The title of the course the topic belongs to.
+        pub course: String,
+
+        pub title: String,
+
+        #[serde(default)]
+        pub description: String,
+
+        /// The username of the profile that created the topic.
+        pub creator: String,
+    }
+
+    impl Seed {
+        pub fn parse(text: &str) -> Result<Seed, toml::de::Error> {
+            toml::from_str(text)
+        }
+    }
+
+    /// Adds whatever part of `seed` is missing from the database and returns
+    /// the profiles that were added. Running it again changes nothing.
+    pub async fn apply(db: &Db, seed: &Seed) -> Result<Vec<Profile>, Box<dyn Error>> {
+        let added = profile::initialize(db, &seed.profiles).await;
+
+        let mut courses = Vec::new();
+        for item in &seed.courses {
+            courses.push(
+                Course::new()
+                    .with_title(item.title.clone())
+                    .with_description(item.description.clone())
+                    .with_creator_id(creator_id(db, &item.creator).await?),
+            );
+        }
+        course::initialize(db, &courses).await;
+
+        let mut topics = Vec::new();
+        for item in &seed.topics {
+            let course_id = match db.read::<Course>().await?.find_by(BY_TITLE, &item.course)? {
+                Some(course) => course.id,
+                None => return Err(format!("unknown course {}", item.course).into()),
+            };
+
+            topics.push(
+                Topic::new()
+                    .with_title(item.title.clone())
+                    .with_description(item.description.clone())
+                    .with_creator_id(creator_id(db, &item.creator).await?)
+                    .with_course_id(course_id),
+            );
+        }
+        topic::initialize(db, &topics).await;
+
+        Ok(added)
+    }
+
+    async fn creator_id(db: &Db, username: &str) -> Result<Id, Box<dyn Error>> {
+        match db.read::<Profile>().await?.find_by(BY_USERNAME, username)? {
+            Some(profile) => Ok(profile.id),
+            None => Err(format!("unknown profile {}", username).into()),
+        }
+    }
+}
+
+pub mod fixture {
+    use super::api_key::{ApiKey, API_KEYS};
+    use super::authorization_code::{AuthorizationCode, AUTHORIZATION_CODES};
+    use super::course::{Course, COURSES};
+    use super::lockout::{Lockout, LOCKOUTS};
+    use super::password_reset::{PasswordReset, PASSWORD_RESETS};
+    use super::profile::{valid_email, Kind, Profile, PROFILES};
+    use super::refresh_token::{RefreshToken, REFRESH_TOKENS};
+    use super::revocation::{Revocation, REVOCATIONS};
+    use super::topic::{Topic, TOPICS};
+    use super::two_factor::{Challenge, TwoFactor, CHALLENGES, TWO_FACTORS};
+    use super::Id;
+    use crate::store::{self, Db, Document, Transaction};
+    use argon2::password_hash;
+    use chrono::Utc;
+    use serde::Serializer;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::HashSet;
+    use std::fmt;
+
+    /// Every collection of the database as a human readable document, so
+    /// that a known dataset can be prepared once and loaded again.
+    ///
+    /// Profiles are exported without their password. When importing, a
+    /// password is given in plain text, and a profile without one keeps the
+    /// password it has, if any.
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    pub struct Fixture {
+        #[serde(default, serialize_with = "without_passwords")]
+        pub profiles: Vec<Profile>,
+
+        #[serde(default)]
+        pub courses: Vec<Course>,
+
+        #[serde(default)]
+        pub topics: Vec<Topic>,
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        /// The fixture itself is wrong, nothing was loaded.
+        Invalid(String),
+        Storage(store::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Invalid(reason) => write!(f, "invalid fixture: {}", reason),
+                Error::Storage(err) => write!(f, "{}", err),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    impl From<store::Error> for Error {
+        fn from(err: store::Error) -> Self {
+            match err {
+                store::Error::Duplicate(_) | store::Error::Conflict(..) => {
+                    Error::Invalid(err.to_string())
+                }
+                err => Error::Storage(err),
+            }
+        }
+    }
+
+    fn without_passwords<S: Serializer>(
+        profiles: &[Profile],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Exported<'a> {
+            id: Id,
+            username: &'a str,
+            first_name: &'a str,
+            last_name: &'a str,
+            kind: &'a Kind,
+            email: &'a str,
+        }
+
+        serializer.collect_seq(profiles.iter().map(|profile| Exported {
+            id: profile.id,
+            username: &profile.username,
+            first_name: &profile.first_name,
+            last_name: &profile.last_name,
+            kind: &profile.kind,
+            email: &profile.email,
+        }))
+    }
+
+    /// Reads every collection at once, so that the fixture is consistent.
+    pub async fn export(db: &Db) -> Result<Fixture, store::Error> {
+        let profiles = db.read::<Profile>().await?;
+        let courses = db.read::<Course>().await?;
+        let topics = db.read::<Topic>().await?;
+
+        Ok(Fixture {
+            profiles: profiles.list(|_| true, 0, usize::MAX)?,
+            courses: courses.list(|_| true, 0, usize::MAX)?,
+            topics: topics.list(|_| true, 0, usize::MAX)?,
+        })
+    }
+
+    /// Replaces the content of every collection with `fixture`, keeping the
+    /// IDs it lists. Other requests see the data either before or after the
+    /// import, and a failed import is rolled back, but a crash halfway
+    /// through may leave it partly loaded.
+    ///
+    /// As an ID may now stand for another profile, everything kept for the
+    /// profiles before goes along: every login is ended, its access tokens
+    /// being revoked for `token_lifetime` seconds, and API keys, two-factor
+    /// secrets, lockouts and pending resets are dropped.
+    pub async fn import(db: &Db, fixture: &Fixture, token_lifetime: u64) -> Result<(), Error> {
+        validate(fixture).map_err(Error::Invalid)?;
+
+        // Hashing takes a while, better not to hold the locks meanwhile, nor
+        // a worker of the runtime.
+        let profiles = fixture.profiles.clone();
+        let mut profiles = tokio::task::spawn_blocking(move || hash_passwords(profiles))
+            .await
+            .map_err(|err| Error::Invalid(err.to_string()))?
+            .map_err(|err| Error::Invalid(err.to_string()))?;
+
+        let mut tx = db
+            .transaction(&[
+                PROFILES,
+                COURSES,
+                TOPICS,
+                REFRESH_TOKENS,
+                REVOCATIONS,
+                AUTHORIZATION_CODES,
+                API_KEYS,
+                LOCKOUTS,
+                TWO_FACTORS,
+                CHALLENGES,
+                PASSWORD_RESETS,
+            ])
+            .await?;
+
+        keep_passwords(&mut tx, &mut profiles)?;
+        replace_all(&mut tx, &profiles)?;
+        replace_all(&mut tx, &fixture.courses)?;
+        replace_all(&mut tx, &fixture.topics)?;
+
+        end_sessions(&mut tx, Utc::now().timestamp() + token_lifetime as i64)?;
+        replace_all::<AuthorizationCode>(&mut tx, &[])?;
+        replace_all::<ApiKey>(&mut tx, &[])?;
+        replace_all::<Lockout>(&mut tx, &[])?;
+        replace_all::<TwoFactor>(&mut tx, &[])?;
+        replace_all::<Challenge>(&mut tx, &[])?;
+        replace_all::<PasswordReset>(&mut tx, &[])?;
+
+        tx.commit();
+        Ok(())
+    }
+
+    // Drops every refresh token, and revokes the access tokens of their
+    // logins until `expires`.
+    fn end_sessions(tx: &mut Transaction, expires: i64) -> Result<(), Error> {
+        let mut sessions = HashSet::new();
+        for token in tx
+            .collection::<RefreshToken>()?
+            .list(|_| true, 0, usize::MAX)?
+        {
+            sessions.insert(token.family);
+        }
+
+        replace_all::<RefreshToken>(tx, &[])?;
+
+        let mut revocations = tx.collection::<Revocation>()?;
+        for session in sessions {
+            let id = revocations.next_id()?;
+            revocations.insert(&Revocation {
+                id,
+                session,
+                expires,
+                ..Revocation::default()
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn hash_passwords(mut profiles: Vec<Profile>) -> Result<Vec<Profile>, password_hash::Error> {
+        for profile in profiles.iter_mut() {
+            if !profile.password.is_empty() {
+                profile.hash_password()?;
+            }
+        }
+
+        Ok(profiles)
+    }
+
+    // Profiles without a password keep the one stored for the same ID and
+    // username.
+    fn keep_passwords(tx: &mut Transaction, profiles: &mut [Profile]) -> Result<(), Error> {
+        let existing = tx.collection::<Profile>()?;
+
+        for profile in profiles
+            .iter_mut()
+            .filter(|profile| profile.password.is_empty())
+        {
+            if let Some(current) = existing.get(profile.id)? {
+                if current.username == profile.username {
+                    profile.password = current.password;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn replace_all<T: Document>(tx: &mut Transaction, docs: &[T]) -> Result<(), Error> {
+        let mut collection = tx.collection::<T>()?;
+
+        for existing in collection.list(|_| true, 0, usize::MAX)? {
+            collection.delete(existing.id())?;
+        }
+
+        for doc in docs {
+            collection.insert(doc)?;
+        }
+
+        Ok(())
+    }
+
+    // Checks that every document has an ID of its own and only refers to
+    // documents that are part of the fixture.
+    fn validate(fixture: &Fixture) -> Result<(), String> {
+        let profiles = ids(PROFILES, &fixture.profiles)?;
+        let courses = ids(COURSES, &fixture.courses)?;
+        ids(TOPICS, &fixture.topics)?;
+
+        for profile in &fixture.profiles {
+            if !valid_email(&profile.email) {
+                return Err(format!(
+                    "profile {} has an invalid email `{}`",
+                    profile.id, profile.email
+                ));
+            }
+        }
+
+        for course in &fixture.courses {
+            if !profiles.contains(&course.creator_id) {
+                return Err(format!(
+                    "course {} refers to unknown profile {}",
+                    course.id, course.creator_id
+                ));
+            }
+        }
+
+        for topic in &fixture.topics {
+            if !profiles.contains(&topic.creator_id) {
+                return Err(format!(
+                    "topic {} refers to unknown profile {}",
+                    topic.id, topic.creator_id
+                ));
+            }
+
+            if !courses.contains(&topic.course_id) {
+                return Err(format!(
+                    "topic {} refers to unknown course {}",
+                    topic.id, topic.course_id
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ids<T: Document>(collection: &str, docs: &[T]) -> Result<HashSet<Id>, String> {
+        let mut ids = HashSet::new();
+
+        for doc in docs {
+            if doc.id() == 0 {
+                return Err(format!("{}: every document needs an id", collection));
+            }
+
+            if !ids.insert(doc.id()) {
+                return Err(format!("{}: id {} is used twice", collection, doc.id()));
+            }
+        }
+
+        Ok(ids)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ListOptions {
-    pub limit: Option<u8>,
-    pub offset: Option<u8>,
-    pub course_id: Option<u8>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+    pub course_id: Option<Id>,
 }
@@ -0,0 +1,84 @@
+use super::auth;
+use super::config;
+use super::handlers;
+use super::models::authorization_code::{AuthorizationRequest, LoginForm, TokenRequest};
+use super::store::Db;
+use std::convert::Infallible;
+use warp::Filter;
+
+/// A minimal OpenID Connect provider signing in the profiles of the database,
+/// for clients using the authorization code flow with PKCE.
+pub fn oidc(db: Db) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    discovery()
+        .or(authorize())
+        .or(login(db.clone()))
+        .or(token(db.clone()))
+        .or(userinfo(db))
+}
+
+/// GET /.well-known/openid-configuration
+pub fn discovery() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!(".well-known" / "openid-configuration")
+        .and(warp::get())
+        .and(base_url())
+        .and_then(handlers::oidc::discovery)
+}
+
+/// GET /oauth/authorize?response_type=code&client_id=...&redirect_uri=...
+pub fn authorize() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("oauth" / "authorize")
+        .and(warp::get())
+        .and(warp::query::<AuthorizationRequest>())
+        .and_then(handlers::oidc::authorize)
+}
+
+/// POST /oauth/authorize with the login form
+pub fn login(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("oauth" / "authorize")
+        .and(warp::post())
+        .and(form_body::<LoginForm>())
+        .and(auth::remote())
+        .and(with_db(db))
+        .and_then(handlers::oidc::login)
+}
+
+/// POST /oauth/token
+pub fn token(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("oauth" / "token")
+        .and(warp::post())
+        .and(form_body::<TokenRequest>())
+        .and(with_db(db))
+        .and_then(handlers::oidc::token)
+}
+
+/// GET /oauth/userinfo
+pub fn userinfo(
+    db: Db,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("oauth" / "userinfo")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(auth::with_auth(db))
+        .and_then(handlers::oidc::userinfo)
+}
+
+fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+// OAuth clients send forms rather than JSON.
+fn form_body<T: serde::de::DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(config::body_limit()).and(warp::body::form())
+}
+
+// Where the endpoints are reached, as seen by the client.
+fn base_url() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("host").map(|host: Option<String>| {
+        format!("http://{}", host.as_deref().unwrap_or("localhost:3030"))
+    })
+}
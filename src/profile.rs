@@ -1,6 +1,8 @@
+use super::config;
 use super::handlers;
 use super::auth;
 use super::models::profile::{Profile};
+use super::models::Id;
 use std::convert::Infallible;
 use warp::Filter;
 use super::store::Db;
@@ -22,7 +24,7 @@ pub fn create(
 pub fn get(
     db: Db,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("profiles" / u8)
+    warp::path!("profiles" / Id)
         .and(warp::get())
         .and(with_db(db.clone()))
         .and(auth::with_auth(db))
@@ -36,5 +38,5 @@ fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
 fn json_body() -> impl Filter<Extract = (Profile,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    warp::body::content_length_limit(config::body_limit()).and(warp::body::json())
 }
@@ -0,0 +1,216 @@
+use super::keyring;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair as _, RsaKeyPair};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A private key signing tokens with RS256 or EdDSA. Its public half is
+/// published as a JWK, so that other services can verify tokens without
+/// knowing any secret.
+pub struct KeyPair {
+    /// Names the key in the header of the tokens it signs.
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Value,
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyPair {
+    /// Reads a private key in PEM format: PKCS#8 for both algorithms, or
+    /// PKCS#1 for RS256.
+    pub fn from_pem(algorithm: Algorithm, data: &[u8]) -> Result<KeyPair, String> {
+        let pem = pem::parse(data).map_err(|err| err.to_string())?;
+
+        let pair = match (algorithm, pem.tag.as_str()) {
+            (Algorithm::RS256, "PRIVATE KEY") => RsaKeyPair::from_pkcs8(&pem.contents),
+            (Algorithm::RS256, "RSA PRIVATE KEY") => RsaKeyPair::from_der(&pem.contents),
+            (Algorithm::EdDSA, "PRIVATE KEY") => return KeyPair::ed25519(&pem.contents),
+            (_, tag) => return Err(format!("unexpected {} for {:?}", tag, algorithm)),
+        };
+
+        let pair = pair.map_err(|err| format!("invalid RSA key: {}", err))?;
+        let encoding = EncodingKey::from_rsa_pem(data).map_err(|err| err.to_string())?;
+        Ok(KeyPair::rsa(&pair, encoding))
+    }
+
+    /// A new key pair along with its private key in PEM format. Only EdDSA
+    /// keys can be generated.
+    pub fn generate(algorithm: Algorithm) -> Result<(KeyPair, String), String> {
+        if algorithm != Algorithm::EdDSA {
+            return Err(format!("{:?} keys cannot be generated", algorithm));
+        }
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| String::from("unable to generate a key"))?;
+
+        let pem = pem::encode(&pem::Pem {
+            tag: String::from("PRIVATE KEY"),
+            contents: pkcs8.as_ref().to_vec(),
+        });
+
+        Ok((KeyPair::ed25519(pkcs8.as_ref())?, pem))
+    }
+
+    fn rsa(pair: &RsaKeyPair, encoding: EncodingKey) -> KeyPair {
+        let public = pair.public_key();
+        let n = public.modulus().big_endian_without_leading_zero();
+        let e = public.exponent().big_endian_without_leading_zero();
+
+        // Members in lexicographic order, as the thumbprint requires.
+        let jwk = json!({
+            "e": URL_SAFE_NO_PAD.encode(e),
+            "kty": "RSA",
+            "n": URL_SAFE_NO_PAD.encode(n),
+        });
+
+        KeyPair::new(
+            Algorithm::RS256,
+            encoding,
+            DecodingKey::from_rsa_raw_components(n, e),
+            jwk,
+        )
+    }
+
+    fn ed25519(pkcs8: &[u8]) -> Result<KeyPair, String> {
+        let pair =
+            Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(|err| err.to_string())?;
+        let public = pair.public_key().as_ref();
+
+        let jwk = json!({
+            "crv": "Ed25519",
+            "kty": "OKP",
+            "x": URL_SAFE_NO_PAD.encode(public),
+        });
+
+        Ok(KeyPair::new(
+            Algorithm::EdDSA,
+            EncodingKey::from_ed_der(pkcs8),
+            DecodingKey::from_ed_der(public),
+            jwk,
+        ))
+    }
+
+    // The kid is the JWK thumbprint of RFC 7638, which only changes with
+    // the key.
+    fn new(
+        algorithm: Algorithm,
+        encoding: EncodingKey,
+        decoding: DecodingKey,
+        jwk: Value,
+    ) -> KeyPair {
+        let kid = URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.to_string()));
+
+        let mut jwk = jwk;
+        jwk["kid"] = json!(kid);
+        jwk["alg"] = json!(algorithm);
+        jwk["use"] = json!("sig");
+
+        KeyPair {
+            kid,
+            algorithm,
+            encoding,
+            decoding,
+            jwk,
+        }
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    /// The public key, as published in the JWKS.
+    pub fn jwk(&self) -> &Value {
+        &self.jwk
+    }
+}
+
+/// Loads the key at `key_file`. Without one, an EdDSA key is generated and
+/// kept at `generated_file` when given, so that it survives restarts.
+pub fn load(
+    algorithm: Algorithm,
+    key_file: Option<&Path>,
+    generated_file: Option<&Path>,
+) -> Result<KeyPair, Box<dyn Error>> {
+    let read = |path: &Path| -> Result<KeyPair, Box<dyn Error>> {
+        let pem = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let pair = KeyPair::from_pem(algorithm, &pem)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(pair)
+    };
+
+    if let Some(path) = key_file {
+        return read(path);
+    }
+
+    if let Some(path) = generated_file.filter(|path| path.exists()) {
+        return read(path);
+    }
+
+    let (pair, pem) = KeyPair::generate(algorithm)?;
+
+    if let Some(path) = generated_file {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        keyring::write_private(path, pem.as_bytes())
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+
+    Ok(pair)
+}
+
+#[test]
+fn test_key_pairs() {
+    use jsonwebtoken::{decode, encode, Header, Validation};
+    use std::collections::HashMap;
+
+    let pem = include_bytes!("../tests/keys/rs256.pem");
+    let rsa = KeyPair::from_pem(Algorithm::RS256, pem).unwrap();
+    assert_eq!(rsa.jwk()["kty"], "RSA");
+    assert_eq!(rsa.jwk()["e"], "AQAB");
+
+    let pem = include_bytes!("../tests/keys/ed25519.pem");
+    let ed = KeyPair::from_pem(Algorithm::EdDSA, pem).unwrap();
+    assert_eq!(ed.jwk()["crv"], "Ed25519");
+    assert!(KeyPair::from_pem(Algorithm::RS256, pem).is_err());
+
+    let (generated, pem) = KeyPair::generate(Algorithm::EdDSA).unwrap();
+    let reloaded = KeyPair::from_pem(Algorithm::EdDSA, pem.as_bytes()).unwrap();
+    assert_eq!(generated.kid, reloaded.kid);
+    assert_ne!(generated.kid, ed.kid);
+    assert!(KeyPair::generate(Algorithm::RS256).is_err());
+
+    // tokens signed by the private half verify with the public half
+    for pair in [rsa, ed, generated] {
+        let claims = HashMap::from([("sub", "123")]);
+        let token = encode(&Header::new(pair.algorithm), &claims, pair.encoding_key()).unwrap();
+
+        let mut validation = Validation::new(pair.algorithm);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        let decoded = decode::<HashMap<String, String>>(&token, pair.decoding_key(), &validation);
+        assert_eq!(decoded.unwrap().claims["sub"], "123");
+    }
+}
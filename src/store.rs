@@ -1,36 +1,641 @@
-use std::sync::Arc;
-use tokio::sync::Mutex;
+mod index;
+pub mod memory;
+pub mod sqlite;
+mod transaction;
+
+use crate::models;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use index::Indexes;
+pub use memory::{MemoryStorage, DEFAULT_WAL_LIMIT};
+pub use sqlite::SqliteStorage;
+pub use transaction::Transaction;
+use transaction::Undo;
+
+pub type Db = Arc<Store>;
+
+// Bump this whenever the serialized layout of a model, or the meaning of one
+// of its fields, changes, and teach `models::upgrade` to convert documents
+// saved with the previous layout.
+pub const DOCUMENT_VERSION: u32 = 5;
+
+/// Decides whether a document is part of a listing.
+pub type Filter<'a> = &'a dyn Fn(&[u8]) -> bool;
+
+/// Stores the serialized documents of a single collection by their ID.
+pub trait Repository: Send + Sync {
+    fn get(&self, id: u64) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Hands out an ID that no document of the collection has ever had, not
+    /// even one that was deleted since.
+    fn next_id(&mut self) -> Result<u64, Error>;
+
+    /// Adds a document, failing if its ID is already taken. IDs that were
+    /// not handed out by `next_id` are never handed out by it afterwards.
+    fn insert(&mut self, id: u64, doc: Vec<u8>) -> Result<(), Error>;
+
+    /// Replaces an existing document and returns whether there was one.
+    fn replace(&mut self, id: u64, doc: Vec<u8>) -> Result<bool, Error>;
+
+    /// Removes a document and returns whether there was one.
+    fn delete(&mut self, id: u64) -> Result<bool, Error>;
+
+    /// Lists the documents accepted by `filter` in ID order, skipping the
+    /// first `offset` of them and returning at most `limit`.
+    fn list(&self, filter: Filter, offset: usize, limit: usize) -> Result<Vec<Vec<u8>>, Error>;
+
+    fn count(&self) -> Result<usize, Error>;
+
+    /// Makes sure every change so far is durably stored.
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+/// The key of a document in a secondary index.
+pub type Key = Vec<u8>;
+
+/// Encodes `value` as an index key, so that equal values give equal keys.
+pub fn key<K: Serialize + ?Sized>(value: &K) -> Key {
+    bincode::serialize(value).expect("invalid index key")
+}
+
+/// A secondary index of a collection.
+pub struct Index {
+    pub name: &'static str,
+
+    /// Whether a key may belong to a single document only.
+    pub unique: bool,
+}
+
+/// A model that is stored in its own collection, keyed by its ID.
+pub trait Document: Serialize + DeserializeOwned {
+    const COLLECTION: &'static str;
+
+    /// The secondary indexes of the collection, which the store keeps up to
+    /// date on every write.
+    const INDEXES: &'static [Index] = &[];
+
+    fn id(&self) -> u64;
+
+    /// The keys of the document, one for each of `INDEXES`.
+    fn keys(&self) -> Vec<Key> {
+        Vec::new()
+    }
+}
+
+// Decodes a document into its ID and index keys.
+type Indexer = fn(&[u8]) -> Result<(u64, Vec<Key>), Error>;
+
+/// Describes how the documents of a collection are indexed.
+pub struct Schema {
+    name: String,
+    indexes: &'static [Index],
+    keys: Indexer,
+}
+
+impl Schema {
+    pub fn of<T: Document>() -> Schema {
+        Schema {
+            name: T::COLLECTION.to_string(),
+            indexes: T::INDEXES,
+            keys: |doc| {
+                let doc: T = decode(doc)?;
+                Ok((doc.id(), doc.keys()))
+            },
+        }
+    }
+
+    /// A collection without secondary indexes.
+    pub fn plain(name: &str) -> Schema {
+        Schema {
+            name: name.to_string(),
+            indexes: &[],
+            keys: |_| Ok((0, Vec::new())),
+        }
+    }
+}
+
+/// The documents and indexes of a single collection, behind its lock.
+pub struct Table {
+    repo: Box<dyn Repository>,
+    indexes: Indexes,
+    // How to undo the writes of the running transaction, if any.
+    journal: Option<Vec<Undo>>,
+}
+
+/// The collections of a database, each behind its own lock so that readers
+/// of a collection run in parallel and never wait for other collections.
+///
+/// Code that holds several collections at once must lock them in the order
+/// they were opened (profiles, courses, then topics), so that two requests
+/// never wait for each other.
+#[derive(Default)]
+pub struct Store {
+    tables: Vec<RwLock<Table>>,
+    names: HashMap<String, usize>,
+}
+
+impl Store {
+    /// Locks the collection of `T` for reading.
+    pub async fn read<T: Document>(&self) -> Result<Reader<'_, T>, Error> {
+        Ok(Collection::new(self.table::<T>()?.read().await))
+    }
+
+    /// Locks the collection of `T` for writing.
+    pub async fn write<T: Document>(&self) -> Result<Writer<'_, T>, Error> {
+        Ok(Collection::new(self.table::<T>()?.write().await))
+    }
+
+    /// Starts a transaction over `collections`, see `Transaction`.
+    pub async fn transaction(&self, collections: &[&str]) -> Result<Transaction<'_>, Error> {
+        let mut positions = Vec::new();
+        for name in collections {
+            match self.names.get(*name) {
+                Some(position) => positions.push(*position),
+                None => return Err(Error::UnknownCollection(name.to_string())),
+            }
+        }
+
+        // Whatever order the collections were given in, lock them in the
+        // order of the store.
+        positions.sort_unstable();
+        positions.dedup();
+
+        let mut tables = Vec::new();
+        for position in positions {
+            tables.push((position, self.tables[position].write().await));
+        }
+
+        Ok(Transaction::new(self, tables))
+    }
+
+    fn table<T: Document>(&self) -> Result<&RwLock<Table>, Error> {
+        match self.names.get(T::COLLECTION) {
+            Some(index) => Ok(&self.tables[*index]),
+            None => Err(Error::UnknownCollection(T::COLLECTION.to_string())),
+        }
+    }
+}
+
+/// A collection locked for reading.
+pub type Reader<'a, T> = Collection<RwLockReadGuard<'a, Table>, T>;
+
+/// A collection locked for writing.
+pub type Writer<'a, T> = Collection<RwLockWriteGuard<'a, Table>, T>;
+
+/// The documents of a single collection, decoded as `T`, for as long as its
+/// lock `G` is held.
+pub struct Collection<G, T> {
+    table: G,
+    marker: PhantomData<T>,
+}
+
+impl<G, T> Collection<G, T> {
+    fn new(table: G) -> Collection<G, T> {
+        Collection {
+            table,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<G: Deref<Target = Table>, T: Document> Collection<G, T> {
+    pub fn get(&self, id: u64) -> Result<Option<T>, Error> {
+        match self.table.repo.get(id)? {
+            Some(doc) => Ok(Some(decode(&doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the first document accepted by `filter`.
+    pub fn find<F: Fn(&T) -> bool>(&self, filter: F) -> Result<Option<T>, Error> {
+        Ok(self.list(filter, 0, 1)?.pop())
+    }
+
+    /// Lists the documents accepted by `filter` in ID order, skipping the
+    /// first `offset` of them and returning at most `limit`.
+    pub fn list<F: Fn(&T) -> bool>(
+        &self,
+        filter: F,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<T>, Error> {
+        // The repository filters raw documents, so keep the first document
+        // that fails to decode and report it once the listing is done.
+        let failure = Cell::new(None);
+
+        let accept = |doc: &[u8]| match decode::<T>(doc) {
+            Ok(doc) => filter(&doc),
+            Err(err) => {
+                failure.set(Some(err));
+                false
+            }
+        };
+
+        let docs = self.table.repo.list(&accept, offset, limit)?;
+
+        if let Some(err) = failure.take() {
+            return Err(err);
+        }
+
+        docs.iter().map(|doc| decode(doc)).collect()
+    }
+
+    /// Returns the first document whose key in `index` is `value`.
+    pub fn find_by<K: Serialize + ?Sized>(
+        &self,
+        index: &str,
+        value: &K,
+    ) -> Result<Option<T>, Error> {
+        Ok(self.list_by(index, value, 0, 1)?.pop())
+    }
+
+    /// Lists the documents whose key in `index` is `value` in ID order,
+    /// skipping the first `offset` of them and returning at most `limit`.
+    pub fn list_by<K: Serialize + ?Sized>(
+        &self,
+        index: &str,
+        value: &K,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<T>, Error> {
+        let position = self
+            .table
+            .indexes
+            .position(index)
+            .ok_or_else(|| Error::UnknownIndex(T::COLLECTION.to_string(), index.to_string()))?;
+
+        let mut docs = Vec::new();
+        for id in self
+            .table
+            .indexes
+            .ids(position, &key(value))
+            .skip(offset)
+            .take(limit)
+        {
+            if let Some(doc) = self.get(id)? {
+                docs.push(doc);
+            }
+        }
+
+        Ok(docs)
+    }
+
+    pub fn count(&self) -> Result<usize, Error> {
+        self.table.repo.count()
+    }
+}
+
+impl<G: DerefMut<Target = Table>, T: Document> Collection<G, T> {
+    /// Hands out an ID for a new document, see `Repository::next_id`.
+    pub fn next_id(&mut self) -> Result<u64, Error> {
+        self.table.repo.next_id()
+    }
+
+    /// Adds a document, failing if its ID or one of its unique keys is
+    /// already taken.
+    pub fn insert(&mut self, doc: &T) -> Result<(), Error> {
+        let keys = self.keys(doc)?;
+
+        self.table.journal(doc.id())?;
+        self.table.repo.insert(doc.id(), encode(doc)?)?;
+        self.table.indexes.add(doc.id(), keys);
+        Ok(())
+    }
+
+    /// Replaces the document with the same ID and returns whether there was
+    /// one, failing if one of its unique keys belongs to another document.
+    pub fn replace(&mut self, doc: &T) -> Result<bool, Error> {
+        let keys = self.keys(doc)?;
+
+        self.table.journal(doc.id())?;
+        if !self.table.repo.replace(doc.id(), encode(doc)?)? {
+            return Ok(false);
+        }
+
+        self.table.indexes.remove(doc.id());
+        self.table.indexes.add(doc.id(), keys);
+        Ok(true)
+    }
+
+    /// Removes a document and returns whether there was one.
+    pub fn delete(&mut self, id: u64) -> Result<bool, Error> {
+        self.table.journal(id)?;
+        if !self.table.repo.delete(id)? {
+            return Ok(false);
+        }
+
+        self.table.indexes.remove(id);
+        Ok(true)
+    }
+
+    // Returns the index keys of `doc` once they are known not to conflict
+    // with another document.
+    fn keys(&self, doc: &T) -> Result<Vec<Key>, Error> {
+        let keys = doc.keys();
+
+        match self.table.indexes.check(doc.id(), &keys) {
+            Ok(()) => Ok(keys),
+            Err(index) => Err(Error::Conflict(T::COLLECTION.to_string(), index)),
+        }
+    }
+}
+
+fn decode<T: Document>(doc: &[u8]) -> Result<T, Error> {
+    bincode::deserialize(doc).map_err(|err| Error::Document(T::COLLECTION.to_string(), err))
+}
+
+fn encode<T: Document>(doc: &T) -> Result<Vec<u8>, Error> {
+    bincode::serialize(doc).map_err(|err| Error::Document(T::COLLECTION.to_string(), err))
+}
+
+/// Opens the repositories that make up a database.
+pub trait Storage {
+    fn open(&self, collection: &str) -> Result<Box<dyn Repository>, Error>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Corrupt(PathBuf, String),
+    Sqlite(rusqlite::Error),
+    Duplicate(u64),
+    /// A unique key of a collection is already taken, by index name.
+    Conflict(String, &'static str),
+    UnknownCollection(String),
+    UnknownIndex(String, String),
+    Document(String, bincode::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            Error::Corrupt(path, reason) => {
+                write!(f, "{}: corrupt data ({})", path.display(), reason)
+            }
+            Error::Sqlite(err) => write!(f, "sqlite: {}", err),
+            Error::Duplicate(id) => write!(f, "document {} already exists", id),
+            Error::Conflict(collection, index) => {
+                write!(f, "{}: {} is already taken", collection, index)
+            }
+            Error::UnknownCollection(name) => write!(f, "unknown collection {}", name),
+            Error::UnknownIndex(collection, index) => {
+                write!(f, "{}: unknown index {}", collection, index)
+            }
+            Error::Document(collection, err) => {
+                write!(f, "{}: invalid document ({})", collection, err)
+            }
+        }
+    }
+}
 
-pub type Collection = HashMap<String, Vec<Vec<u8>>>;
-pub type Db = Arc<Mutex<Collection>>;
+impl std::error::Error for Error {}
 
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+/// Creates a database that only lives in memory.
 pub async fn new_db(collections: Vec<&str>) -> Db {
-    let db = Arc::new(Mutex::new(Collection::new()));
+    open(collections, &MemoryStorage::default())
+        .await
+        .expect("Error creating in-memory database.")
+}
 
-    let clone = db.clone();
+/// Creates a database whose collections are opened from `storage`, indexed
+/// as described by `models::schema`.
+pub async fn open(collections: Vec<&str>, storage: &dyn Storage) -> Result<Db, Error> {
+    let schemas = collections.into_iter().map(models::schema).collect();
 
-    let mut _db = clone.lock().await;
+    Ok(Arc::new(open_schemas(schemas, storage)?))
+}
+
+fn open_schemas(schemas: Vec<Schema>, storage: &dyn Storage) -> Result<Store, Error> {
+    let mut db = Store::default();
+
+    for schema in schemas {
+        let repo = storage.open(&schema.name)?;
+        let mut indexes = Indexes::new(schema.indexes);
+
+        if !schema.indexes.is_empty() {
+            for doc in repo.list(&|_| true, 0, usize::MAX)? {
+                let (id, keys) = (schema.keys)(&doc)?;
+
+                if let Err(index) = indexes.check(id, &keys) {
+                    return Err(Error::Conflict(schema.name, index));
+                }
+                indexes.add(id, keys);
+            }
+        }
 
-    for name in collections {
-        _db.insert(name.to_string(), Vec::new());
+        db.names.insert(schema.name, db.tables.len());
+        db.tables.push(RwLock::new(Table {
+            repo,
+            indexes,
+            journal: None,
+        }));
     }
 
-    db
+    Ok(db)
+}
+
+/// Flushes every collection of `db` to its storage.
+pub async fn snapshot(db: &Db) -> Result<(), Error> {
+    for table in &db.tables {
+        table.write().await.repo.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+struct Note {
+    id: u64,
+    text: String,
+}
+
+#[cfg(test)]
+impl Document for Note {
+    const COLLECTION: &'static str = "notes";
+
+    const INDEXES: &'static [Index] = &[Index {
+        name: "text",
+        unique: true,
+    }];
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        vec![key(&self.text)]
+    }
 }
 
 #[tokio::test]
 async fn test_new_db() {
-    let name = "test";
+    let name = Note::COLLECTION;
     let collections = vec![name];
 
     let db = new_db(collections);
     let db = db.await;
 
-    let mut db = db.lock().await;
-    assert_eq!(db.get(name), Some(&vec![]));
+    let mut notes = db.write::<Note>().await.unwrap();
+    assert_eq!(notes.count().unwrap(), 0);
+
+    let note = Note {
+        id: 4,
+        text: String::from("four"),
+    };
+    notes.insert(&note).unwrap();
+    assert_eq!(notes.get(4).unwrap(), Some(note));
+
+    // IDs are never handed out twice, even after a delete.
+    assert_eq!(notes.next_id().unwrap(), 5);
+    assert!(notes.delete(4).unwrap());
+    assert_eq!(notes.next_id().unwrap(), 6);
+}
+
+#[tokio::test]
+async fn test_collection_errors() {
+    let db = new_db(vec![Note::COLLECTION]).await;
+
+    db.tables[0]
+        .write()
+        .await
+        .repo
+        .insert(1, vec![1, 2, 3])
+        .unwrap();
+
+    let notes = db.read::<Note>().await.unwrap();
+    assert!(matches!(notes.get(1), Err(Error::Document(..))));
+    assert!(matches!(
+        notes.list(|_| true, 0, 10),
+        Err(Error::Document(..))
+    ));
+
+    let db = new_db(vec![]).await;
+    assert!(matches!(
+        db.read::<Note>().await,
+        Err(Error::UnknownCollection(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_collection_locks() {
+    let db = new_db(vec![Note::COLLECTION]).await;
+
+    // Readers share the lock, a writer waits for all of them.
+    let first = db.read::<Note>().await.unwrap();
+    let second = db.read::<Note>().await.unwrap();
+    assert!(db.tables[0].try_write().is_err());
+
+    drop(first);
+    drop(second);
+    assert!(db.tables[0].try_write().is_ok());
+}
+
+#[tokio::test]
+async fn test_collection_indexes() {
+    let storage = MemoryStorage::default();
+    let db = open_schemas(vec![Schema::of::<Note>()], &storage).unwrap();
+    let mut notes = db.write::<Note>().await.unwrap();
+
+    let note = |id, text: &str| Note {
+        id,
+        text: text.to_string(),
+    };
+
+    notes.insert(&note(1, "one")).unwrap();
+    notes.insert(&note(2, "two")).unwrap();
+    assert!(matches!(
+        notes.insert(&note(3, "one")),
+        Err(Error::Conflict(_, "text"))
+    ));
+    assert!(matches!(
+        notes.replace(&note(2, "one")),
+        Err(Error::Conflict(_, "text"))
+    ));
+
+    assert_eq!(notes.find_by("text", "two").unwrap(), Some(note(2, "two")));
+
+    // Keys follow replacements and deletes.
+    assert!(notes.replace(&note(2, "three")).unwrap());
+    assert_eq!(notes.find_by("text", "two").unwrap(), None);
+    assert!(notes.delete(1).unwrap());
+    notes.insert(&note(4, "one")).unwrap();
+    assert_eq!(
+        notes.list_by("text", "one", 0, 10).unwrap(),
+        vec![note(4, "one")]
+    );
+
+    assert!(matches!(
+        notes.find_by("title", "one"),
+        Err(Error::UnknownIndex(..))
+    ));
+}
+
+#[tokio::test]
+async fn test_transaction() {
+    let storage = MemoryStorage::default();
+    let db = open_schemas(vec![Schema::of::<Note>(), Schema::plain("other")], &storage).unwrap();
+
+    let note = |id, text: &str| Note {
+        id,
+        text: text.to_string(),
+    };
+
+    db.write::<Note>()
+        .await
+        .unwrap()
+        .insert(&note(1, "one"))
+        .unwrap();
+
+    // Rolling back restores documents and their index keys.
+    let mut tx = db.transaction(&["other", Note::COLLECTION]).await.unwrap();
+    let mut notes = tx.collection::<Note>().unwrap();
+    notes.insert(&note(2, "two")).unwrap();
+    assert!(notes.replace(&note(1, "uno")).unwrap());
+    assert_eq!(notes.count().unwrap(), 2);
+    tx.rollback().unwrap();
+
+    let notes = db.read::<Note>().await.unwrap();
+    assert_eq!(notes.list(|_| true, 0, 10).unwrap(), vec![note(1, "one")]);
+    assert_eq!(notes.find_by("text", "one").unwrap(), Some(note(1, "one")));
+    assert_eq!(notes.find_by("text", "uno").unwrap(), None);
+    drop(notes);
+
+    // So does dropping a transaction without committing it.
+    {
+        let mut tx = db.transaction(&[Note::COLLECTION]).await.unwrap();
+        assert!(tx.collection::<Note>().unwrap().delete(1).unwrap());
+    }
+    assert_eq!(db.read::<Note>().await.unwrap().count().unwrap(), 1);
+
+    let mut tx = db.transaction(&[Note::COLLECTION]).await.unwrap();
+    tx.collection::<Note>()
+        .unwrap()
+        .insert(&note(2, "two"))
+        .unwrap();
+    tx.commit();
+    assert_eq!(db.read::<Note>().await.unwrap().count().unwrap(), 2);
 
-    let docs: &mut Vec<Vec<u8>> = db.get_mut(name).unwrap();
-    docs.push(vec![4]);
-    assert_eq!(db.get(name), Some(&vec![vec![4]]));
+    let mut tx = db.transaction(&["other"]).await.unwrap();
+    assert!(matches!(
+        tx.collection::<Note>(),
+        Err(Error::UnknownCollection(_))
+    ));
 }
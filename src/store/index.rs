@@ -0,0 +1,115 @@
+use super::{Index, Key};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The secondary indexes of a single collection, kept in memory whatever the
+/// storage and rebuilt whenever the collection is opened.
+pub struct Indexes {
+    defs: &'static [Index],
+    // Document IDs by key, for each of `defs`.
+    entries: Vec<BTreeMap<Key, BTreeSet<u64>>>,
+    // The keys of every document, to find its entries again when it changes.
+    keys: HashMap<u64, Vec<Key>>,
+}
+
+impl Indexes {
+    pub fn new(defs: &'static [Index]) -> Indexes {
+        Indexes {
+            defs,
+            entries: defs.iter().map(|_| BTreeMap::new()).collect(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.defs.iter().position(|def| def.name == name)
+    }
+
+    /// Returns the name of a unique index in which another document than
+    /// `id` already has one of `keys`.
+    pub fn check(&self, id: u64, keys: &[Key]) -> Result<(), &'static str> {
+        for ((def, entries), key) in self.defs.iter().zip(&self.entries).zip(keys) {
+            if !def.unique {
+                continue;
+            }
+
+            if let Some(ids) = entries.get(key) {
+                if ids.iter().any(|other| *other != id) {
+                    return Err(def.name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, id: u64, keys: Vec<Key>) {
+        for (entries, key) in self.entries.iter_mut().zip(&keys) {
+            entries.entry(key.clone()).or_default().insert(id);
+        }
+
+        self.keys.insert(id, keys);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let keys = match self.keys.remove(&id) {
+            Some(keys) => keys,
+            None => return,
+        };
+
+        for (entries, key) in self.entries.iter_mut().zip(&keys) {
+            if let Some(ids) = entries.get_mut(key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    entries.remove(key);
+                }
+            }
+        }
+    }
+
+    /// The keys of the document `id`, if it is indexed.
+    pub fn keys(&self, id: u64) -> Option<&Vec<Key>> {
+        self.keys.get(&id)
+    }
+
+    /// The IDs of the documents with `key` in the index at `position`, in
+    /// ascending order.
+    pub fn ids<'a>(&'a self, position: usize, key: &Key) -> impl Iterator<Item = u64> + 'a {
+        self.entries[position]
+            .get(key)
+            .into_iter()
+            .flat_map(|ids| ids.iter().copied())
+    }
+}
+
+#[test]
+fn test_indexes() {
+    const DEFS: &[Index] = &[
+        Index {
+            name: "unique",
+            unique: true,
+        },
+        Index {
+            name: "shared",
+            unique: false,
+        },
+    ];
+
+    let mut indexes = Indexes::new(DEFS);
+    assert_eq!(indexes.position("shared"), Some(1));
+    assert_eq!(indexes.position("other"), None);
+
+    indexes.add(1, vec![vec![1], vec![0]]);
+    indexes.add(2, vec![vec![2], vec![0]]);
+
+    assert_eq!(indexes.check(3, &[vec![1], vec![0]]), Err("unique"));
+    assert_eq!(indexes.check(3, &[vec![3], vec![0]]), Ok(()));
+    // A document never conflicts with itself.
+    assert_eq!(indexes.check(1, &[vec![1], vec![0]]), Ok(()));
+
+    assert_eq!(indexes.ids(1, &vec![0]).collect::<Vec<_>>(), vec![1, 2]);
+
+    indexes.remove(1);
+    assert_eq!(indexes.check(3, &[vec![1], vec![0]]), Ok(()));
+    assert_eq!(indexes.ids(1, &vec![0]).collect::<Vec<_>>(), vec![2]);
+    assert_eq!(indexes.ids(0, &vec![1]).count(), 0);
+}
@@ -0,0 +1,743 @@
+use super::{Error, Filter, Repository, Storage, DOCUMENT_VERSION};
+use crate::models;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Bump this whenever the layout of `Snapshot` changes.
+const SNAPSHOT_VERSION: u32 = 5;
+
+// Write-ahead logs start with this marker followed by the document version of
+// their records. Logs written before that have no header at all.
+const LOG_MAGIC: [u8; 4] = [0xff; 4];
+const LOG_HEADER_LEN: u64 = 8;
+
+// Compact the write-ahead log once it grows past this many bytes.
+pub const DEFAULT_WAL_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    document_version: u32,
+    // Sequence number of the last log record included in this snapshot.
+    lsn: u64,
+    // The last ID handed out by the collection.
+    sequence: u64,
+    documents: Vec<(u64, Vec<u8>)>,
+}
+
+// Snapshots written before the write-ahead log existed.
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    documents: Vec<Vec<u8>>,
+}
+
+// Snapshots written before documents were stored by ID.
+#[derive(Deserialize)]
+struct SnapshotV2 {
+    lsn: u64,
+    documents: Vec<Vec<u8>>,
+}
+
+// Snapshots written before the document version was recorded.
+#[derive(Deserialize)]
+struct SnapshotV3 {
+    lsn: u64,
+    documents: Vec<(u64, Vec<u8>)>,
+}
+
+// Snapshots written before the ID sequence was recorded.
+#[derive(Deserialize)]
+struct SnapshotV4 {
+    document_version: u32,
+    lsn: u64,
+    documents: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Entry {
+    // Logged before documents were stored by ID.
+    Push(Vec<u8>),
+    ReplaceAt(u64, Vec<u8>),
+
+    Insert(u64, Vec<u8>),
+    Replace(u64, Vec<u8>),
+    Delete(u64),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    lsn: u64,
+    entry: Entry,
+}
+
+/// Keeps every collection in memory, optionally persisted to a directory as a
+/// snapshot plus a write-ahead log per collection.
+#[derive(Default)]
+pub struct MemoryStorage {
+    dir: Option<PathBuf>,
+    wal_limit: u64,
+}
+
+impl MemoryStorage {
+    /// Persists the collections in `dir`, compacting each write-ahead log
+    /// once it grows past `wal_limit` bytes.
+    pub fn persistent(dir: &Path, wal_limit: u64) -> Result<MemoryStorage, Error> {
+        fs::create_dir_all(dir).map_err(|err| Error::Io(dir.to_path_buf(), err))?;
+
+        Ok(MemoryStorage {
+            dir: Some(dir.to_path_buf()),
+            wal_limit,
+        })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, collection: &str) -> Result<Box<dyn Repository>, Error> {
+        match &self.dir {
+            Some(dir) => Ok(Box::new(MemoryRepository::open(
+                dir,
+                collection,
+                self.wal_limit,
+            )?)),
+            None => Ok(Box::<MemoryRepository>::default()),
+        }
+    }
+}
+
+// Serialized documents by their ID.
+type Documents = BTreeMap<u64, Vec<u8>>;
+
+/// The documents of a single collection.
+///
+/// When the collection is persisted every change is appended to its
+/// write-ahead log before it is applied, so that writes only return once the
+/// change would survive the process being killed.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    docs: Documents,
+    // The last ID handed out, which only ever grows so that the IDs of
+    // deleted documents are never reused.
+    sequence: u64,
+    log: Option<Log>,
+}
+
+#[derive(Debug)]
+struct Log {
+    snapshot: PathBuf,
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    lsn: u64,
+    limit: u64,
+}
+
+impl MemoryRepository {
+    /// Loads the snapshot and replays the write-ahead log of `name` in `dir`.
+    /// A collection without any saved data starts empty, but unreadable or
+    /// corrupt data is an error so that it is never silently discarded.
+    pub fn open(dir: &Path, name: &str, limit: u64) -> Result<MemoryRepository, Error> {
+        let snapshot = dir.join(format!("{}.snapshot", name));
+        let path = dir.join(format!("{}.wal", name));
+
+        let saved = read_snapshot(&snapshot)?;
+        let version = saved.document_version;
+        let mut lsn = saved.lsn;
+
+        let docs = upgrade(name, version, saved.documents.into_iter().collect())
+            .map_err(|err| Error::Corrupt(snapshot.clone(), err.to_string()))?;
+
+        let mut repo = MemoryRepository {
+            docs,
+            sequence: saved.sequence,
+            log: None,
+        };
+
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| Error::Io(path.clone(), err))?;
+
+        let (log_version, records, size) = read_log(&mut file, &path)?;
+
+        for record in records {
+            // Records up to the snapshot's sequence number are already part
+            // of it, this happens when a compaction was interrupted.
+            if record.lsn <= lsn {
+                continue;
+            }
+
+            let entry = upgrade_entry(name, log_version, record.entry)
+                .map_err(|err| Error::Corrupt(path.clone(), err.to_string()))?;
+
+            repo.apply(entry)
+                .map_err(|reason| Error::Corrupt(path.clone(), reason))?;
+            lsn = record.lsn;
+        }
+
+        repo.log = Some(Log {
+            snapshot,
+            path,
+            file,
+            size,
+            lsn,
+            limit,
+        });
+
+        // Save upgraded documents right away, so that the log only ever holds
+        // records in the current layout.
+        if version < DOCUMENT_VERSION || log_version < DOCUMENT_VERSION || size == 0 {
+            repo.compact()?;
+        }
+
+        Ok(repo)
+    }
+
+    /// Writes a snapshot of the documents and empties the write-ahead log.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        write_snapshot(&log.snapshot, log.lsn, self.sequence, &self.docs)?;
+
+        let mut header = LOG_MAGIC.to_vec();
+        header.extend(DOCUMENT_VERSION.to_le_bytes());
+
+        log.file
+            .set_len(0)
+            .and_then(|_| log.file.write_all(&header))
+            .and_then(|_| log.file.sync_all())
+            .map_err(|err| Error::Io(log.path.clone(), err))?;
+        log.size = LOG_HEADER_LEN;
+
+        Ok(())
+    }
+
+    fn write(&mut self, entry: Entry) -> Result<(), Error> {
+        // Callers check the entry against the documents up front, so applying
+        // it cannot fail here.
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => {
+                self.apply(entry).expect("invalid log entry");
+                return Ok(());
+            }
+        };
+
+        let record = Record {
+            lsn: log.lsn + 1,
+            entry,
+        };
+
+        let data = bincode::serialize(&record)
+            .map_err(|err| Error::Corrupt(log.path.clone(), err.to_string()))?;
+
+        let mut frame = (data.len() as u32).to_le_bytes().to_vec();
+        frame.extend(data);
+
+        log.file
+            .write_all(&frame)
+            .and_then(|_| log.file.sync_data())
+            .map_err(|err| Error::Io(log.path.clone(), err))?;
+
+        log.size += frame.len() as u64;
+        log.lsn = record.lsn;
+
+        let compact = log.size > log.limit;
+
+        self.apply(record.entry).expect("invalid log entry");
+
+        // The change is already durable, a failed compaction only means the
+        // log keeps growing until the next attempt.
+        if compact {
+            if let Err(err) = self.compact() {
+                log::error!("Unable to compact write-ahead log: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: Entry) -> Result<(), String> {
+        match entry {
+            Entry::Push(doc) | Entry::ReplaceAt(_, doc) => {
+                let id = legacy_id(&doc)?;
+                self.sequence = self.sequence.max(id);
+                self.docs.insert(id, doc);
+            }
+            Entry::Insert(id, doc) => {
+                if self.docs.insert(id, doc).is_some() {
+                    return Err(format!("document {} inserted twice", id));
+                }
+                self.sequence = self.sequence.max(id);
+            }
+            Entry::Replace(id, doc) => match self.docs.get_mut(&id) {
+                Some(existing) => *existing = doc,
+                None => return Err(format!("no document {} to replace", id)),
+            },
+            Entry::Delete(id) => {
+                if self.docs.remove(&id).is_none() {
+                    return Err(format!("no document {} to delete", id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn get(&self, id: u64) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.docs.get(&id).cloned())
+    }
+
+    fn next_id(&mut self) -> Result<u64, Error> {
+        // Handing out an ID is not logged, the insert that uses it is.
+        self.sequence += 1;
+        Ok(self.sequence)
+    }
+
+    fn insert(&mut self, id: u64, doc: Vec<u8>) -> Result<(), Error> {
+        if self.docs.contains_key(&id) {
+            return Err(Error::Duplicate(id));
+        }
+
+        self.write(Entry::Insert(id, doc))
+    }
+
+    fn replace(&mut self, id: u64, doc: Vec<u8>) -> Result<bool, Error> {
+        if !self.docs.contains_key(&id) {
+            return Ok(false);
+        }
+
+        self.write(Entry::Replace(id, doc))?;
+        Ok(true)
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool, Error> {
+        if !self.docs.contains_key(&id) {
+            return Ok(false);
+        }
+
+        self.write(Entry::Delete(id))?;
+        Ok(true)
+    }
+
+    fn list(&self, filter: Filter, offset: usize, limit: usize) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(self
+            .docs
+            .values()
+            .filter(|doc| filter(doc))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn count(&self) -> Result<usize, Error> {
+        Ok(self.docs.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.compact()
+    }
+}
+
+// Documents saved before they were stored by ID all start with their `u8` ID,
+// which is the first field of every model.
+fn legacy_id(doc: &[u8]) -> Result<u64, String> {
+    match doc.first() {
+        Some(id) => Ok(u64::from(*id)),
+        None => Err(String::from("empty document")),
+    }
+}
+
+fn upgrade(collection: &str, version: u32, docs: Documents) -> Result<Documents, bincode::Error> {
+    if version >= DOCUMENT_VERSION {
+        return Ok(docs);
+    }
+
+    docs.into_iter()
+        .map(|(id, doc)| Ok((id, models::upgrade(collection, version, &doc)?)))
+        .collect()
+}
+
+fn upgrade_entry(collection: &str, version: u32, entry: Entry) -> Result<Entry, bincode::Error> {
+    if version >= DOCUMENT_VERSION {
+        return Ok(entry);
+    }
+
+    let upgrade = |doc: Vec<u8>| models::upgrade(collection, version, &doc);
+
+    Ok(match entry {
+        Entry::Push(doc) => Entry::Push(upgrade(doc)?),
+        Entry::ReplaceAt(index, doc) => Entry::ReplaceAt(index, upgrade(doc)?),
+        Entry::Insert(id, doc) => Entry::Insert(id, upgrade(doc)?),
+        Entry::Replace(id, doc) => Entry::Replace(id, upgrade(doc)?),
+        Entry::Delete(id) => Entry::Delete(id),
+    })
+}
+
+// Reads a snapshot of any version, converted to the current layout apart from
+// the documents themselves.
+fn read_snapshot(path: &Path) -> Result<Snapshot, Error> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Snapshot {
+                version: SNAPSHOT_VERSION,
+                document_version: DOCUMENT_VERSION,
+                lsn: 0,
+                sequence: 0,
+                documents: Vec::new(),
+            })
+        }
+        Err(err) => return Err(Error::Io(path.to_path_buf(), err)),
+    };
+
+    let corrupt = |err: bincode::Error| Error::Corrupt(path.to_path_buf(), err.to_string());
+    let corrupt_reason = |reason: String| Error::Corrupt(path.to_path_buf(), reason);
+
+    let version: u32 = bincode::deserialize(&data).map_err(corrupt)?;
+
+    // Older snapshots did not keep the sequence, the highest ID is the best
+    // that can be recovered from them.
+    let legacy = |lsn, document_version, documents: Vec<(u64, Vec<u8>)>| Snapshot {
+        version: SNAPSHOT_VERSION,
+        document_version,
+        lsn,
+        sequence: documents.iter().map(|(id, _)| *id).max().unwrap_or(0),
+        documents,
+    };
+    let legacy_ids = |docs: Vec<Vec<u8>>| -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        docs.into_iter()
+            .map(|doc| Ok((legacy_id(&doc).map_err(corrupt_reason)?, doc)))
+            .collect()
+    };
+
+    match version {
+        1 => {
+            let snapshot: SnapshotV1 = bincode::deserialize(&data[4..]).map_err(corrupt)?;
+            Ok(legacy(0, 1, legacy_ids(snapshot.documents)?))
+        }
+        2 => {
+            let snapshot: SnapshotV2 = bincode::deserialize(&data[4..]).map_err(corrupt)?;
+            Ok(legacy(snapshot.lsn, 1, legacy_ids(snapshot.documents)?))
+        }
+        3 => {
+            let snapshot: SnapshotV3 = bincode::deserialize(&data[4..]).map_err(corrupt)?;
+            Ok(legacy(snapshot.lsn, 1, snapshot.documents))
+        }
+        4 => {
+            let snapshot: SnapshotV4 = bincode::deserialize(&data[4..]).map_err(corrupt)?;
+            Ok(legacy(
+                snapshot.lsn,
+                snapshot.document_version,
+                snapshot.documents,
+            ))
+        }
+        SNAPSHOT_VERSION => bincode::deserialize(&data).map_err(corrupt),
+        _ => Err(Error::Corrupt(
+            path.to_path_buf(),
+            format!("unsupported version {}", version),
+        )),
+    }
+}
+
+fn write_snapshot(path: &Path, lsn: u64, sequence: u64, docs: &Documents) -> Result<(), Error> {
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        document_version: DOCUMENT_VERSION,
+        lsn,
+        sequence,
+        documents: docs.iter().map(|(id, doc)| (*id, doc.clone())).collect(),
+    };
+
+    let data = bincode::serialize(&snapshot)
+        .map_err(|err| Error::Corrupt(path.to_path_buf(), err.to_string()))?;
+
+    // Write to a temporary file first so that a crash halfway through never
+    // leaves a truncated snapshot behind.
+    let tmp = path.with_extension("snapshot.tmp");
+
+    let write = |tmp: &Path| -> io::Result<()> {
+        let mut file = fs::File::create(tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    };
+
+    write(&tmp).map_err(|err| Error::Io(path.to_path_buf(), err))
+}
+
+// Reads the document version and every complete record of a write-ahead log.
+// A record cut short at the end of the file was never acknowledged, so it is
+// dropped and the file is truncated to the last complete record.
+fn read_log(file: &mut fs::File, path: &Path) -> Result<(u32, Vec<Record>, u64), Error> {
+    let io_error = |err| Error::Io(path.to_path_buf(), err);
+
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+    file.read_to_end(&mut data).map_err(io_error)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    let mut version = 1;
+
+    if data.len() >= LOG_HEADER_LEN as usize && data.starts_with(&LOG_MAGIC) {
+        let mut value = [0; 4];
+        value.copy_from_slice(&data[4..8]);
+        version = u32::from_le_bytes(value);
+        offset = LOG_HEADER_LEN as usize;
+    }
+
+    while data.len() - offset >= 4 {
+        let mut len = [0; 4];
+        len.copy_from_slice(&data[offset..offset + 4]);
+        let len = u32::from_le_bytes(len) as usize;
+
+        let start = offset + 4;
+        if data.len() - start < len {
+            break;
+        }
+
+        let record: Record = bincode::deserialize(&data[start..start + len])
+            .map_err(|err| Error::Corrupt(path.to_path_buf(), err.to_string()))?;
+        records.push(record);
+
+        offset = start + len;
+    }
+
+    if offset < data.len() {
+        log::warn!(
+            "{}: dropping {} bytes of an incomplete record",
+            path.display(),
+            data.len() - offset
+        );
+        file.set_len(offset as u64).map_err(io_error)?;
+    }
+
+    Ok((version, records, offset as u64))
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dummy-api-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_snapshot_reload() {
+    let dir = test_dir("snapshot-reload");
+
+    let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    repo.insert(7, vec![4, 2]).unwrap();
+    repo.flush().unwrap();
+    assert_eq!(
+        fs::metadata(dir.join("test.wal")).unwrap().len(),
+        LOG_HEADER_LEN
+    );
+
+    let repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.get(7).unwrap(), Some(vec![4, 2]));
+
+    let repo = MemoryRepository::open(&dir, "other", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.count().unwrap(), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_log_replay() {
+    let dir = test_dir("log-replay");
+
+    {
+        let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+        repo.insert(1, vec![1]).unwrap();
+        repo.insert(2, vec![2]).unwrap();
+        repo.insert(3, vec![3]).unwrap();
+        assert!(repo.replace(1, vec![4]).unwrap());
+        assert!(repo.delete(3).unwrap());
+        assert!(matches!(repo.insert(2, vec![5]), Err(Error::Duplicate(2))));
+    }
+
+    // Simulate a crash in the middle of appending a record.
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("test.wal"))
+        .unwrap();
+    file.write_all(&[42, 0, 0, 0, 1]).unwrap();
+
+    let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.list(&|_| true, 0, 10).unwrap(), vec![vec![4], vec![2]]);
+
+    // New records go after the last complete one.
+    repo.insert(5, vec![5]).unwrap();
+    drop(repo);
+
+    let repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(
+        repo.list(&|_| true, 0, 10).unwrap(),
+        vec![vec![4], vec![2], vec![5]]
+    );
+    assert_eq!(repo.list(&|doc| doc[0] > 2, 1, 10).unwrap(), vec![vec![5]]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_log_compaction() {
+    let dir = test_dir("log-compaction");
+
+    {
+        let mut repo = MemoryRepository::open(&dir, "test", 32).unwrap();
+        for i in 0..10 {
+            repo.insert(u64::from(i), vec![i; 8]).unwrap();
+        }
+    }
+
+    assert!(fs::metadata(dir.join("test.wal")).unwrap().len() <= 32);
+    assert!(dir.join("test.snapshot").exists());
+
+    let repo = MemoryRepository::open(&dir, "test", 32).unwrap();
+    assert_eq!(repo.count().unwrap(), 10);
+    assert_eq!(repo.get(9).unwrap(), Some(vec![9; 8]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sequence_reload() {
+    let dir = test_dir("sequence-reload");
+
+    {
+        let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+        let id = repo.next_id().unwrap();
+        repo.insert(id, vec![1]).unwrap();
+        repo.insert(5, vec![5]).unwrap();
+        assert_eq!(repo.next_id().unwrap(), 6);
+        assert!(repo.delete(5).unwrap());
+    }
+
+    // Replaying the log restores the sequence.
+    let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.next_id().unwrap(), 6);
+    repo.flush().unwrap();
+
+    // So does the snapshot, although the highest ID is gone.
+    let mut repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.next_id().unwrap(), 7);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_legacy_snapshot() {
+    let dir = test_dir("legacy-snapshot");
+
+    #[derive(Serialize)]
+    struct Legacy {
+        version: u32,
+        lsn: u64,
+        documents: Vec<Vec<u8>>,
+    }
+
+    let legacy = Legacy {
+        version: 2,
+        lsn: 0,
+        documents: vec![vec![1, 0], vec![3, 0]],
+    };
+    fs::write(
+        dir.join("test.snapshot"),
+        bincode::serialize(&legacy).unwrap(),
+    )
+    .unwrap();
+
+    let repo = MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT).unwrap();
+    assert_eq!(repo.get(3).unwrap(), Some(vec![3, 0]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_legacy_documents() {
+    use crate::models::profile::{verify_password, Kind, Profile, PROFILES};
+    use crate::models::revocation::{Revocation, REVOCATIONS};
+
+    let dir = test_dir("legacy-documents");
+
+    // A profile as saved before IDs were widened, with its fields in order.
+    let profile = bincode::serialize(&(
+        9u8,
+        String::from("root"),
+        String::from("secret"),
+        String::new(),
+        String::new(),
+        Kind::Root,
+    ))
+    .unwrap();
+
+    let mut data = 3u32.to_le_bytes().to_vec();
+    data.extend(bincode::serialize(&(0u64, vec![(9u64, profile)])).unwrap());
+    fs::write(dir.join("profiles.snapshot"), data).unwrap();
+
+    let repo = MemoryRepository::open(&dir, PROFILES, DEFAULT_WAL_LIMIT).unwrap();
+    let doc: Profile = bincode::deserialize(&repo.get(9).unwrap().unwrap()).unwrap();
+    assert_eq!((doc.id, doc.username.as_str()), (9, "root"));
+    assert_eq!(doc.email, "");
+
+    // Passwords saved in plain text are hashed.
+    assert_ne!(doc.password, "secret");
+    assert!(verify_password(&doc.password, "secret"));
+
+    // The upgraded documents are saved right away.
+    let saved = read_snapshot(&dir.join("profiles.snapshot")).unwrap();
+    assert_eq!(saved.document_version, DOCUMENT_VERSION);
+
+    // A revocation as saved before impersonation tokens could be revoked by
+    // their target.
+    let revocation = bincode::serialize(&(1u64, String::new(), 7u64, 1_700_000_000i64)).unwrap();
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        document_version: 4,
+        lsn: 0,
+        sequence: 1,
+        documents: vec![(1, revocation)],
+    };
+    fs::write(
+        dir.join("revocations.snapshot"),
+        bincode::serialize(&snapshot).unwrap(),
+    )
+    .unwrap();
+
+    let repo = MemoryRepository::open(&dir, REVOCATIONS, DEFAULT_WAL_LIMIT).unwrap();
+    let doc: Revocation = bincode::deserialize(&repo.get(1).unwrap().unwrap()).unwrap();
+    assert_eq!(
+        (doc.session, doc.target, doc.expires),
+        (7, 0, 1_700_000_000)
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_corrupt_snapshot() {
+    let dir = test_dir("corrupt-snapshot");
+
+    fs::write(dir.join("test.snapshot"), [3, 0, 0, 0, 1]).unwrap();
+
+    match MemoryRepository::open(&dir, "test", DEFAULT_WAL_LIMIT) {
+        Err(Error::Corrupt(path, _)) => assert_eq!(path, dir.join("test.snapshot")),
+        _ => panic!("expected a corrupt snapshot error"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
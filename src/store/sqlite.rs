@@ -0,0 +1,293 @@
+use super::{Error, Filter, Repository, Storage, DOCUMENT_VERSION};
+use crate::models;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Keeps every collection as a table of an embedded SQLite database.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, Error> {
+        let conn = Connection::open(path)?;
+
+        // Readers don't block the writer, and a commit is durable once it
+        // reaches the log.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+
+        Ok(SqliteStorage::new(conn))
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStorage, Error> {
+        Ok(SqliteStorage::new(Connection::open_in_memory()?))
+    }
+
+    fn new(conn: Connection) -> SqliteStorage {
+        SqliteStorage {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn open(&self, collection: &str) -> Result<Box<dyn Repository>, Error> {
+        let table = format!("\"{}\"", collection.replace('"', "\"\""));
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        // Remembers the document version of every collection. Tables created
+        // before it existed hold documents of the first version.
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS _collections (name TEXT PRIMARY KEY, version INTEGER NOT NULL)",
+            [],
+        )?;
+
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            params![collection],
+            |row| row.get(0),
+        )?;
+
+        let version: Option<u32> = tx
+            .query_row(
+                "SELECT version FROM _collections WHERE name = ?1",
+                params![collection],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let version = match (exists, version) {
+            (_, Some(version)) => version,
+            (true, None) => 1,
+            (false, None) => DOCUMENT_VERSION,
+        };
+
+        tx.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, data BLOB NOT NULL)",
+                table
+            ),
+            [],
+        )?;
+
+        if version < DOCUMENT_VERSION {
+            upgrade(&tx, collection, &table, version)?;
+        }
+
+        // The last ID handed out by every collection. Tables that predate it
+        // continue after their highest ID.
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS _sequences (name TEXT PRIMARY KEY, value INTEGER NOT NULL)",
+            [],
+        )?;
+
+        tx.execute(
+            &format!(
+                "INSERT INTO _sequences (name, value) SELECT ?1, COALESCE(MAX(id), 0) FROM {}
+                 WHERE true ON CONFLICT (name) DO NOTHING",
+                table
+            ),
+            params![collection],
+        )?;
+
+        tx.execute(
+            "INSERT INTO _collections (name, version) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET version = excluded.version",
+            params![collection, DOCUMENT_VERSION],
+        )?;
+
+        tx.commit()?;
+
+        Ok(Box::new(SqliteRepository {
+            conn: self.conn.clone(),
+            name: collection.to_string(),
+            table,
+        }))
+    }
+}
+
+// Rewrites every document of a table saved with an older layout.
+fn upgrade(conn: &Connection, collection: &str, table: &str, version: u32) -> Result<(), Error> {
+    let docs = conn
+        .prepare(&format!("SELECT id, data FROM {}", table))?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (id, doc) in docs {
+        let doc = models::upgrade(collection, version, &doc)
+            .map_err(|err| Error::Document(collection.to_string(), err))?;
+
+        conn.execute(
+            &format!("UPDATE {} SET data = ?2 WHERE id = ?1", table),
+            params![id, doc],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub struct SqliteRepository {
+    conn: Arc<Mutex<Connection>>,
+    name: String,
+    // Already quoted for use in statements.
+    table: String,
+}
+
+impl Repository for SqliteRepository {
+    fn get(&self, id: u64) -> Result<Option<Vec<u8>>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let doc = conn
+            .query_row(
+                &format!("SELECT data FROM {} WHERE id = ?1", self.table),
+                params![id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(doc)
+    }
+
+    fn next_id(&mut self) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let id: i64 = conn.query_row(
+            "UPDATE _sequences SET value = value + 1 WHERE name = ?1 RETURNING value",
+            params![self.name],
+            |row| row.get(0),
+        )?;
+
+        Ok(id as u64)
+    }
+
+    fn insert(&mut self, id: u64, doc: Vec<u8>) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let inserted = tx.execute(
+            &format!(
+                "INSERT INTO {} (id, data) VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING",
+                self.table
+            ),
+            params![id as i64, doc],
+        )?;
+
+        if inserted == 0 {
+            return Err(Error::Duplicate(id));
+        }
+
+        tx.execute(
+            "UPDATE _sequences SET value = MAX(value, ?2) WHERE name = ?1",
+            params![self.name, id as i64],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn replace(&mut self, id: u64, doc: Vec<u8>) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let updated = conn.execute(
+            &format!("UPDATE {} SET data = ?2 WHERE id = ?1", self.table),
+            params![id as i64, doc],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let deleted = conn.execute(
+            &format!("DELETE FROM {} WHERE id = ?1", self.table),
+            params![id as i64],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    fn list(&self, filter: Filter, offset: usize, limit: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!("SELECT data FROM {} ORDER BY id", self.table))?;
+
+        let mut docs = Vec::new();
+        let mut skipped = 0;
+
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if docs.len() >= limit {
+                break;
+            }
+
+            let doc: Vec<u8> = row.get(0)?;
+            if !filter(&doc) {
+                continue;
+            }
+
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+
+            docs.push(doc);
+        }
+
+        Ok(docs)
+    }
+
+    fn count(&self) -> Result<usize, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let count: i64 =
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", self.table), [], |row| {
+                row.get(0)
+            })?;
+
+        Ok(count as usize)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // Every statement is committed on its own.
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sqlite_repository() {
+    let storage = SqliteStorage::open_in_memory().unwrap();
+    let mut repo = storage.open("test").unwrap();
+
+    repo.insert(1, vec![1]).unwrap();
+    repo.insert(2, vec![2]).unwrap();
+    repo.insert(3, vec![3]).unwrap();
+    assert!(matches!(repo.insert(2, vec![5]), Err(Error::Duplicate(2))));
+    assert_eq!(repo.next_id().unwrap(), 4);
+
+    assert_eq!(repo.get(2).unwrap(), Some(vec![2]));
+    assert_eq!(repo.get(4).unwrap(), None);
+
+    assert!(repo.replace(1, vec![4]).unwrap());
+    assert!(!repo.replace(4, vec![4]).unwrap());
+
+    assert!(repo.delete(3).unwrap());
+    assert!(!repo.delete(3).unwrap());
+    assert_eq!(repo.next_id().unwrap(), 5);
+
+    assert_eq!(repo.count().unwrap(), 2);
+    assert_eq!(repo.list(&|_| true, 0, 10).unwrap(), vec![vec![4], vec![2]]);
+    assert_eq!(repo.list(&|doc| doc[0] < 4, 0, 10).unwrap(), vec![vec![2]]);
+    assert_eq!(repo.list(&|_| true, 1, 1).unwrap(), vec![vec![2]]);
+
+    // Collections are independent tables of the same database.
+    let mut other = storage.open("other").unwrap();
+    assert_eq!(other.count().unwrap(), 0);
+    assert_eq!(other.next_id().unwrap(), 1);
+}
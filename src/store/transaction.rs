@@ -0,0 +1,132 @@
+use super::{Collection, Document, Error, Key, Store, Table};
+use tokio::sync::RwLockWriteGuard;
+
+// Restores a document to how it was before the transaction wrote to it.
+pub struct Undo {
+    id: u64,
+    // The document and its index keys, or `None` when it did not exist.
+    previous: Option<(Vec<u8>, Vec<Key>)>,
+}
+
+impl Table {
+    // Remembers how to undo a write to the document `id`, when part of a
+    // transaction.
+    pub(super) fn journal(&mut self, id: u64) -> Result<(), Error> {
+        if self.journal.is_none() {
+            return Ok(());
+        }
+
+        let previous = match self.repo.get(id)? {
+            Some(doc) => Some((doc, self.indexes.keys(id).cloned().unwrap_or_default())),
+            None => None,
+        };
+
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(Undo { id, previous });
+        }
+
+        Ok(())
+    }
+
+    // Undoes the writes of the transaction, the most recent first.
+    fn undo(&mut self) -> Result<(), Error> {
+        let journal = self.journal.take().unwrap_or_default();
+
+        for undo in journal.into_iter().rev() {
+            match undo.previous {
+                Some((doc, keys)) => {
+                    if !self.repo.replace(undo.id, doc.clone())? {
+                        self.repo.insert(undo.id, doc)?;
+                    }
+
+                    self.indexes.remove(undo.id);
+                    self.indexes.add(undo.id, keys);
+                }
+                None => {
+                    self.repo.delete(undo.id)?;
+                    self.indexes.remove(undo.id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads and writes several collections without other requests getting in
+/// between.
+///
+/// The collections stay locked for writing until the transaction ends, so
+/// other requests never see it half done. Writes are applied and persisted
+/// as they are made, and undone again when the transaction is rolled back,
+/// or dropped without being committed. This is no protection against
+/// crashes: one before the transaction ends may keep part of the writes.
+pub struct Transaction<'a> {
+    store: &'a Store,
+    // Locked in the order of the store.
+    tables: Vec<(usize, RwLockWriteGuard<'a, Table>)>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(
+        store: &'a Store,
+        mut tables: Vec<(usize, RwLockWriteGuard<'a, Table>)>,
+    ) -> Transaction<'a> {
+        for (_, table) in tables.iter_mut() {
+            table.journal = Some(Vec::new());
+        }
+
+        Transaction { store, tables }
+    }
+
+    /// Gives typed access to the collection of `T`, which must be one of the
+    /// collections the transaction was started with.
+    pub fn collection<T: Document>(&mut self) -> Result<Collection<&mut Table, T>, Error> {
+        let position = self.store.names.get(T::COLLECTION);
+
+        match self
+            .tables
+            .iter_mut()
+            .find(|(index, _)| Some(index) == position)
+        {
+            Some((_, table)) => Ok(Collection::new(&mut **table)),
+            None => Err(Error::UnknownCollection(T::COLLECTION.to_string())),
+        }
+    }
+
+    /// Keeps every write of the transaction.
+    pub fn commit(mut self) {
+        for (_, table) in self.tables.iter_mut() {
+            table.journal = None;
+        }
+    }
+
+    /// Undoes every write of the transaction.
+    pub fn rollback(mut self) -> Result<(), Error> {
+        self.undo()
+    }
+
+    // Undoes the writes of every collection, even when one of them fails,
+    // and reports the first failure.
+    fn undo(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for (_, table) in self.tables.iter_mut().rev() {
+            if let Err(err) = table.undo() {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.undo() {
+            log::error!("Unable to roll back transaction: {}", err);
+        }
+    }
+}
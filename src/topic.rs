@@ -1,7 +1,8 @@
 use super::auth;
+use super::config;
 use super::handlers;
 use super::models::topic::{Topic};
-use super::models::ListOptions;
+use super::models::{Id, ListOptions};
 use std::convert::Infallible;
 use warp::Filter;
 use super::store::Db;
@@ -29,7 +30,7 @@ pub fn create(
 pub fn get(
     db: Db,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("topics" / u8)
+    warp::path!("topics" / Id)
         .and(warp::get())
         .and(with_db(db.clone()))
         .and(auth::with_auth(db.clone()))
@@ -39,7 +40,7 @@ pub fn get(
 pub fn update(
     db: Db,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path!("topics" / u8)
+    warp::path!("topics" / Id)
         .and(warp::put())
         .and(json_body())
         .and(with_db(db.clone()))
@@ -65,5 +66,5 @@ fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
 fn json_body() -> impl Filter<Extract = (Topic,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    warp::body::content_length_limit(config::body_limit()).and(warp::body::json())
 }
@@ -0,0 +1,128 @@
+use data_encoding::BASE32_NOPAD;
+use rand::Rng;
+use ring::hmac;
+
+/// Seconds each code is valid for.
+pub const PERIOD: u64 = 30;
+
+/// Digits of each code.
+pub const DIGITS: u32 = 6;
+
+// Codes of the steps just before and after the current one are accepted too,
+// for clocks a little off and codes typed in late.
+const SKEW: u64 = 1;
+
+/// A new random secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// The time step `time`, a Unix timestamp, falls in.
+pub fn step(time: i64) -> u64 {
+    time.max(0) as u64 / PERIOD
+}
+
+/// The code of `secret` for `step`, as in RFC 6238 with HMAC-SHA1.
+pub fn code(secret: &[u8], step: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &step.to_be_bytes());
+    let digest = digest.as_ref();
+
+    // Dynamic truncation, from RFC 4226.
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// The step `code` was computed for, if it is a code of the base32 `secret`
+/// around `time` from a step after `last_step`, so that a code is only ever
+/// accepted once.
+pub fn verify(secret: &str, code: &str, time: i64, last_step: u64) -> Option<u64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let current = step(time);
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| constant_time_eq(&self::code(&secret, *step), &code))
+}
+
+/// The `otpauth://` URI authenticator apps enroll from, usually shown as a
+/// QR code.
+pub fn uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(issuer),
+        encode(account),
+        secret,
+        encode(issuer),
+        DIGITS,
+        PERIOD
+    )
+}
+
+// Percent-encodes everything but unreserved characters.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[test]
+fn test_totp() {
+    // The SHA-1 vectors of RFC 6238, truncated to six digits.
+    let secret = b"12345678901234567890";
+    for (time, expected) in [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+    ] {
+        assert_eq!(code(secret, step(time)), expected);
+    }
+
+    let encoded = BASE32_NOPAD.encode(secret);
+    assert_eq!(verify(&encoded, "081804", 1111111109, 0), Some(37037036));
+    assert_eq!(
+        verify(&encoded, "081 804", 1111111109 + 30, 0),
+        Some(37037036)
+    );
+    assert_eq!(verify(&encoded, "081804", 1111111109 + 60, 0), None);
+    assert_eq!(verify(&encoded, "081804", 1111111109, 37037036), None);
+    assert_eq!(verify(&encoded, "81804", 1111111109, 0), None);
+
+    assert_eq!(generate_secret().len(), 32);
+    assert_eq!(
+        uri("dummy api", "mara", "ABC"),
+        "otpauth://totp/dummy%20api:mara?secret=ABC&issuer=dummy%20api&algorithm=SHA1&digits=6&period=30"
+    );
+}
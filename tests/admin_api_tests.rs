@@ -0,0 +1,535 @@
+use serde_json::{json, Value};
+use std::str::from_utf8;
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    admin, auth, handlers,
+    models::lockout::Lockout,
+    models::profile::{Credentials, Kind, Profile},
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_export_import() {
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(1, "root", Kind::Root),
+            common::profile(2, "mara", Kind::Admin),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .recover(handlers::rejection::recover);
+
+    // admin login
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("mara"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let authorization = format!("Bearer {}", value["data"]["token"]);
+    let refresh_token = value["data"]["refresh_token"].clone();
+
+    // only root may export
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization)
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // root login
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("root"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let authorization = format!("Bearer {}", value["data"]["token"]);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", authorization.clone())
+        .path("/admin/api-keys")
+        .json(&json!({"name": "backup", "scopes": ["admin"]}))
+        .reply(&api)
+        .await;
+
+    let value: Value = serde_json::from_slice(resp.body()).unwrap();
+    let key = value["data"]["key"].as_str().unwrap().to_string();
+
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("mara"),
+            password: String::from("wrong"),
+        })
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // root export
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization.clone())
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    assert_eq!(value["data"]["profiles"].as_array().unwrap().len(), 2);
+    assert!(!data.contains("password"));
+    assert_eq!(value["data"]["courses"], json!([]));
+
+    let mut fixture = value["data"].clone();
+    fixture["courses"] = json!([{
+        "id": 10,
+        "title": "Rust in Action",
+        "description": "The most recommended training for Rust developers.",
+        "creator_id": 2,
+    }]);
+
+    // a topic of a course that is not part of the fixture
+    let mut invalid = fixture.clone();
+    invalid["topics"] = json!([{
+        "id": 20,
+        "title": "Ownership",
+        "creator_id": 2,
+        "course_id": 11,
+    }]);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", authorization.clone())
+        .path("/admin/import")
+        .json(&invalid)
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // root import
+    fixture["topics"] = json!([{
+        "id": 20,
+        "title": "Ownership",
+        "description": "",
+        "creator_id": 2,
+        "course_id": 10,
+    }]);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", authorization.clone())
+        .path("/admin/import")
+        .json(&fixture)
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // every login ends, as the profiles may be other people now
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization)
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": refresh_token }))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .header("X-API-Key", key)
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let lockouts = db.read::<Lockout>().await.unwrap().count().unwrap();
+    assert_eq!(lockouts, 0);
+
+    // imported profiles keep their password
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("root"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let authorization = format!("Bearer {}", value["data"]["token"]);
+
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization)
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    assert_eq!(value["data"], fixture);
+}
+
+#[tokio::test]
+async fn test_revoke_sessions() {
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(1, "root", Kind::Root),
+            common::profile(2, "mara", Kind::Admin),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .or(dummy_api::course::courses(db))
+        .recover(handlers::rejection::recover);
+
+    let mut sessions = Vec::new();
+    for username in ["root", "mara", "mara"] {
+        let resp = request()
+            .method("POST")
+            .path("/auth")
+            .json(&Credentials {
+                username: String::from(username),
+                password: String::from("secret"),
+            })
+            .reply(&api)
+            .await;
+
+        let data = from_utf8(resp.body()).unwrap();
+        let value: Value = serde_json::from_str(data).unwrap();
+        sessions.push((
+            format!("Bearer {}", value["data"]["token"]),
+            value["data"]["refresh_token"].clone(),
+        ));
+    }
+
+    let root = sessions[0].0.clone();
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[1].0.clone())
+        .path("/admin/api-keys")
+        .json(&json!({"name": "backup", "scopes": ["courses:read"]}))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let value: Value = serde_json::from_slice(resp.body()).unwrap();
+    let key = value["data"]["key"].as_str().unwrap().to_string();
+
+    let resp = request()
+        .header("X-API-Key", key.clone())
+        .path("/courses")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // only root may revoke sessions
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[1].0.clone())
+        .path("/admin/profiles/1/sessions/revoke")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", root.clone())
+        .path("/admin/profiles/2/sessions/revoke")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2,\"sessions\":2}}");
+
+    // every session of the profile is logged out
+    for (authorization, refresh) in &sessions[1..] {
+        let resp = request()
+            .method("POST")
+            .header("Authorization", authorization.clone())
+            .path("/admin/profiles/1/sessions/revoke")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request()
+            .method("POST")
+            .path("/auth/refresh")
+            .json(&json!({ "refresh_token": refresh }))
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // and its API keys are revoked
+    let resp = request()
+        .header("X-API-Key", key)
+        .path("/courses")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(from_utf8(resp.body()).unwrap().contains("Invalid API key!"));
+
+    // other profiles are left alone
+    let resp = request()
+        .method("GET")
+        .header("Authorization", root)
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[0].0.clone())
+        .path("/admin/profiles/9/sessions/revoke")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_api_keys() {
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(1, "root", Kind::Root),
+            common::profile(2, "mara", Kind::Admin),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .or(dummy_api::course::courses(db.clone()))
+        .recover(handlers::rejection::recover);
+
+    let mut sessions = Vec::new();
+    for username in ["root", "mara"] {
+        let resp = request()
+            .method("POST")
+            .path("/auth")
+            .json(&Credentials {
+                username: String::from(username),
+                password: String::from("secret"),
+            })
+            .reply(&api)
+            .await;
+
+        let value: Value = serde_json::from_slice(resp.body()).unwrap();
+        sessions.push(format!(
+            "Bearer {}",
+            value["data"]["token"].as_str().unwrap()
+        ));
+    }
+
+    // keys cannot have scopes their creator lacks
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[1].clone())
+        .path("/admin/api-keys")
+        .json(&json!({"name": "backup", "scopes": ["admin"]}))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let mut keys = Vec::new();
+    for (session, scopes) in [
+        (&sessions[0], json!(["admin"])),
+        (&sessions[1], json!(["courses:read"])),
+    ] {
+        let resp = request()
+            .method("POST")
+            .header("Authorization", session.clone())
+            .path("/admin/api-keys")
+            .json(&json!({"name": "backup", "scopes": scopes}))
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let value: Value = serde_json::from_slice(resp.body()).unwrap();
+        let key = value["data"]["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with(value["data"]["prefix"].as_str().unwrap()));
+        keys.push((value["data"]["id"].as_u64().unwrap(), key));
+    }
+
+    // the root key exports, by either header
+    let resp = request()
+        .header("X-API-Key", keys[0].1.clone())
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .header("Authorization", format!("Bearer {}", keys[0].1))
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // but cannot read courses, nor create keys
+    let resp = request()
+        .header("X-API-Key", keys[0].1.clone())
+        .path("/courses")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", keys[0].1))
+        .path("/admin/api-keys")
+        .json(&json!({"name": "copy", "scopes": ["admin"]}))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // the admin key reads courses only
+    let resp = request()
+        .header("X-API-Key", keys[1].1.clone())
+        .path("/courses")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .header("X-API-Key", keys[1].1.clone())
+        .path("/admin/export")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // admins list their own keys, root lists all of them
+    let resp = request()
+        .header("Authorization", sessions[1].clone())
+        .path("/admin/api-keys")
+        .reply(&api)
+        .await;
+
+    let value: Value = serde_json::from_slice(resp.body()).unwrap();
+    let listed = value["data"].as_array().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["id"], keys[1].0);
+    assert!(listed[0]["last_used_at"].is_i64());
+    assert!(listed[0].get("hash").is_none());
+    assert!(listed[0].get("key").is_none());
+
+    let resp = request()
+        .header("Authorization", sessions[0].clone())
+        .path("/admin/api-keys")
+        .reply(&api)
+        .await;
+
+    let value: Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(value["data"].as_array().unwrap().len(), 2);
+
+    // admins cannot revoke keys of others
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[1].clone())
+        .path(&format!("/admin/api-keys/{}/revoke", keys[0].0))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", sessions[1].clone())
+        .path(&format!("/admin/api-keys/{}/revoke", keys[1].0))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .header("X-API-Key", keys[1].1.clone())
+        .path("/courses")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(from_utf8(resp.body()).unwrap().contains("Invalid API key!"));
+
+    // keys lose the rights their profile loses
+    let export = || {
+        request()
+            .header("X-API-Key", keys[0].1.clone())
+            .path("/admin/export")
+    };
+
+    let mut profiles = db.write::<Profile>().await.unwrap();
+    let root = profiles.get(1).unwrap().unwrap();
+    profiles.replace(&root.with_kind(Kind::Admin)).unwrap();
+    drop(profiles);
+
+    let resp = export().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    db.write::<Profile>().await.unwrap().delete(1).unwrap();
+
+    let resp = export().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(from_utf8(resp.body()).unwrap().contains("Unknown user!"));
+}
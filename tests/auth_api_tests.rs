@@ -1,40 +1,37 @@
+use serde_json::{json, Value};
+use std::str::from_utf8;
 use warp::http::StatusCode;
 use warp::test::request;
+use warp::Filter;
 
 use dummy_api::{
-    auth, config,
-    models::profile::{self, Credentials, Kind, Profile},
-    store,
+    auth, handlers,
+    models::profile::{Credentials, Kind, Profile},
+    profile as profile_filter,
 };
 
+mod common;
+
 #[tokio::test]
 async fn test_login() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES]).await;
+    let db = common::setup(
+        common::config(),
+        &[common::profile(123, "mara", Kind::Admin)],
+    )
+    .await;
 
     let username = String::from("mara");
     let password = String::from("secret");
 
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(username.clone())
-        .with_password(password.clone())
-        .with_kind(Kind::Admin);
-
-    profile::initialize(&db, &[admin]).await;
-
-    let api = auth::auth(db);
+    let api = auth::auth(db).recover(handlers::rejection::recover);
 
     // login with existing user
     let resp = request()
         .method("POST")
         .path("/auth")
         .json(&Credentials {
-            username: username,
-            password: password,
+            username,
+            password,
         })
         .reply(&api)
         .await;
@@ -55,3 +52,217 @@ async fn test_login() {
     assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
     assert_eq!(resp.body(), "{\"error\":\"Invalid username or password!\"}");
 }
+
+#[tokio::test]
+async fn test_refresh() {
+    let db = common::setup(
+        common::config(),
+        &[common::profile(123, "mara", Kind::Admin)],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(profile_filter::profiles(db))
+        .recover(handlers::rejection::recover);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("mara"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let first = value["data"]["refresh_token"].clone();
+    let access = format!("Bearer {}", value["data"]["token"].as_str().unwrap());
+
+    // exchange the refresh token for new tokens
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": first }))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let second = value["data"]["refresh_token"].clone();
+    assert_eq!(value["data"]["id"], 123);
+    assert_ne!(value["data"]["token"], Value::Null);
+    assert_ne!(second, first);
+
+    let get_profile = || {
+        request()
+            .method("GET")
+            .header("Authorization", access.clone())
+            .path("/profiles/123")
+    };
+
+    let resp = get_profile().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // reusing the first token revokes its whole family, and logs the
+    // session out
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": first }))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": second }))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Invalid refresh token!\"}");
+
+    let resp = get_profile().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Token has been revoked!\"}");
+}
+
+#[tokio::test]
+async fn test_logout() {
+    let db = common::setup(
+        common::config(),
+        &[common::profile(123, "mara", Kind::Admin)],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(profile_filter::profiles(db))
+        .recover(handlers::rejection::recover);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("mara"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let authorization = format!("Bearer {}", value["data"]["token"]);
+    let refresh = value["data"]["refresh_token"].clone();
+
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization.clone())
+        .path("/profiles/123")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", authorization.clone())
+        .path("/auth/logout")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the access token is no longer accepted
+    let resp = request()
+        .method("GET")
+        .header("Authorization", authorization.clone())
+        .path("/profiles/123")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Token has been revoked!\"}");
+
+    // and neither is the refresh token of the session
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": refresh }))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_rejections() {
+    let db = common::setup(
+        common::config(),
+        &[common::profile(123, "mara", Kind::Admin)],
+    )
+    .await;
+
+    let api = profile_filter::profiles(db.clone()).recover(handlers::rejection::recover);
+
+    let cases = vec![
+        (None, "Not authorized!"),
+        (
+            Some(String::from("Basic bWFyYTpzZWNyZXQ=")),
+            "Malformed authorization header!",
+        ),
+        (Some(String::from("Bearer not.a.token")), "Invalid token!"),
+    ];
+
+    for (header, message) in cases {
+        let mut req = request().method("GET").path("/profiles/123");
+        if let Some(header) = header {
+            req = req.header("Authorization", header);
+        }
+
+        let resp = req.reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.body(), &format!("{{\"error\":\"{}\"}}", message));
+    }
+
+    // optional_auth lets anonymous requests through but not invalid tokens
+    let whoami = auth::optional_auth(db.clone())
+        .map(|user: Option<auth::User>| format!("{:?}", user.map(|user| user.id)))
+        .recover(handlers::rejection::recover);
+
+    let resp = request().path("/").reply(&whoami).await;
+    assert_eq!(resp.body(), "None");
+
+    let token = auth::generate_token(123, Kind::Admin, 0).unwrap();
+    let resp = request()
+        .path("/")
+        .header("Authorization", format!("Bearer {}", token))
+        .reply(&whoami)
+        .await;
+    assert_eq!(resp.body(), "Some(123)");
+
+    let resp = request()
+        .path("/")
+        .header("Authorization", "Bearer not.a.token")
+        .reply(&whoami)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // tokens of deleted profiles are no longer accepted
+    db.write::<Profile>().await.unwrap().delete(123).unwrap();
+
+    let resp = request()
+        .method("GET")
+        .path("/profiles/123")
+        .header("Authorization", format!("Bearer {}", token))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Unknown user!\"}");
+}
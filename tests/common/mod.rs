@@ -0,0 +1,53 @@
+// Not every test file uses every helper.
+#![allow(dead_code)]
+
+use dummy_api::config::{self, Config};
+use dummy_api::models::profile::{self, Kind, Profile};
+use dummy_api::models::{
+    api_key, authorization_code, course, lockout, outbox, password_reset, refresh_token,
+    revocation, topic, two_factor, Id,
+};
+use dummy_api::store::{self, Db};
+
+/// The configuration of the tests, which sign tokens with a known secret.
+pub fn config() -> Config {
+    Config {
+        jwt_secret: "secret_key".as_bytes(),
+        ..Default::default()
+    }
+}
+
+/// Configures the application with `config`, unless another test of the
+/// same file did already, and opens a database with every collection,
+/// holding `profiles`.
+pub async fn setup(config: Config, profiles: &[Profile]) -> Db {
+    let _ = config::CONFIG.set(config);
+
+    let db = store::new_db(vec![
+        profile::PROFILES,
+        course::COURSES,
+        topic::TOPICS,
+        refresh_token::REFRESH_TOKENS,
+        revocation::REVOCATIONS,
+        authorization_code::AUTHORIZATION_CODES,
+        api_key::API_KEYS,
+        lockout::LOCKOUTS,
+        two_factor::TWO_FACTORS,
+        two_factor::CHALLENGES,
+        outbox::OUTBOX,
+        password_reset::PASSWORD_RESETS,
+    ])
+    .await;
+
+    profile::initialize(&db, profiles).await;
+    db
+}
+
+/// A profile signing in with the password `secret`.
+pub fn profile(id: Id, username: &str, kind: Kind) -> Profile {
+    Profile::new()
+        .with_id(id)
+        .with_username(String::from(username))
+        .with_password(String::from("secret"))
+        .with_kind(kind)
+}
@@ -5,35 +5,27 @@ use warp::test::request;
 use warp::Filter;
 
 use dummy_api::{
-    auth, config, course as course_filter,
-    models::course::{self, Course},
-    models::profile::{self, Credentials, Kind, Profile},
-    store,
+    auth, course as course_filter, handlers,
+    models::course::Course,
+    models::profile::{Credentials, Kind},
 };
 
+mod common;
+
 #[tokio::test]
 async fn test_create_course() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    profile::initialize(&db, &[admin, trainee]).await;
-
-    let api = auth::auth(db.clone()).or(course_filter::courses(db));
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(course_filter::courses(db))
+        .recover(handlers::rejection::recover);
 
     // create course without authorization
     let resp = request()
@@ -119,27 +111,18 @@ async fn test_create_course() {
 
 #[tokio::test]
 async fn test_update_course() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    profile::initialize(&db, &[admin, trainee]).await;
-
-    let api = auth::auth(db.clone()).or(course_filter::courses(db));
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(course_filter::courses(db))
+        .recover(handlers::rejection::recover);
 
     // admin login
     let resp = request()
@@ -223,27 +206,18 @@ async fn test_update_course() {
 
 #[tokio::test]
 async fn test_list_courses() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    profile::initialize(&db, &[admin, trainee]).await;
-
-    let api = auth::auth(db.clone()).or(course_filter::courses(db));
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(course_filter::courses(db))
+        .recover(handlers::rejection::recover);
 
     // admin login
     let resp = request()
@@ -0,0 +1,180 @@
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    admin, auth, handlers, models::profile::Kind, models::two_factor, profile as profile_filter,
+};
+
+mod common;
+
+fn login(username: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth")
+        .json(&json!({ "username": username, "password": "secret" }))
+}
+
+fn impersonate(token: &str, id: u64) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", token))
+        .path(&format!("/admin/profiles/{}/impersonate", id))
+}
+
+fn get_profile(token: &str, id: u64) -> warp::test::RequestBuilder {
+    request()
+        .method("GET")
+        .header("Authorization", format!("Bearer {}", token))
+        .path(&format!("/profiles/{}", id))
+}
+
+fn data(body: &[u8]) -> Value {
+    let value: Value = serde_json::from_slice(body).unwrap();
+    value["data"].clone()
+}
+
+#[tokio::test]
+async fn test_impersonation() {
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(1, "root", Kind::Root),
+            common::profile(2, "mara", Kind::Admin),
+            common::profile(3, "lena", Kind::Trainee),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .or(profile_filter::profiles(db.clone()))
+        .recover(handlers::rejection::recover);
+
+    let resp = login("root").reply(&api).await;
+    let root_token = data(resp.body())["token"].as_str().unwrap().to_string();
+
+    let resp = login("mara").reply(&api).await;
+    let admin_token = data(resp.body())["token"].as_str().unwrap().to_string();
+
+    let resp = login("lena").reply(&api).await;
+    let trainee_token = data(resp.body())["token"].as_str().unwrap().to_string();
+
+    // only root users and admins can impersonate, and admins not root users
+    let resp = impersonate(&trainee_token, 2).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = impersonate(&admin_token, 1).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = impersonate(&admin_token, 2).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = impersonate(&admin_token, 9).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = impersonate(&admin_token, 3).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let impersonation = data(resp.body());
+    assert_eq!(impersonation["id"], 3);
+    assert_eq!(impersonation["role"], "student");
+    assert_eq!(impersonation["impersonator"], 2);
+    assert!(impersonation["refresh_token"].is_null());
+    let token = impersonation["token"].as_str().unwrap().to_string();
+
+    // the token acts as the trainee, and replies name the actor
+    let resp = auth::mark_impersonation(get_profile(&token, 3).reply(&api)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-impersonated-by"], "2");
+
+    let resp = auth::mark_impersonation(get_profile(&token, 2).reply(&api)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(resp.headers()["x-impersonated-by"], "2");
+
+    let resp = auth::mark_impersonation(get_profile(&admin_token, 2).reply(&api)).await;
+    assert!(!resp.headers().contains_key("x-impersonated-by"));
+
+    // nor can it change how the profile signs in
+    for path in [
+        "/auth/2fa/enroll",
+        "/auth/2fa/confirm",
+        "/auth/2fa/disable",
+        "/auth/2fa/recovery-codes",
+    ] {
+        let resp = request()
+            .method("POST")
+            .header("Authorization", format!("Bearer {}", token))
+            .path(path)
+            .json(&json!({ "code": "123456" }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    let enrolled = two_factor::find(&db, 3).await.unwrap();
+    assert!(enrolled.is_none());
+
+    // the token has the rights of the trainee only
+    let resp = impersonate(&token, 3).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // ending it revokes the token only, not the login of the actor
+    let resp = request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", admin_token))
+        .path("/auth/impersonation/end")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", token))
+        .path("/auth/impersonation/end")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2,\"impersonated\":3}}");
+
+    let resp = auth::mark_impersonation(get_profile(&token, 3).reply(&api)).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(!resp.headers().contains_key("x-impersonated-by"));
+
+    let resp = get_profile(&admin_token, 3).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // root users can impersonate admins, until they log out
+    let resp = impersonate(&root_token, 2).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = data(resp.body())["token"].as_str().unwrap().to_string();
+
+    let resp = impersonate(&token, 3).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.body(),
+        "{\"error\":\"Already impersonating a profile!\"}"
+    );
+
+    // and cannot create API keys that would outlive it
+    let resp = request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", token))
+        .path("/admin/api-keys")
+        .json(&json!({ "name": "backup", "scopes": ["courses:read"] }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", format!("Bearer {}", root_token))
+        .path("/auth/logout")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = get_profile(&token, 2).reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
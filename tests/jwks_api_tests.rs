@@ -0,0 +1,96 @@
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::from_utf8;
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    auth, config, handlers,
+    models::profile::{Credentials, Kind},
+    profile as profile_filter,
+    signing::KeyPair,
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_jwks() {
+    let pem = include_bytes!("keys/rs256.pem");
+
+    let db = common::setup(
+        config::Config {
+            jwt_key_pair: Some(KeyPair::from_pem(Algorithm::RS256, pem).unwrap()),
+            ..common::config()
+        },
+        &[common::profile(123, "mara", Kind::Admin)],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(profile_filter::profiles(db))
+        .recover(handlers::rejection::recover);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth")
+        .json(&Credentials {
+            username: String::from("mara"),
+            password: String::from("secret"),
+        })
+        .reply(&api)
+        .await;
+
+    let data = from_utf8(resp.body()).unwrap();
+    let value: Value = serde_json::from_str(data).unwrap();
+    let token = value["data"]["token"].as_str().unwrap().to_string();
+
+    let resp = request()
+        .method("GET")
+        .path("/.well-known/jwks.json")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // another service verifies the token with the published key
+    let jwks: JwkSet = serde_json::from_slice(resp.body()).unwrap();
+    let header = decode_header(&token).unwrap();
+    assert_eq!(header.alg, Algorithm::RS256);
+
+    let jwk = jwks.find(&header.kid.unwrap()).unwrap();
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&["dummy-api"]);
+    let claims =
+        decode::<HashMap<String, Value>>(&token, &DecodingKey::from_jwk(jwk).unwrap(), &validation)
+            .unwrap()
+            .claims;
+    assert_eq!(claims["user_id"], 123);
+
+    let resp = request()
+        .method("GET")
+        .header("Authorization", format!("Bearer {}", token))
+        .path("/profiles/123")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // a token naming a key we do not have
+    let (other, _) = KeyPair::generate(Algorithm::EdDSA).unwrap();
+    let mut header = jsonwebtoken::Header::new(Algorithm::EdDSA);
+    header.kid = Some(other.kid.clone());
+    let forged = jsonwebtoken::encode(&header, &claims, other.encoding_key()).unwrap();
+
+    let resp = request()
+        .method("GET")
+        .header("Authorization", format!("Bearer {}", forged))
+        .path("/profiles/123")
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Invalid token!\"}");
+}
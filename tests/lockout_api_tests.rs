@@ -0,0 +1,248 @@
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    admin, auth, config, handlers,
+    models::lockout::{self, Lockout, Policy},
+    models::profile::{Kind, Profile},
+};
+
+mod common;
+
+// Three failed logins lock a username out, five an address.
+fn lockout_config() -> config::Config {
+    config::Config {
+        lockout: Policy {
+            username_threshold: 3,
+            ip_threshold: 5,
+            duration: 60,
+            max_duration: 600,
+        },
+        ..common::config()
+    }
+}
+
+fn login(username: &str, password: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth")
+        .json(&json!({ "username": username, "password": password }))
+}
+
+#[test]
+fn test_lock_duration() {
+    let policy = Policy {
+        duration: 60,
+        max_duration: 600,
+        ..Default::default()
+    };
+
+    let durations: Vec<u64> = (0..6)
+        .map(|lockouts| policy.lock_duration(lockouts))
+        .collect();
+    assert_eq!(durations, vec![60, 120, 240, 480, 600, 600]);
+    assert_eq!(policy.lock_duration(u32::MAX), 600);
+}
+
+#[tokio::test]
+async fn test_lockout() {
+    let db = common::setup(
+        lockout_config(),
+        &[
+            common::profile(1, "root", Kind::Root),
+            common::profile(2, "mara", Kind::Admin),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db))
+        .recover(handlers::rejection::recover);
+
+    // failures are counted, and a success forgets them
+    for password in ["wrong", "wrong", "secret"] {
+        let resp = login("mara", password).reply(&api).await;
+        assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    for _ in 0..3 {
+        let resp = login("mara", "wrong").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // even the right password is refused once locked
+    let resp = login("mara", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    // counted from the start of the failed login, a second may have passed
+    let retry_after = resp.headers()["retry-after"].to_str().unwrap();
+    assert!(["59", "60"].contains(&retry_after));
+    assert_eq!(
+        resp.body(),
+        "{\"error\":\"Too many failed login attempts, try again later!\"}"
+    );
+
+    // other profiles are not locked
+    let resp = login("root", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let value: Value = serde_json::from_slice(resp.body()).unwrap();
+    let root = format!("Bearer {}", value["data"]["token"].as_str().unwrap());
+
+    // unknown usernames are locked as well, so that they cannot be told apart
+    for _ in 0..3 {
+        login("nobody", "wrong").reply(&api).await;
+    }
+
+    let resp = login("nobody", "wrong").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // an address trying many usernames is locked out, whichever it tries
+    let attacker: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    for username in ["a", "b", "c", "d", "e"] {
+        let resp = login(username, "wrong")
+            .remote_addr(attacker)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let resp = login("root", "secret")
+        .remote_addr(attacker)
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = login("root", "secret")
+        .remote_addr("10.0.0.2:4000".parse().unwrap())
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // unlocking takes a root user, mara cannot sign in anyway
+    let resp = login("mara", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = request()
+        .method("POST")
+        .path("/admin/profiles/2/unlock")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", root.clone())
+        .path("/admin/profiles/2/unlock")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2,\"unlocked\":true}}");
+
+    let resp = login("mara", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", root.clone())
+        .path("/admin/profiles/2/unlock")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2,\"unlocked\":false}}");
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", root)
+        .path("/admin/profiles/9/unlock")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_concurrent_failures() {
+    let db = common::setup(lockout_config(), &[common::profile(2, "mara", Kind::Admin)]).await;
+
+    let api = auth::auth(db).recover(handlers::rejection::recover);
+
+    // guesses sent at once are counted as they come, not after the fact
+    let mut guesses = tokio::task::JoinSet::new();
+    for _ in 0..10 {
+        let api = api.clone();
+        guesses.spawn(async move { login("mara", "wrong").reply(&api).await.status() });
+    }
+
+    let mut rejected = 0;
+    while let Some(status) = guesses.join_next().await {
+        match status.unwrap() {
+            StatusCode::UNAUTHORIZED => rejected += 1,
+            status => assert_eq!(status, StatusCode::TOO_MANY_REQUESTS),
+        }
+    }
+    assert_eq!(rejected, 3);
+
+    let resp = login("mara", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_concurrent_logins() {
+    let db = common::setup(
+        lockout_config(),
+        &[
+            common::profile(1, "mara", Kind::Trainee),
+            common::profile(2, "lena", Kind::Trainee),
+        ],
+    )
+    .await;
+
+    let api = auth::auth(db.clone()).recover(handlers::rejection::recover);
+
+    // logins waiting to check their password do not keep others waiting:
+    // both usernames and their address are counted while the profiles
+    // cannot be read yet
+    let profiles = db.write::<Profile>().await.unwrap();
+
+    let mut logins = tokio::task::JoinSet::new();
+    for username in ["mara", "lena"] {
+        let api = api.clone();
+        logins.spawn(async move {
+            let resp = login(username, "secret")
+                .remote_addr("10.0.0.1:4000".parse().unwrap())
+                .reply(&api)
+                .await;
+            resp.status()
+        });
+    }
+
+    let counted = async {
+        loop {
+            let lockouts = db.read::<Lockout>().await.unwrap();
+            if lockouts.count().unwrap() == 3 {
+                break;
+            }
+            drop(lockouts);
+            tokio::task::yield_now().await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), counted)
+        .await
+        .unwrap();
+
+    drop(profiles);
+
+    while let Some(status) = logins.join_next().await {
+        assert_eq!(status.unwrap(), StatusCode::OK);
+    }
+
+    // and the right password takes back the failures of the address
+    let lockouts = db.read::<Lockout>().await.unwrap();
+    let address = lockouts
+        .find_by(lockout::BY_SUBJECT, "ip:10.0.0.1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(address.failures, 0);
+}
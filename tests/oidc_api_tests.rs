@@ -0,0 +1,247 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::str::from_utf8;
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{auth, config, handlers, models::profile::Kind, models::two_factor, oidc, totp};
+
+mod common;
+
+const REDIRECT_URI: &str = "http://localhost:5173/callback";
+
+// The query of the Location header of a redirect.
+fn redirect_query(resp: &warp::http::Response<warp::hyper::body::Bytes>) -> Vec<(String, String)> {
+    let location = resp.headers()["location"].to_str().unwrap();
+    let (uri, query) = location.split_once('?').unwrap();
+    assert_eq!(uri, REDIRECT_URI);
+    serde_urlencoded::from_str(query).unwrap()
+}
+
+#[tokio::test]
+async fn test_authorization_code_flow() {
+    let db = common::setup(
+        config::Config {
+            oidc_redirect_uris: vec![String::from(REDIRECT_URI)],
+            ..common::config()
+        },
+        &[common::profile(123, "mara", Kind::Mentor)],
+    )
+    .await;
+
+    let api = oidc::oidc(db.clone()).recover(handlers::rejection::recover);
+
+    let resp = request()
+        .method("GET")
+        .path("/.well-known/openid-configuration")
+        .header("host", "localhost:3030")
+        .reply(&api)
+        .await;
+
+    let discovery: Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(discovery["issuer"], "dummy-api");
+    assert_eq!(
+        discovery["token_endpoint"],
+        "http://localhost:3030/oauth/token"
+    );
+
+    let verifier = "a-verifier-of-at-least-forty-three-characters-long";
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", "frontend"),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "openid profile"),
+        ("state", "xyz"),
+        ("nonce", "n-0S6"),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ])
+    .unwrap();
+
+    // the login form carries the request along
+    let resp = request()
+        .method("GET")
+        .path(&format!("/oauth/authorize?{}", query))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let page = from_utf8(resp.body()).unwrap();
+    assert!(page.contains("<input type=\"hidden\" name=\"state\" value=\"xyz\">"));
+
+    // unknown redirect URIs are never redirected to
+    let resp = request()
+        .method("GET")
+        .path(&format!(
+            "/oauth/authorize?{}",
+            query.replace("5173", "6666")
+        ))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = request()
+        .method("POST")
+        .path("/oauth/authorize")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(format!("username=mara&password=wrong&{}", query))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .path("/oauth/authorize")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(format!("username=mara&password=secret&{}", query))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    let params = redirect_query(&resp);
+    assert_eq!(params[1], (String::from("state"), String::from("xyz")));
+    let code = params[0].1.clone();
+
+    let exchange = |verifier: &str| {
+        serde_urlencoded::to_string([
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", REDIRECT_URI),
+            ("client_id", "frontend"),
+            ("code_verifier", verifier),
+        ])
+        .unwrap()
+    };
+
+    // a wrong verifier burns the code
+    let resp = request()
+        .method("POST")
+        .path("/oauth/token")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(exchange("someone-else"))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.body(),
+        "{\"error\":\"invalid_grant\",\"error_description\":\"Invalid code_verifier!\"}"
+    );
+
+    let resp = request()
+        .method("POST")
+        .path("/oauth/token")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(exchange(verifier))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // the whole flow again, with the right verifier
+    let resp = request()
+        .method("POST")
+        .path("/oauth/authorize")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(format!("username=mara&password=secret&{}", query))
+        .reply(&api)
+        .await;
+
+    let code = redirect_query(&resp)[0].1.clone();
+    let resp = request()
+        .method("POST")
+        .path("/oauth/token")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(
+            serde_urlencoded::to_string([
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", REDIRECT_URI),
+                ("client_id", "frontend"),
+                ("code_verifier", verifier),
+            ])
+            .unwrap(),
+        )
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let tokens: Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(tokens["token_type"], "Bearer");
+
+    let id_token = tokens["id_token"].as_str().unwrap();
+    let mut validation = jsonwebtoken::Validation::default();
+    validation.set_audience(&["frontend"]);
+    let key = jsonwebtoken::DecodingKey::from_secret(b"secret_key");
+    let claims = jsonwebtoken::decode::<auth::IdClaims>(id_token, &key, &validation)
+        .unwrap()
+        .claims;
+    assert_eq!(claims.aud, "frontend");
+    assert_eq!(claims.nonce.as_deref(), Some("n-0S6"));
+    assert_eq!(claims.user.sub, "123");
+    assert_eq!(claims.user.preferred_username, "mara");
+
+    let resp = request()
+        .method("GET")
+        .path("/oauth/userinfo")
+        .header(
+            "Authorization",
+            format!("Bearer {}", tokens["access_token"].as_str().unwrap()),
+        )
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let info: Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(info["role"], "teacher");
+
+    let resp = request()
+        .method("POST")
+        .path("/oauth/token")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(format!(
+            "grant_type=refresh_token&refresh_token={}",
+            tokens["refresh_token"].as_str().unwrap()
+        ))
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let refreshed: Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_ne!(refreshed["refresh_token"], tokens["refresh_token"]);
+
+    // with two-factor authentication, the code comes along with the password
+    let secret = two_factor::enroll(&db, 123).await.unwrap().unwrap().secret;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = totp::step(Utc::now().timestamp());
+    two_factor::confirm(&db, 123, &totp::code(&key, step))
+        .await
+        .unwrap()
+        .unwrap();
+
+    for (code, expected) in [
+        (String::new(), StatusCode::UNAUTHORIZED),
+        (totp::code(&key, step + 1), StatusCode::FOUND),
+    ] {
+        let resp = request()
+            .method("POST")
+            .path("/oauth/authorize")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(format!(
+                "username=mara&password=secret&code={}&{}",
+                code, query
+            ))
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), expected);
+    }
+}
@@ -0,0 +1,200 @@
+use serde_json::{json, Value};
+use std::fs;
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    admin, auth, config, handlers,
+    models::api_key::{self, NewApiKey},
+    models::profile::Kind,
+};
+
+mod common;
+
+fn login(username: &str, password: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth")
+        .json(&json!({ "username": username, "password": password }))
+}
+
+fn forgot(email: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth/password/forgot")
+        .json(&json!({ "email": email }))
+}
+
+fn reset(token: &str, password: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth/password/reset")
+        .json(&json!({ "token": token, "password": password }))
+}
+
+fn data(body: &[u8]) -> Value {
+    let value: Value = serde_json::from_slice(body).unwrap();
+    value["data"].clone()
+}
+
+// The token of the link in a reset email.
+fn token(email: &Value) -> String {
+    let body = email["body"].as_str().unwrap();
+    let (_, rest) = body.split_once("?token=").unwrap();
+    rest.split_whitespace().next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_password_reset() {
+    let dir = std::env::temp_dir().join(format!("dummy-api-outbox-{}", std::process::id()));
+
+    let trainee =
+        common::profile(2, "mara", Kind::Trainee).with_email(String::from("mara@app.test"));
+
+    let db = common::setup(
+        config::Config {
+            mail_from: String::from("noreply@app.test"),
+            outbox_dir: Some(dir.clone()),
+            password_reset_url: Some(String::from("https://app.test/reset")),
+            ..common::config()
+        },
+        &[common::profile(1, "root", Kind::Root), trainee],
+    )
+    .await;
+
+    let new_key = NewApiKey {
+        name: String::from("backup"),
+        scopes: vec![String::from("courses:read")],
+    };
+    let (_, key) = api_key::create(&db, new_key, 2, Kind::Trainee)
+        .await
+        .unwrap();
+
+    let api = auth::auth(db.clone())
+        .or(admin::admin(db.clone()))
+        .recover(handlers::rejection::recover);
+
+    let resp = login("root", "secret").reply(&api).await;
+    let root = format!("Bearer {}", data(resp.body())["token"].as_str().unwrap());
+
+    let resp = login("mara", "secret").reply(&api).await;
+    let refresh_token = data(resp.body())["refresh_token"].clone();
+
+    let outbox = |to: &str| {
+        request()
+            .method("GET")
+            .header("Authorization", root.clone())
+            .path(&format!("/admin/outbox?to={}", to))
+    };
+
+    // impersonating the profile, which only has the rights of a trainee
+    let resp = request()
+        .method("POST")
+        .header("Authorization", root.clone())
+        .path("/admin/profiles/2/impersonate")
+        .reply(&api)
+        .await;
+    let impersonation = format!("Bearer {}", data(resp.body())["token"].as_str().unwrap());
+
+    let resp = outbox("mara@app.test")
+        .header("Authorization", impersonation.clone())
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // unknown addresses get the same reply, and no email
+    let resp = forgot("nobody@app.test").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let unknown = resp.body().clone();
+
+    let resp = forgot("mara@app.test").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), &unknown);
+
+    let resp = outbox("nobody@app.test").reply(&api).await;
+    assert_eq!(data(resp.body()), json!([]));
+
+    let resp = forgot("not an address").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // a new token replaces the one sent before
+    forgot("mara@app.test").reply(&api).await;
+
+    let resp = request()
+        .method("GET")
+        .path("/admin/outbox")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = outbox("mara@app.test").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let emails = data(resp.body());
+    assert_eq!(emails.as_array().unwrap().len(), 2);
+    assert_eq!(emails[0]["from"], "noreply@app.test");
+    assert_eq!(emails[0]["subject"], "Reset your password");
+
+    let (latest, replaced) = (token(&emails[0]), token(&emails[1]));
+
+    let resp = reset(&replaced, "changed").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.body(),
+        "{\"error\":\"Invalid or expired reset token!\"}"
+    );
+
+    // a missing password does not use the token up
+    let resp = reset(&latest, "").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = reset(&latest, "changed").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2}}");
+
+    let resp = reset(&latest, "again").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // the profile is logged out everywhere, its API keys are revoked, and
+    // only the new password works
+    assert!(api_key::verify(&db, &key).await.unwrap().is_none());
+
+    let resp = outbox("mara@app.test")
+        .header("Authorization", impersonation.clone())
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .path("/auth/refresh")
+        .json(&json!({ "refresh_token": refresh_token }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = login("mara", "secret").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = login("mara", "changed").reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = outbox("mara@app.test").reply(&api).await;
+    assert_eq!(data(resp.body())[0]["subject"], "Your password was changed");
+
+    // every email is written to the outbox directory as well
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    assert_eq!(files.len(), 3);
+
+    let eml = fs::read_to_string(&files[0]).unwrap();
+    assert!(eml.starts_with("From: noreply@app.test\r\nTo: mara@app.test\r\n"));
+    assert!(eml.contains("\r\nSubject: Reset your password\r\n"));
+    assert!(eml.contains("https://app.test/reset?token="));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
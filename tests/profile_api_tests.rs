@@ -2,13 +2,15 @@ use warp::http::StatusCode;
 use warp::test::request;
 
 use dummy_api::{
-    models::profile::{self, Kind, Profile},
-    profile as profile_filter, store,
+    models::profile::{Kind, Profile},
+    profile as profile_filter,
 };
 
+mod common;
+
 #[tokio::test]
 async fn test_create_profile() {
-    let db = store::new_db(vec![profile::PROFILES]).await;
+    let db = common::setup(common::config(), &[]).await;
 
     let api = profile_filter::profiles(db);
 
@@ -46,4 +48,19 @@ async fn test_create_profile() {
         resp.body(),
         "{\"error\":\"Username is no longer available!\"}"
     );
+
+    let resp = request()
+        .method("POST")
+        .path("/profiles")
+        .json(
+            &Profile::new()
+                .with_username(String::from("mara"))
+                .with_password(String::from("secret"))
+                .with_email(String::from("mara@app.test\r\nBcc: everyone@app.test")),
+        )
+        .reply(&api)
+        .await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.body(), "{\"error\":\"Invalid email address!\"}");
 }
@@ -0,0 +1,52 @@
+use dummy_api::{
+    models::course::Course,
+    models::profile::{self, Profile},
+    models::seed::{self, Seed},
+    models::topic::Topic,
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_apply_seed() {
+    let db = common::setup(common::config(), &[]).await;
+
+    let seed = Seed::parse(include_str!("../seed.toml")).unwrap();
+
+    let added = seed::apply(&db, &seed).await.unwrap();
+    assert_eq!(added.len(), seed.profiles.len());
+
+    let courses = db.read::<Course>().await.unwrap();
+    let course = courses.find(|_| true).unwrap().unwrap();
+    let admin = db
+        .read::<Profile>()
+        .await
+        .unwrap()
+        .find_by(profile::BY_USERNAME, "admin")
+        .unwrap()
+        .unwrap();
+    assert_eq!(course.creator_id, admin.id);
+
+    let topics = db.read::<Topic>().await.unwrap();
+    let topic = topics.find(|_| true).unwrap().unwrap();
+    assert_eq!(topic.course_id, course.id);
+    drop(courses);
+    drop(topics);
+
+    // seeding again changes nothing
+    let added = seed::apply(&db, &seed).await.unwrap();
+    assert!(added.is_empty());
+    assert_eq!(db.read::<Course>().await.unwrap().count().unwrap(), 1);
+    assert_eq!(db.read::<Topic>().await.unwrap().count().unwrap(), 1);
+
+    // courses must refer to a known profile
+    let seed = Seed::parse(
+        r#"
+        [[courses]]
+        title = "Go in Action"
+        creator = "nobody"
+        "#,
+    )
+    .unwrap();
+    assert!(seed::apply(&db, &seed).await.is_err());
+}
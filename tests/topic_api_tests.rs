@@ -5,44 +5,31 @@ use warp::test::request;
 use warp::Filter;
 
 use dummy_api::{
-    auth, config, course as course_filter,
-    models::course::{self, Course},
-    models::profile::{self, Credentials, Kind, Profile},
-    models::topic::{self, Topic},
-    store, topic as topic_filter,
+    auth, course as course_filter, handlers,
+    models::course::Course,
+    models::profile::{Credentials, Kind},
+    models::topic::Topic,
+    topic as topic_filter,
 };
 
+mod common;
+
 #[tokio::test]
 async fn test_create_topic() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES, topic::TOPICS]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    let mentor = Profile::new()
-        .with_id(125)
-        .with_username(String::from("nara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Mentor);
-
-    profile::initialize(&db, &[admin, trainee, mentor]).await;
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+            common::profile(125, "nara", Kind::Mentor),
+        ],
+    )
+    .await;
 
     let api = auth::auth(db.clone())
         .or(course_filter::courses(db.clone()))
-        .or(topic_filter::topics(db));
+        .or(topic_filter::topics(db))
+        .recover(handlers::rejection::recover);
 
     let resp = request()
         .method("POST")
@@ -205,35 +192,20 @@ async fn test_create_topic() {
 
 #[tokio::test]
 async fn test_update_topic() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES, topic::TOPICS]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    let mentor = Profile::new()
-        .with_id(125)
-        .with_username(String::from("nara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Mentor);
-
-    profile::initialize(&db, &[admin, trainee, mentor]).await;
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+            common::profile(125, "nara", Kind::Mentor),
+        ],
+    )
+    .await;
 
     let api = auth::auth(db.clone())
         .or(course_filter::courses(db.clone()))
-        .or(topic_filter::topics(db));
+        .or(topic_filter::topics(db))
+        .recover(handlers::rejection::recover);
 
     // admin login
     let resp = request()
@@ -377,35 +349,20 @@ async fn test_update_topic() {
 
 #[tokio::test]
 async fn test_list_topics() {
-    let _ = config::CONFIG.set(config::Config {
-        jwt_secret: "secret_key".as_bytes(),
-    });
-
-    let db = store::new_db(vec![profile::PROFILES, course::COURSES, topic::TOPICS]).await;
-
-    let admin = Profile::new()
-        .with_id(123)
-        .with_username(String::from("mara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Admin);
-
-    let trainee = Profile::new()
-        .with_id(124)
-        .with_username(String::from("dara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Trainee);
-
-    let mentor = Profile::new()
-        .with_id(125)
-        .with_username(String::from("nara"))
-        .with_password(String::from("secret"))
-        .with_kind(Kind::Mentor);
-
-    profile::initialize(&db, &[admin, trainee, mentor]).await;
+    let db = common::setup(
+        common::config(),
+        &[
+            common::profile(123, "mara", Kind::Admin),
+            common::profile(124, "dara", Kind::Trainee),
+            common::profile(125, "nara", Kind::Mentor),
+        ],
+    )
+    .await;
 
     let api = auth::auth(db.clone())
         .or(course_filter::courses(db.clone()))
-        .or(topic_filter::topics(db));
+        .or(topic_filter::topics(db))
+        .recover(handlers::rejection::recover);
 
     // admin login
     let resp = request()
@@ -0,0 +1,209 @@
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::test::request;
+use warp::Filter;
+
+use dummy_api::{
+    auth, config, handlers, models::lockout::Policy, models::profile::Kind, models::two_factor,
+    totp,
+};
+
+mod common;
+
+fn login() -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth")
+        .json(&json!({ "username": "mara", "password": "secret" }))
+}
+
+fn answer(challenge_token: &Value, code: &str) -> warp::test::RequestBuilder {
+    request()
+        .method("POST")
+        .path("/auth/2fa")
+        .json(&json!({ "challenge_token": challenge_token, "code": code }))
+}
+
+fn data(body: &[u8]) -> Value {
+    let value: Value = serde_json::from_slice(body).unwrap();
+    value["data"].clone()
+}
+
+#[tokio::test]
+async fn test_two_factor() {
+    let db = common::setup(
+        config::Config {
+            lockout: Policy {
+                username_threshold: 100,
+                ..Default::default()
+            },
+            ..common::config()
+        },
+        &[common::profile(2, "mara", Kind::Admin)],
+    )
+    .await;
+
+    let api = auth::auth(db).recover(handlers::rejection::recover);
+
+    let resp = login().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let session = format!("Bearer {}", data(resp.body())["token"].as_str().unwrap());
+
+    // enrolling takes a session
+    let resp = request()
+        .method("POST")
+        .path("/auth/2fa/enroll")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/enroll")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let enrolled = data(resp.body());
+    let secret = enrolled["secret"].as_str().unwrap().to_string();
+    assert_eq!(
+        enrolled["otpauth_uri"],
+        format!(
+            "otpauth://totp/dummy-api:mara?secret={}&issuer=dummy-api&algorithm=SHA1&digits=6&period=30",
+            secret
+        )
+    );
+
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = totp::step(Utc::now().timestamp());
+
+    // logins take a single step until the secret is confirmed
+    let resp = login().reply(&api).await;
+    assert!(data(resp.body())["token"].is_string());
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/confirm")
+        .json(&json!({ "code": totp::code(&key, step + 5) }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/confirm")
+        .json(&json!({ "code": totp::code(&key, step) }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let recovery_codes: Vec<String> =
+        serde_json::from_value(data(resp.body())["recovery_codes"].clone()).unwrap();
+    assert_eq!(recovery_codes.len(), two_factor::RECOVERY_CODES);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/enroll")
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // the password only gets a challenge now
+    let resp = login().reply(&api).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let challenge = data(resp.body());
+    assert_eq!(challenge["two_factor"], true);
+    assert!(challenge["token"].is_null());
+    let challenge_token = &challenge["challenge_token"];
+
+    let resp = answer(challenge_token, &totp::code(&key, step + 5))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "{\"error\":\"Invalid two-factor code!\"}");
+
+    // the code used to confirm cannot be used again
+    let resp = answer(challenge_token, &totp::code(&key, step))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = answer(challenge_token, &totp::code(&key, step + 1))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(data(resp.body())["role"], "admin");
+
+    // challenges are answered once
+    let resp = answer(challenge_token, &totp::code(&key, step + 1))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.body(), "{\"error\":\"Invalid challenge token!\"}");
+
+    // recovery codes are accepted once, typed in any case
+    let typed = recovery_codes[0].replace('-', "").to_uppercase();
+    for expected in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+        let resp = login().reply(&api).await;
+        let resp = answer(&data(resp.body())["challenge_token"], &typed)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), expected);
+    }
+
+    // a challenge takes a few wrong codes only
+    let resp = login().reply(&api).await;
+    let challenge_token = &data(resp.body())["challenge_token"];
+
+    for _ in 0..two_factor::MAX_ATTEMPTS {
+        let resp = answer(challenge_token, "000000").reply(&api).await;
+        assert_eq!(resp.body(), "{\"error\":\"Invalid two-factor code!\"}");
+    }
+
+    let resp = answer(challenge_token, &recovery_codes[1])
+        .reply(&api)
+        .await;
+    assert_eq!(resp.body(), "{\"error\":\"Invalid challenge token!\"}");
+
+    // new recovery codes replace the old ones
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/recovery-codes")
+        .json(&json!({ "code": recovery_codes[1] }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let renewed: Vec<String> =
+        serde_json::from_value(data(resp.body())["recovery_codes"].clone()).unwrap();
+    assert!(!renewed.contains(&recovery_codes[2]));
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/disable")
+        .json(&json!({ "code": recovery_codes[2] }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = request()
+        .method("POST")
+        .header("Authorization", session.clone())
+        .path("/auth/2fa/disable")
+        .json(&json!({ "code": renewed[0] }))
+        .reply(&api)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "{\"data\":{\"id\":2,\"two_factor\":false}}");
+
+    let resp = login().reply(&api).await;
+    assert!(data(resp.body())["token"].is_string());
+}